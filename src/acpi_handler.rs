@@ -0,0 +1,174 @@
+//! Glue between the `acpi` crate and the kernel.
+//!
+//! ACPI tables live in RAM that the HHDM already maps, so "mapping" a table
+//! is just an offset. The kernel only reads static tables for now; the
+//! register, port and PCI accessors exist because the `Handler` trait
+//! requires them.
+use acpi::{AcpiTables, Handler, PciAddress, PhysicalMapping};
+use core::ptr::NonNull;
+
+#[derive(Clone, Copy, Debug)]
+pub struct KernelAcpiHandler;
+
+/// Returns the ACPI tables, or `None` on machines booted without ACPI.
+/// Must be called before bootloader memory is reclaimed, since the RSDP
+/// response lives there.
+#[must_use]
+pub fn tables() -> Option<AcpiTables<KernelAcpiHandler>> {
+    let rsdp = crate::RSDP_REQUEST.response()?.address as usize;
+    // Older base revisions report the RSDP through the HHDM.
+    let hhdm = crate::memory::hhdm_offset();
+    let rsdp = if rsdp >= hhdm { rsdp - hhdm } else { rsdp };
+    // Safety: the bootloader reports a valid RSDP.
+    match unsafe { AcpiTables::from_rsdp(KernelAcpiHandler, rsdp) } {
+        Ok(tables) => Some(tables),
+        Err(err) => {
+            log::warn!("acpi: failed to parse tables: {err:?}");
+            None
+        }
+    }
+}
+
+fn hhdm_ptr<T>(address: usize) -> *mut T {
+    (address + crate::memory::hhdm_offset()) as *mut T
+}
+
+impl Handler for KernelAcpiHandler {
+    unsafe fn map_physical_region<T>(
+        &self,
+        physical_address: usize,
+        size: usize,
+    ) -> PhysicalMapping<Self, T> {
+        PhysicalMapping {
+            physical_start: physical_address,
+            virtual_start: NonNull::new(hhdm_ptr(physical_address))
+                .expect("acpi: null HHDM address"),
+            region_length: size,
+            mapped_length: size,
+            handler: *self,
+        }
+    }
+
+    fn unmap_physical_region<T>(_region: &PhysicalMapping<Self, T>) {}
+
+    fn read_u8(&self, address: usize) -> u8 {
+        unsafe { hhdm_ptr::<u8>(address).read_volatile() }
+    }
+
+    fn read_u16(&self, address: usize) -> u16 {
+        unsafe { hhdm_ptr::<u16>(address).read_volatile() }
+    }
+
+    fn read_u32(&self, address: usize) -> u32 {
+        unsafe { hhdm_ptr::<u32>(address).read_volatile() }
+    }
+
+    fn read_u64(&self, address: usize) -> u64 {
+        unsafe { hhdm_ptr::<u64>(address).read_volatile() }
+    }
+
+    fn write_u8(&self, address: usize, value: u8) {
+        unsafe { hhdm_ptr::<u8>(address).write_volatile(value) }
+    }
+
+    fn write_u16(&self, address: usize, value: u16) {
+        unsafe { hhdm_ptr::<u16>(address).write_volatile(value) }
+    }
+
+    fn write_u32(&self, address: usize, value: u32) {
+        unsafe { hhdm_ptr::<u32>(address).write_volatile(value) }
+    }
+
+    fn write_u64(&self, address: usize, value: u64) {
+        unsafe { hhdm_ptr::<u64>(address).write_volatile(value) }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn read_io_u8(&self, port: u16) -> u8 {
+        unsafe { x86_64::instructions::port::Port::new(port).read() }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn read_io_u16(&self, port: u16) -> u16 {
+        unsafe { x86_64::instructions::port::Port::new(port).read() }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn read_io_u32(&self, port: u16) -> u32 {
+        unsafe { x86_64::instructions::port::Port::new(port).read() }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn write_io_u8(&self, port: u16, value: u8) {
+        unsafe { x86_64::instructions::port::Port::new(port).write(value) }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn write_io_u16(&self, port: u16, value: u16) {
+        unsafe { x86_64::instructions::port::Port::new(port).write(value) }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn write_io_u32(&self, port: u16, value: u32) {
+        unsafe { x86_64::instructions::port::Port::new(port).write(value) }
+    }
+
+    // There are no I/O ports outside x86; reads float high.
+    #[cfg(not(target_arch = "x86_64"))]
+    fn read_io_u8(&self, _port: u16) -> u8 {
+        u8::MAX
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn read_io_u16(&self, _port: u16) -> u16 {
+        u16::MAX
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn read_io_u32(&self, _port: u16) -> u32 {
+        u32::MAX
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn write_io_u8(&self, _port: u16, _value: u8) {}
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn write_io_u16(&self, _port: u16, _value: u16) {}
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn write_io_u32(&self, _port: u16, _value: u32) {}
+
+    // There is no PCI support yet; configuration reads return "no device".
+    fn read_pci_u8(&self, _address: PciAddress, _offset: u16) -> u8 {
+        u8::MAX
+    }
+
+    fn read_pci_u16(&self, _address: PciAddress, _offset: u16) -> u16 {
+        u16::MAX
+    }
+
+    fn read_pci_u32(&self, _address: PciAddress, _offset: u16) -> u32 {
+        u32::MAX
+    }
+
+    fn write_pci_u8(&self, _address: PciAddress, _offset: u16, _value: u8) {}
+
+    fn write_pci_u16(&self, _address: PciAddress, _offset: u16, _value: u16) {}
+
+    fn write_pci_u32(&self, _address: PciAddress, _offset: u16, _value: u32) {}
+
+    // There is no timer yet.
+    fn nanos_since_boot(&self) -> u64 {
+        0
+    }
+
+    fn stall(&self, microseconds: u64) {
+        for _ in 0..microseconds * 1000 {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn sleep(&self, milliseconds: u64) {
+        self.stall(milliseconds * 1000);
+    }
+}
@@ -1,15 +1,54 @@
 use crate::heap::GlobalHeap;
+use crate::memory::{kaslr, mode};
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-/// End of the lower canonical half on x86_64 (4-level paging).
-/// `usize::MAX` is non-canonical and would cause `#GP`, not a page fault.
-pub const HEAP_END: usize = 0x0000_7FFF_FFFF_FFFF;
-pub const HEAP_SIZE: usize = HEAP_END - HEAP_START + 1;
+/// Lowest address the heap may start at, halfway up the lower half. User
+/// space ends here.
+#[must_use]
+pub fn heap_area_start() -> usize {
+    mode::lower_half_end() / 2
+}
+
+/// Last address of the lower canonical half: 0x7fff_ffff_ffff with 4-level
+/// paging. Anything above is non-canonical and would cause `#GP` on
+/// x86_64, not a page fault.
+#[must_use]
+pub fn heap_area_end() -> usize {
+    mode::lower_half_end() - 1
+}
+
+/// Size of the heap, which `kaslr` places somewhere in
+/// `heap_area_start()..=heap_area_end()`: half of that area, 32 TiB with
+/// 4-level paging.
+#[must_use]
+pub fn heap_size() -> usize {
+    mode::lower_half_end() / 4
+}
+
+/// Returns the first address of the heap.
+#[must_use]
+pub fn heap_start() -> usize {
+    kaslr::layout().heap_start
+}
+
+/// Returns the last address of the heap.
+#[must_use]
+pub fn heap_end() -> usize {
+    heap_start() + heap_size() - 1
+}
 
 #[global_allocator]
 static HEAP: GlobalHeap = GlobalHeap::new();
 
 pub fn init() {
-    HEAP.init(HEAP_START, HEAP_SIZE);
+    HEAP.init(heap_start(), heap_size());
     log::info!("Heap allocator initialized");
 }
+
+/// Runs `GlobalHeap::self_test` on the kernel heap if the kernel was built
+/// with the `selftest` feature or `heap.selftest` is on the command line.
+pub fn self_test() {
+    if !cfg!(feature = "selftest") && !crate::memory::kaslr::has_option("heap.selftest") {
+        return;
+    }
+    HEAP.self_test();
+}
@@ -1,21 +1,304 @@
 //! aarch64-specific architecture code.
 
+use crate::memory::asid::Asid;
+use crate::memory::walk;
 use core::arch::asm;
+use core::sync::atomic::{AtomicU32, Ordering};
+use memory_addr::PhysAddr;
+use page_table_entry::aarch64::MemAttr;
 pub mod paging;
+pub mod trap;
 
 pub type PageTable = paging::PageTable;
 pub type PageTableEntry = paging::PageTableEntry;
+pub type PagingMetaData = paging::PagingMetaData;
 
 /// Halts the CPU.
-pub fn holt() -> ! {
-    loop {
+///
+/// Uses the `wfi` instruction to put the CPU into a low-power state until
+/// the next interrupt, then returns; callers loop around it, as on the
+/// other architectures, so they can do work between interrupts.
+pub fn holt() {
+    unsafe {
+        asm!("wfi");
+    }
+}
+
+/// Records `index` as the current processor's index, in `TPIDR_EL1`.
+pub fn set_cpu_index(index: usize) {
+    unsafe { asm!("msr tpidr_el1, {}", in(reg) index, options(nomem, nostack)) };
+}
+
+/// Returns the index recorded by `set_cpu_index` on this processor.
+#[must_use]
+pub fn cpu_index() -> usize {
+    let index: usize;
+    unsafe { asm!("mrs {}, tpidr_el1", out(reg) index, options(nomem, nostack)) };
+    index
+}
+
+/// Returns 64 random bits from `RNDR`, or `None` if the processor lacks
+/// `FEAT_RNG` (`ID_AA64ISAR0_EL1.RNDR`) or it keeps failing.
+#[must_use]
+pub fn random_u64() -> Option<u64> {
+    let isar0: u64;
+    unsafe { asm!("mrs {}, id_aa64isar0_el1", out(reg) isar0, options(nomem, nostack)) };
+    if (isar0 >> 60) & 0xf == 0 {
+        return None;
+    }
+    for _ in 0..10 {
+        let value: u64;
+        let ok: u64;
+        // RNDR, by encoding for assemblers without FEAT_RNG. Sets Z on
+        // failure.
         unsafe {
-            asm!("wfi");
+            asm!(
+                "mrs {value}, s3_3_c2_c4_0",
+                "cset {ok}, ne",
+                value = out(reg) value,
+                ok = out(reg) ok,
+                options(nomem, nostack),
+            );
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Returns the virtual counter.
+#[must_use]
+pub fn timestamp() -> u64 {
+    let count: u64;
+    unsafe { asm!("isb", "mrs {}, cntvct_el0", out(reg) count, options(nomem, nostack)) };
+    count
+}
+
+/// Cleans and invalidates the data cache lines covering
+/// `vaddr..vaddr + len` to the point of coherency, so memory holds what the
+/// caches did and no dirty line is left to be written back over it later.
+/// Always returns `true`.
+pub fn clean_invalidate_dcache(vaddr: usize, len: usize) -> bool {
+    let ctr: usize;
+    unsafe { asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack)) };
+    // `CTR_EL0.DminLine`: log2 of the smallest data cache line, in words.
+    let line = 4 << ((ctr >> 16) & 0xf);
+    // Safety: cache maintenance on mapped memory does not change its
+    // contents.
+    unsafe {
+        for addr in (vaddr & !(line - 1)..vaddr + len).step_by(line) {
+            asm!("dc civac, {}", in(reg) addr, options(nostack));
+        }
+        asm!("dsb sy", options(nostack));
+    }
+    true
+}
+
+/// Returns the root of the page table that translates `vaddr`: TTBR1 for
+/// the higher half, TTBR0 for the lower half.
+#[must_use]
+pub fn active_root(vaddr: usize) -> PhysAddr {
+    let ttbr: u64;
+    unsafe {
+        if vaddr >> 63 == 1 {
+            asm!("mrs {}, ttbr1_el1", out(reg) ttbr, options(nomem, nostack));
+        } else {
+            asm!("mrs {}, ttbr0_el1", out(reg) ttbr, options(nomem, nostack));
+        }
+    }
+    // Drop the ASID and CnP bits.
+    PhysAddr::from((ttbr & 0x0000_ffff_ffff_f000) as usize)
+}
+
+/// Load the kernel page table into both translation table base registers.
+/// The same root serves the lower and the higher half. Limine's tables sit
+/// in bootloader-reclaimable memory, so every processor has to leave them
+/// before it is reclaimed. MAIR is set to the attribute layout `A64PTE`
+/// expects, as Limine's may index the attributes differently.
+fn load_page_table() {
+    let mapper = crate::memory::PAGE_MAPPER.read();
+    let root_paddr = mapper.root_paddr().as_usize();
+    unsafe {
+        asm!(
+            "msr mair_el1, {mair}",
+            "msr ttbr0_el1, {root}",
+            "msr ttbr1_el1, {root}",
+            "isb",
+            "tlbi vmalle1",
+            "dsb sy",
+            "isb",
+            mair = in(reg) MemAttr::MAIR_VALUE,
+            root = in(reg) root_paddr,
+        );
+    }
+}
+
+/// Number of ASID bits in use, 8 or 16, set by `enable_asids`.
+static ASID_BITS: AtomicU32 = AtomicU32::new(0);
+
+/// `TCR_EL1.A1`: take the ASID from TTBR1 instead of TTBR0.
+const TCR_A1: u64 = 1 << 22;
+/// `TCR_EL1.AS`: 16-bit ASIDs.
+const TCR_AS: u64 = 1 << 36;
+/// Descriptor bit marking a translation as tagged with the current ASID.
+const PTE_NOT_GLOBAL: u64 = 1 << 11;
+
+/// Takes ASIDs from TTBR0 and uses 16 of them when
+/// `ID_AA64MMFR0_EL1.ASIDBits` says the processor has them. Must run on
+/// the kernel page table, before any ASID other than 0 is in use.
+fn enable_asids() {
+    let mmfr0: u64;
+    let mut tcr: u64;
+    unsafe {
+        asm!("mrs {}, id_aa64mmfr0_el1", out(reg) mmfr0, options(nomem, nostack));
+        asm!("mrs {}, tcr_el1", out(reg) tcr, options(nomem, nostack));
+    }
+    let bits = if (mmfr0 >> 4) & 0xf == 0b0010 {
+        tcr |= TCR_AS;
+        16
+    } else {
+        tcr &= !TCR_AS;
+        8
+    };
+    tcr &= !TCR_A1;
+    unsafe {
+        asm!(
+            "msr tcr_el1, {}",
+            "isb",
+            "tlbi vmalle1",
+            "dsb nsh",
+            "isb",
+            in(reg) tcr,
+        );
+    }
+    ASID_BITS.store(bits, Ordering::Relaxed);
+}
+
+/// Returns the number of address space identifier bits in use.
+#[must_use]
+pub fn asid_bits() -> u32 {
+    ASID_BITS.load(Ordering::Relaxed)
+}
+
+/// Loads the page table rooted at `root` into TTBR0, which translates the
+/// lower half, tagged with ASID `asid`. The TLB keeps the entries of other
+/// address spaces. The higher half stays on the kernel page table in TTBR1.
+///
+/// # Safety
+/// `root` must be the root of a page table that shares the kernel half of
+/// the kernel page table, and must stay alive while it is loaded. TLB
+/// entries tagged with `asid` must belong to this page table.
+pub unsafe fn set_user_page_table(root: PhysAddr, asid: Asid) {
+    unsafe {
+        asm!(
+            "msr ttbr0_el1, {ttbr}",
+            "isb",
+            ttbr = in(reg) (u64::from(asid) << 48) | root.as_usize() as u64,
+        );
+    }
+}
+
+/// Operand of a `tlbi` by address: the ASID in bits 63:48 and VA[55:12] in
+/// bits 43:0.
+fn tlbi_operand(asid: Asid, vaddr: usize) -> u64 {
+    (u64::from(asid) << 48) | ((vaddr as u64 >> 12) & ((1 << 44) - 1))
+}
+
+/// Invalidates this processor's TLB entries for the page at `vaddr`, or for
+/// every page if `None`, in address space `asid`, or in every address space
+/// if `None`. Returns `true` if more than that page was invalidated.
+pub fn flush_tlb_local(asid: Option<Asid>, vaddr: Option<usize>) -> bool {
+    unsafe {
+        match (asid, vaddr) {
+            (Some(asid), Some(vaddr)) => asm!(
+                "dsb nshst", "tlbi vae1, {}", "dsb nsh", "isb",
+                in(reg) tlbi_operand(asid, vaddr),
+            ),
+            (Some(asid), None) => asm!(
+                "dsb nshst", "tlbi aside1, {}", "dsb nsh", "isb",
+                in(reg) tlbi_operand(asid, 0),
+            ),
+            (None, Some(vaddr)) => asm!(
+                "dsb nshst", "tlbi vaae1, {}", "dsb nsh", "isb",
+                in(reg) tlbi_operand(0, vaddr),
+            ),
+            (None, None) => asm!("dsb nshst", "tlbi vmalle1", "dsb nsh", "isb"),
+        }
+    }
+    vaddr.is_none()
+}
+
+/// Like `flush_tlb_local`, but on every processor in the inner shareable
+/// domain. Returns once all of them have completed the invalidation.
+pub fn flush_tlb_broadcast(asid: Option<Asid>, vaddr: Option<usize>) {
+    unsafe {
+        match (asid, vaddr) {
+            (Some(asid), Some(vaddr)) => asm!(
+                "dsb ishst", "tlbi vae1is, {}", "dsb ish", "isb",
+                in(reg) tlbi_operand(asid, vaddr),
+            ),
+            (Some(asid), None) => asm!(
+                "dsb ishst", "tlbi aside1is, {}", "dsb ish", "isb",
+                in(reg) tlbi_operand(asid, 0),
+            ),
+            (None, Some(vaddr)) => asm!(
+                "dsb ishst", "tlbi vaae1is, {}", "dsb ish", "isb",
+                in(reg) tlbi_operand(0, vaddr),
+            ),
+            (None, None) => asm!("dsb ishst", "tlbi vmalle1is", "dsb ish", "isb"),
         }
     }
 }
 
+/// Marks the page at `vaddr` in the page table rooted at `root` as not
+/// global, so its TLB entries are tagged with the ASID it is used under.
+/// `A64PTE` leaves every mapping global, which would let one address
+/// space's user pages show through in another. Returns `false` if `vaddr`
+/// is not mapped.
+///
+/// # Safety
+/// `root` must be the root of a live page table the caller exclusively
+/// owns, and the page must not have been used since it was mapped.
+pub unsafe fn set_not_global(root: PhysAddr, vaddr: usize) -> bool {
+    // Safety: forwarded to the caller.
+    let Some((entry, _)) = (unsafe { walk::leaf(root, vaddr) }) else {
+        return false;
+    };
+    // Safety: `A64PTE` is a transparent wrapper around the descriptor.
+    unsafe { *core::ptr::from_mut(entry).cast::<u64>() |= PTE_NOT_GLOBAL };
+    true
+}
+
 /// Initialize rutines
 pub fn init() {
+    load_page_table();
+    trap::init();
+    enable_asids();
     log::info!("aarch64 architecture initialized.");
 }
+
+/// Initialization code for an application processor.
+pub fn init_ap() {
+    load_page_table();
+    trap::init();
+    enable_asids();
+}
+
+/// Switches to the stack ending at `stack_top` and branches to `entry` on it.
+///
+/// # Safety
+/// `stack_top` must be the 16-byte aligned top of a mapped, unused stack.
+/// Nothing on the current stack may be referenced afterwards.
+pub unsafe fn switch_stack(stack_top: usize, entry: extern "C" fn() -> !) -> ! {
+    unsafe {
+        asm!(
+            "mov sp, {stack}",
+            "mov x29, xzr",
+            "br {entry}",
+            stack = in(reg) stack_top,
+            entry = in(reg) entry,
+            options(noreturn)
+        )
+    }
+}
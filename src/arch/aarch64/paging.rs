@@ -2,7 +2,8 @@
 
 use crate::memory::paging::AmirOSPagingHandler;
 use page_table_entry::aarch64::A64PTE;
-use page_table_multiarch::aarch64::A64PageTable;
+use page_table_multiarch::aarch64::{A64PageTable, A64PagingMetaData};
 
 pub type PageTable = A64PageTable<AmirOSPagingHandler>;
 pub type PageTableEntry = A64PTE;
+pub type PagingMetaData = A64PagingMetaData;
@@ -0,0 +1,191 @@
+//! EL1 exception handling.
+//!
+//! `VBAR_EL1` points at `exception_vectors`, whose 16 entries each carve a
+//! frame out of the interrupted stack, save two registers and branch to
+//! `trap_common` with their index. That saves the rest of the registers,
+//! `ELR_EL1` and `SPSR_EL1`, and the SIMD registers with `FPCR` and `FPSR`,
+//! which the kernel is compiled to use and the fault path may clobber, and
+//! calls `trap_handler`. Instruction and data
+//! aborts are decoded into a `fault::PageFault` and resolved by
+//! `fault::handle`; anything else is fatal, as no interrupts are enabled
+//! yet.
+use crate::memory::fault::{self, Access, PageFault};
+use core::arch::{asm, global_asm};
+
+global_asm!(
+    ".section .text",
+    ".balign 2048",
+    ".global exception_vectors",
+    "exception_vectors:",
+    ".set index, 0",
+    ".rept 16",
+    ".balign 128",
+    "sub sp, sp, #800",
+    "stp x0, x1, [sp]",
+    "mov x0, #index",
+    "b trap_common",
+    ".set index, index + 1",
+    ".endr",
+    "trap_common:",
+    "stp x2, x3, [sp, #16]",
+    "stp x4, x5, [sp, #32]",
+    "stp x6, x7, [sp, #48]",
+    "stp x8, x9, [sp, #64]",
+    "stp x10, x11, [sp, #80]",
+    "stp x12, x13, [sp, #96]",
+    "stp x14, x15, [sp, #112]",
+    "stp x16, x17, [sp, #128]",
+    "stp x18, x19, [sp, #144]",
+    "stp x20, x21, [sp, #160]",
+    "stp x22, x23, [sp, #176]",
+    "stp x24, x25, [sp, #192]",
+    "stp x26, x27, [sp, #208]",
+    "stp x28, x29, [sp, #224]",
+    "mrs x2, elr_el1",
+    "mrs x3, spsr_el1",
+    "stp x30, x2, [sp, #240]",
+    "str x3, [sp, #256]",
+    "stp q0, q1, [sp, #288]",
+    "stp q2, q3, [sp, #320]",
+    "stp q4, q5, [sp, #352]",
+    "stp q6, q7, [sp, #384]",
+    "stp q8, q9, [sp, #416]",
+    "stp q10, q11, [sp, #448]",
+    "stp q12, q13, [sp, #480]",
+    "stp q14, q15, [sp, #512]",
+    "stp q16, q17, [sp, #544]",
+    "stp q18, q19, [sp, #576]",
+    "stp q20, q21, [sp, #608]",
+    "stp q22, q23, [sp, #640]",
+    "stp q24, q25, [sp, #672]",
+    "stp q26, q27, [sp, #704]",
+    "stp q28, q29, [sp, #736]",
+    "stp q30, q31, [sp, #768]",
+    "mrs x2, fpcr",
+    "mrs x3, fpsr",
+    "str x2, [sp, #264]",
+    "str x3, [sp, #272]",
+    "mov x1, x0",
+    "mov x0, sp",
+    "bl {handler}",
+    "ldr x2, [sp, #264]",
+    "ldr x3, [sp, #272]",
+    "msr fpcr, x2",
+    "msr fpsr, x3",
+    "ldp q0, q1, [sp, #288]",
+    "ldp q2, q3, [sp, #320]",
+    "ldp q4, q5, [sp, #352]",
+    "ldp q6, q7, [sp, #384]",
+    "ldp q8, q9, [sp, #416]",
+    "ldp q10, q11, [sp, #448]",
+    "ldp q12, q13, [sp, #480]",
+    "ldp q14, q15, [sp, #512]",
+    "ldp q16, q17, [sp, #544]",
+    "ldp q18, q19, [sp, #576]",
+    "ldp q20, q21, [sp, #608]",
+    "ldp q22, q23, [sp, #640]",
+    "ldp q24, q25, [sp, #672]",
+    "ldp q26, q27, [sp, #704]",
+    "ldp q28, q29, [sp, #736]",
+    "ldp q30, q31, [sp, #768]",
+    "ldr x3, [sp, #256]",
+    "ldp x30, x2, [sp, #240]",
+    "msr elr_el1, x2",
+    "msr spsr_el1, x3",
+    "ldp x2, x3, [sp, #16]",
+    "ldp x4, x5, [sp, #32]",
+    "ldp x6, x7, [sp, #48]",
+    "ldp x8, x9, [sp, #64]",
+    "ldp x10, x11, [sp, #80]",
+    "ldp x12, x13, [sp, #96]",
+    "ldp x14, x15, [sp, #112]",
+    "ldp x16, x17, [sp, #128]",
+    "ldp x18, x19, [sp, #144]",
+    "ldp x20, x21, [sp, #160]",
+    "ldp x22, x23, [sp, #176]",
+    "ldp x24, x25, [sp, #192]",
+    "ldp x26, x27, [sp, #208]",
+    "ldp x28, x29, [sp, #224]",
+    "ldp x0, x1, [sp]",
+    "add sp, sp, #800",
+    "eret",
+    handler = sym trap_handler,
+);
+
+unsafe extern "C" {
+    static exception_vectors: u8;
+}
+
+/// What `exception_vectors` and `trap_common` save.
+#[repr(C)]
+struct TrapFrame {
+    /// `x0`-`x30`.
+    regs: [u64; 31],
+    elr: u64,
+    spsr: u64,
+    fpcr: u64,
+    fpsr: u64,
+    _pad: u64,
+    /// `q0`-`q31`.
+    vregs: [u128; 32],
+}
+
+/// Exception classes in `ESR_EL1.EC`.
+const EC_INSTRUCTION_ABORT_LOWER: u64 = 0x20;
+const EC_INSTRUCTION_ABORT: u64 = 0x21;
+const EC_DATA_ABORT_LOWER: u64 = 0x24;
+const EC_DATA_ABORT: u64 = 0x25;
+/// `ESR_EL1.ISS.WnR`: a data abort was caused by a write.
+const ESR_WNR: u64 = 1 << 6;
+/// Fault status codes `0b0001xx` are translation faults at level `xx`;
+/// the others (access flag, permission) are for mapped pages.
+const FSC_TYPE_MASK: u64 = 0b11_1100;
+const FSC_TRANSLATION: u64 = 0b00_0100;
+
+/// Handles the exception taken through vector `index`: synchronous ones
+/// are at multiples of 4.
+extern "C" fn trap_handler(frame: &mut TrapFrame, index: usize) {
+    let esr: u64;
+    let far: usize;
+    unsafe {
+        asm!("mrs {}, esr_el1", out(reg) esr, options(nomem, nostack));
+        asm!("mrs {}, far_el1", out(reg) far, options(nomem, nostack));
+    }
+    let class = (esr >> 26) & 0x3f;
+    let access = match class {
+        _ if !index.is_multiple_of(4) => None,
+        EC_INSTRUCTION_ABORT_LOWER | EC_INSTRUCTION_ABORT => Some(Access::Execute),
+        EC_DATA_ABORT_LOWER | EC_DATA_ABORT if esr & ESR_WNR != 0 => Some(Access::Write),
+        EC_DATA_ABORT_LOWER | EC_DATA_ABORT => Some(Access::Read),
+        _ => None,
+    };
+    let Some(access) = access else {
+        panic!(
+            "unexpected exception {index}, esr {esr:#x}, far {far:#x}, elr {:#x}",
+            frame.elr
+        );
+    };
+    let fault = PageFault {
+        addr: far,
+        access,
+        present: esr & FSC_TYPE_MASK != FSC_TRANSLATION,
+        user: matches!(class, EC_INSTRUCTION_ABORT_LOWER | EC_DATA_ABORT_LOWER),
+    };
+    if let Err(error) = fault::handle(&fault) {
+        panic!(
+            "Page fault at {far:#x} ({error:?}), esr {esr:#x}, elr {:#x}",
+            frame.elr
+        );
+    }
+}
+
+/// Points `VBAR_EL1` at `exception_vectors` on the calling processor.
+pub fn init() {
+    unsafe {
+        asm!(
+            "msr vbar_el1, {}",
+            "isb",
+            in(reg) &raw const exception_vectors,
+        );
+    }
+}
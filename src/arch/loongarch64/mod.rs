@@ -1,23 +1,210 @@
 //! loongarch64-specific architecture code.
 
-use core::arch::asm;
+use crate::memory::asid::Asid;
+use core::arch::{asm, global_asm};
+use memory_addr::PhysAddr;
+use page_table_multiarch::loongarch64::LA64MetaData;
 pub mod paging;
+pub mod trap;
 
 pub type PageTable = paging::PageTable;
 pub type PageTableEntry = paging::PageTableEntry;
+pub type PagingMetaData = paging::PagingMetaData;
+
+// TLB refill handler. The bootloader's handler lives in bootloader-reclaimable
+// memory, so we install our own: it walks the tables rooted at PGD with the
+// hardware `lddir`/`ldpte` helpers and fills the TLB. It runs in direct
+// address mode, so TLBRENTRY must hold its physical address. `lddir` does
+// not check for a missing table, so an empty directory entry fills an
+// invalid 4 KiB entry instead, and the access raises a page fault.
+global_asm!(
+    ".section .text",
+    ".balign 4096",
+    ".global tlb_refill_handler",
+    "tlb_refill_handler:",
+    "csrwr $t0, 0x8b",
+    "csrrd $t0, 0x1b",
+    "lddir $t0, $t0, 3",
+    "beqz $t0, 1f",
+    "lddir $t0, $t0, 2",
+    "beqz $t0, 1f",
+    "lddir $t0, $t0, 1",
+    "beqz $t0, 1f",
+    "ldpte $t0, 0",
+    "ldpte $t0, 1",
+    "tlbfill",
+    "csrrd $t0, 0x8b",
+    "ertn",
+    "1:",
+    "csrrd $t0, 0x8e",
+    "bstrins.d $t0, $zero, 5, 0",
+    "ori $t0, $t0, 12",
+    "csrwr $t0, 0x8e",
+    "csrwr $zero, 0x8c",
+    "csrwr $zero, 0x8d",
+    "tlbfill",
+    "csrrd $t0, 0x8b",
+    "ertn",
+);
+
+unsafe extern "C" {
+    fn tlb_refill_handler();
+}
 
 /// Halts the CPU.
 ///
-/// This function enters an infinite loop and uses the `hlt` instruction
-/// to put the CPU into a low-power state until the next interrupt.
+/// Uses the `idle` instruction to put the CPU into a low-power state until
+/// the next interrupt, then returns; callers loop around it.
 pub fn holt() {
     unsafe {
         asm!("idle 0");
     }
 }
 
+/// Records `index` as the current processor's index. The index lives in
+/// `$tp`, which the kernel does not use for thread-local storage.
+pub fn set_cpu_index(index: usize) {
+    unsafe { asm!("move $tp, {}", in(reg) index, options(nomem, nostack)) };
+}
+
+/// Returns the index recorded by `set_cpu_index` on this processor.
+#[must_use]
+pub fn cpu_index() -> usize {
+    let index: usize;
+    unsafe { asm!("move {}, $tp", out(reg) index, options(nomem, nostack)) };
+    index
+}
+
+/// Returns random bits from a hardware source. LoongArch has none.
+#[must_use]
+pub const fn random_u64() -> Option<u64> {
+    None
+}
+
+/// Returns the stable counter.
+#[must_use]
+pub fn timestamp() -> u64 {
+    let time: u64;
+    unsafe { asm!("rdtime.d {}, $zero", out(reg) time, options(nomem, nostack)) };
+    time
+}
+
+/// Translate a kernel image address to its physical address.
+fn kernel_virt_to_phys(vaddr: usize) -> usize {
+    let kernel_address = crate::EXECUTABLE_ADDRESS_REQUEST
+        .response()
+        .expect("loongarch64: failed to get kernel address response");
+    let offset = vaddr - kernel_address.virtual_base as usize;
+    kernel_address.physical_base as usize + offset
+}
+
+lazy_static::lazy_static! {
+    /// Physical address of `tlb_refill_handler`, resolved while the
+    /// bootloader's kernel address response is still valid.
+    static ref TLB_REFILL_PADDR: usize = kernel_virt_to_phys(tlb_refill_handler as *const () as usize);
+}
+
+/// Returns the root of the page table that translates `vaddr`: PGDH for
+/// the higher half, PGDL for the lower half.
+#[must_use]
+pub fn active_root(vaddr: usize) -> PhysAddr {
+    let root: usize;
+    unsafe {
+        if vaddr >> 63 == 1 {
+            asm!("csrrd {}, 0x1a", out(reg) root, options(nomem, nostack));
+        } else {
+            asm!("csrrd {}, 0x19", out(reg) root, options(nomem, nostack));
+        }
+    }
+    PhysAddr::from(root & !0xfff)
+}
+
+/// Load the kernel page table into PGDL and PGDH and install our TLB refill
+/// handler. The same root serves the lower and the higher half.
+fn load_page_table() {
+    let mapper = crate::memory::PAGE_MAPPER.read();
+    let root_paddr = mapper.root_paddr().as_usize();
+    let refill = *TLB_REFILL_PADDR;
+    unsafe {
+        asm!(
+            "csrwr {pwcl}, 0x1c",
+            "csrwr {pwch}, 0x1d",
+            "csrwr {refill}, 0x88",
+            "csrwr {pgdl}, 0x19",
+            "csrwr {pgdh}, 0x1a",
+            "dbar 0",
+            "invtlb 0x00, $r0, $r0",
+            pwcl = inout(reg) LA64MetaData::PWCL_VALUE as usize => _,
+            pwch = inout(reg) LA64MetaData::PWCH_VALUE as usize => _,
+            refill = inout(reg) refill => _,
+            pgdl = inout(reg) root_paddr => _,
+            pgdh = inout(reg) root_paddr => _,
+        );
+    }
+}
+
+/// Returns the number of address space identifier bits in use. ASIDs are
+/// not used on loongarch64 yet, so every switch flushes the TLB.
+#[must_use]
+pub const fn asid_bits() -> u32 {
+    0
+}
+
+/// Loads the page table rooted at `root` into PGDL, which translates the
+/// lower half, and flushes the TLB; `_asid` is ignored. The higher half
+/// stays on the kernel page table in PGDH.
+///
+/// # Safety
+/// `root` must be the root of a page table that shares the kernel half of
+/// the kernel page table, and must stay alive while it is loaded.
+pub unsafe fn set_user_page_table(root: PhysAddr, _asid: Asid) {
+    unsafe {
+        asm!(
+            "csrwr {pgdl}, 0x19",
+            "dbar 0",
+            "invtlb 0x00, $r0, $r0",
+            pgdl = inout(reg) root.as_usize() => _,
+        );
+    }
+}
+
+/// Invalidates this processor's TLB entries for the page at `vaddr`, or all
+/// entries if `None`. Without ASIDs `_asid` makes no difference. Returns
+/// `true` if more than that page was invalidated.
+pub fn flush_tlb_local(_asid: Option<Asid>, vaddr: Option<usize>) -> bool {
+    <PagingMetaData as page_table_multiarch::PagingMetaData>::flush_tlb(
+        vaddr.map(memory_addr::VirtAddr::from),
+    );
+    vaddr.is_none()
+}
+
 /// Initializes loongarch64-specific features.
 pub fn init() {
-    // initialization stuff
+    load_page_table();
+    trap::init();
     log::info!("loongarch64 architecture initialized.");
 }
+
+/// Initialization code for an application processor.
+pub fn init_ap() {
+    load_page_table();
+    trap::init();
+}
+
+/// Switches to the stack ending at `stack_top` and jumps to `entry` on it.
+///
+/// # Safety
+/// `stack_top` must be the 16-byte aligned top of a mapped, unused stack.
+/// Nothing on the current stack may be referenced afterwards.
+pub unsafe fn switch_stack(stack_top: usize, entry: extern "C" fn() -> !) -> ! {
+    unsafe {
+        asm!(
+            "move $sp, {stack}",
+            "move $fp, $zero",
+            "jirl $zero, {entry}, 0",
+            stack = in(reg) stack_top,
+            entry = in(reg) entry,
+            options(noreturn)
+        )
+    }
+}
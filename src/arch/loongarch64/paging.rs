@@ -2,7 +2,8 @@
 
 use crate::memory::paging::AmirOSPagingHandler;
 use page_table_entry::loongarch64::LA64PTE;
-use page_table_multiarch::loongarch64::LA64PageTable;
+use page_table_multiarch::loongarch64::{LA64MetaData, LA64PageTable};
 
 pub type PageTable = LA64PageTable<AmirOSPagingHandler>;
 pub type PageTableEntry = LA64PTE;
+pub type PagingMetaData = LA64MetaData;
@@ -0,0 +1,268 @@
+//! General exception handling.
+//!
+//! `EENTRY` points at `exception_entry` and `ECFG.VS` is 0, so every
+//! exception other than a TLB refill lands there. Exceptions from user mode
+//! switch to the processor's trap stack, whose top `SAVE0` holds;
+//! exceptions from the kernel stay on the interrupted stack. It saves the
+//! registers a Rust function may clobber on that stack, floating-point ones,
+//! the condition flags and `fcsr0` included when the FPU is on, along with
+//! `ERA` and `PRMD` in case the handler raises another exception, and
+//! calls `trap_handler`. Page invalid and page privilege exceptions are
+//! decoded into a `fault::PageFault` and resolved by `fault::handle`;
+//! anything else is fatal, as no interrupts are enabled yet.
+use crate::memory::fault::{self, Access, PageFault};
+use core::arch::{asm, global_asm};
+
+global_asm!(
+    ".section .text",
+    ".balign 4096",
+    ".global exception_entry",
+    "exception_entry:",
+    // SAVE1 holds $t0 while it picks the stack.
+    "csrwr $t0, 0x31",
+    "csrrd $t0, 0x1",
+    "andi $t0, $t0, 3",
+    "beqz $t0, 2f",
+    "move $t0, $sp",
+    "csrrd $sp, 0x30",
+    "b 3f",
+    "2:",
+    "move $t0, $sp",
+    "3:",
+    "addi.d $sp, $sp, -384",
+    "st.d $t0, $sp, 168",
+    "csrrd $t0, 0x31",
+    "st.d $ra, $sp, 0",
+    "st.d $a0, $sp, 8",
+    "st.d $a1, $sp, 16",
+    "st.d $a2, $sp, 24",
+    "st.d $a3, $sp, 32",
+    "st.d $a4, $sp, 40",
+    "st.d $a5, $sp, 48",
+    "st.d $a6, $sp, 56",
+    "st.d $a7, $sp, 64",
+    "st.d $t0, $sp, 72",
+    "st.d $t1, $sp, 80",
+    "st.d $t2, $sp, 88",
+    "st.d $t3, $sp, 96",
+    "st.d $t4, $sp, 104",
+    "st.d $t5, $sp, 112",
+    "st.d $t6, $sp, 120",
+    "st.d $t7, $sp, 128",
+    "st.d $t8, $sp, 136",
+    "st.d $r21, $sp, 144",
+    "csrrd $t0, 0x6",
+    "st.d $t0, $sp, 152",
+    "csrrd $t0, 0x1",
+    "st.d $t0, $sp, 160",
+    // The FPU is only touched if it is on (`EUEN.FPE`).
+    "csrrd $t0, 0x2",
+    "andi $t0, $t0, 1",
+    "beqz $t0, 1f",
+    "fst.d $f0, $sp, 192",
+    "fst.d $f1, $sp, 200",
+    "fst.d $f2, $sp, 208",
+    "fst.d $f3, $sp, 216",
+    "fst.d $f4, $sp, 224",
+    "fst.d $f5, $sp, 232",
+    "fst.d $f6, $sp, 240",
+    "fst.d $f7, $sp, 248",
+    "fst.d $f8, $sp, 256",
+    "fst.d $f9, $sp, 264",
+    "fst.d $f10, $sp, 272",
+    "fst.d $f11, $sp, 280",
+    "fst.d $f12, $sp, 288",
+    "fst.d $f13, $sp, 296",
+    "fst.d $f14, $sp, 304",
+    "fst.d $f15, $sp, 312",
+    "fst.d $f16, $sp, 320",
+    "fst.d $f17, $sp, 328",
+    "fst.d $f18, $sp, 336",
+    "fst.d $f19, $sp, 344",
+    "fst.d $f20, $sp, 352",
+    "fst.d $f21, $sp, 360",
+    "fst.d $f22, $sp, 368",
+    "fst.d $f23, $sp, 376",
+    "movcf2gr $t1, $fcc0",
+    "st.b $t1, $sp, 184",
+    "movcf2gr $t1, $fcc1",
+    "st.b $t1, $sp, 185",
+    "movcf2gr $t1, $fcc2",
+    "st.b $t1, $sp, 186",
+    "movcf2gr $t1, $fcc3",
+    "st.b $t1, $sp, 187",
+    "movcf2gr $t1, $fcc4",
+    "st.b $t1, $sp, 188",
+    "movcf2gr $t1, $fcc5",
+    "st.b $t1, $sp, 189",
+    "movcf2gr $t1, $fcc6",
+    "st.b $t1, $sp, 190",
+    "movcf2gr $t1, $fcc7",
+    "st.b $t1, $sp, 191",
+    "movfcsr2gr $t1, $fcsr0",
+    "st.d $t1, $sp, 176",
+    "1:",
+    "move $a0, $sp",
+    "bl {handler}",
+    "csrrd $t0, 0x2",
+    "andi $t0, $t0, 1",
+    "beqz $t0, 1f",
+    "fld.d $f0, $sp, 192",
+    "fld.d $f1, $sp, 200",
+    "fld.d $f2, $sp, 208",
+    "fld.d $f3, $sp, 216",
+    "fld.d $f4, $sp, 224",
+    "fld.d $f5, $sp, 232",
+    "fld.d $f6, $sp, 240",
+    "fld.d $f7, $sp, 248",
+    "fld.d $f8, $sp, 256",
+    "fld.d $f9, $sp, 264",
+    "fld.d $f10, $sp, 272",
+    "fld.d $f11, $sp, 280",
+    "fld.d $f12, $sp, 288",
+    "fld.d $f13, $sp, 296",
+    "fld.d $f14, $sp, 304",
+    "fld.d $f15, $sp, 312",
+    "fld.d $f16, $sp, 320",
+    "fld.d $f17, $sp, 328",
+    "fld.d $f18, $sp, 336",
+    "fld.d $f19, $sp, 344",
+    "fld.d $f20, $sp, 352",
+    "fld.d $f21, $sp, 360",
+    "fld.d $f22, $sp, 368",
+    "fld.d $f23, $sp, 376",
+    "ld.b $t1, $sp, 184",
+    "movgr2cf $fcc0, $t1",
+    "ld.b $t1, $sp, 185",
+    "movgr2cf $fcc1, $t1",
+    "ld.b $t1, $sp, 186",
+    "movgr2cf $fcc2, $t1",
+    "ld.b $t1, $sp, 187",
+    "movgr2cf $fcc3, $t1",
+    "ld.b $t1, $sp, 188",
+    "movgr2cf $fcc4, $t1",
+    "ld.b $t1, $sp, 189",
+    "movgr2cf $fcc5, $t1",
+    "ld.b $t1, $sp, 190",
+    "movgr2cf $fcc6, $t1",
+    "ld.b $t1, $sp, 191",
+    "movgr2cf $fcc7, $t1",
+    "ld.d $t1, $sp, 176",
+    "movgr2fcsr $fcsr0, $t1",
+    "1:",
+    "ld.d $t0, $sp, 152",
+    "csrwr $t0, 0x6",
+    "ld.d $t0, $sp, 160",
+    "csrwr $t0, 0x1",
+    "ld.d $ra, $sp, 0",
+    "ld.d $a0, $sp, 8",
+    "ld.d $a1, $sp, 16",
+    "ld.d $a2, $sp, 24",
+    "ld.d $a3, $sp, 32",
+    "ld.d $a4, $sp, 40",
+    "ld.d $a5, $sp, 48",
+    "ld.d $a6, $sp, 56",
+    "ld.d $a7, $sp, 64",
+    "ld.d $t0, $sp, 72",
+    "ld.d $t1, $sp, 80",
+    "ld.d $t2, $sp, 88",
+    "ld.d $t3, $sp, 96",
+    "ld.d $t4, $sp, 104",
+    "ld.d $t5, $sp, 112",
+    "ld.d $t6, $sp, 120",
+    "ld.d $t7, $sp, 128",
+    "ld.d $t8, $sp, 136",
+    "ld.d $r21, $sp, 144",
+    "ld.d $sp, $sp, 168",
+    "ertn",
+    handler = sym trap_handler,
+);
+
+unsafe extern "C" {
+    fn exception_entry();
+}
+
+/// What `exception_entry` saves: `$ra`, `$a0`-`$a7`, `$t0`-`$t8` and
+/// `$r21`, the exception CSRs, then `fcsr0`, `$fcc0`-`$fcc7` and
+/// `$f0`-`$f23`.
+#[repr(C)]
+struct TrapFrame {
+    regs: [usize; 19],
+    era: usize,
+    prmd: usize,
+    /// The interrupted stack pointer.
+    sp: usize,
+    fcsr: usize,
+    fcc: [u8; 8],
+    fregs: [u64; 24],
+}
+
+/// Exception codes in `ESTAT.Ecode` for loads, stores and fetches from an
+/// invalid page, stores to a clean page, reads from a non-readable page,
+/// fetches from a non-executable page and accesses above the page's
+/// privilege level.
+const ECODE_PIL: usize = 0x1;
+const ECODE_PIS: usize = 0x2;
+const ECODE_PIF: usize = 0x3;
+const ECODE_PME: usize = 0x4;
+const ECODE_PNR: usize = 0x5;
+const ECODE_PNX: usize = 0x6;
+const ECODE_PPI: usize = 0x7;
+/// `PRMD.PPLV`: the privilege level the exception was taken from.
+const PRMD_PPLV: usize = 0b11;
+
+extern "C" fn trap_handler(frame: &mut TrapFrame) {
+    let estat: usize;
+    let badv: usize;
+    unsafe {
+        asm!("csrrd {}, 0x5", out(reg) estat, options(nomem, nostack));
+        asm!("csrrd {}, 0x7", out(reg) badv, options(nomem, nostack));
+    }
+    let ecode = (estat >> 16) & 0x3f;
+    let access = match ecode {
+        ECODE_PIL | ECODE_PNR | ECODE_PPI => Some(Access::Read),
+        ECODE_PIS | ECODE_PME => Some(Access::Write),
+        ECODE_PIF | ECODE_PNX => Some(Access::Execute),
+        _ => None,
+    };
+    let Some(access) = access else {
+        panic!(
+            "unexpected exception, estat {estat:#x}, badv {badv:#x}, era {:#x}",
+            frame.era
+        );
+    };
+    let fault = PageFault {
+        addr: badv,
+        access,
+        present: !matches!(ecode, ECODE_PIL | ECODE_PIS | ECODE_PIF),
+        user: frame.prmd & PRMD_PPLV != 0,
+    };
+    if let Err(error) = fault::handle(&fault) {
+        panic!(
+            "Page fault at {badv:#x} ({error:?}), estat {estat:#x}, era {:#x}",
+            frame.era
+        );
+    }
+    // The TLB refill handler left an invalid entry for the page.
+    super::flush_tlb_local(None, Some(badv));
+}
+
+/// Points `EENTRY` at `exception_entry` on the calling processor, with
+/// every exception sharing it, and `SAVE0` at a new kernel stack for the
+/// exceptions it takes from user mode.
+pub fn init() {
+    let stack_top = crate::memory::alloc_kernel_stack();
+    unsafe {
+        asm!(
+            "csrwr {top}, 0x30",
+            top = inout(reg) stack_top => _,
+            options(nomem, nostack),
+        );
+        asm!(
+            "csrxchg $zero, {vs}, 0x4",
+            "csrwr {entry}, 0xc",
+            vs = in(reg) 0x7 << 16,
+            entry = inout(reg) exception_entry as *const () as usize => _,
+        );
+    }
+}
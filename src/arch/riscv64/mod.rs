@@ -1,39 +1,280 @@
 //! riscv64-specific architecture code.
 
+use crate::memory::asid::Asid;
+use crate::memory::frame_cache::MAX_CPUS;
+use crate::memory::mode;
 use core::arch::asm;
-use memory_addr::VirtAddr;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use memory_addr::PhysAddr;
 use riscv::register::satp;
 pub mod paging;
+pub mod trap;
 
 pub type PageTable = paging::PageTable;
 pub type PageTableEntry = paging::PageTableEntry;
+pub type PagingMetaData = paging::PagingMetaData;
 
 /// Halts the CPU.
 ///
-/// This function enters an infinite loop and uses the `hlt` instruction
-/// to put the CPU into a low-power state until the next interrupt.
+/// Uses the `wfi` instruction to put the CPU into a low-power state until
+/// the next interrupt, then returns; callers loop around it.
 pub fn holt() {
     unsafe {
         asm!("wfi");
     }
 }
 
-/// Initializes riscv64-specific features.
-pub fn init() {
+/// Records `index` as the current processor's index. The index lives in
+/// `tp`, which the kernel does not use for thread-local storage.
+pub fn set_cpu_index(index: usize) {
+    unsafe { asm!("mv tp, {}", in(reg) index, options(nomem, nostack)) };
+}
+
+/// Returns the index recorded by `set_cpu_index` on this processor.
+#[must_use]
+pub fn cpu_index() -> usize {
+    let index: usize;
+    unsafe { asm!("mv {}, tp", out(reg) index, options(nomem, nostack)) };
+    index
+}
+
+/// Returns random bits from a hardware source. The Zkr `seed` CSR is the
+/// only one, and it traps in S-mode unless M-mode firmware opted in, so
+/// there is none.
+#[must_use]
+pub const fn random_u64() -> Option<u64> {
+    None
+}
+
+/// Returns the `time` counter.
+#[must_use]
+pub fn timestamp() -> u64 {
+    let time: u64;
+    unsafe { asm!("rdtime {}", out(reg) time, options(nomem, nostack)) };
+    time
+}
+
+/// Set when the device tree lists the Svpbmt extension, which lets page
+/// table entries select non-cacheable memory.
+static SVPBMT: AtomicBool = AtomicBool::new(false);
+
+/// Returns `true` if page table entries carry a memory type, so `UNCACHED`
+/// and `DEVICE` mappings bypass the caches.
+#[must_use]
+pub fn has_svpbmt() -> bool {
+    SVPBMT.load(Ordering::Relaxed)
+}
+
+/// Size of the cache blocks Zicbom's `cbo.flush` works on, or 0 if the
+/// device tree does not list Zicbom.
+static CBOM_BLOCK_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Looks for the extension `name` in the device tree, either in
+/// `riscv,isa` or in `riscv,isa-extensions`. A plain byte search is enough:
+/// extension names only appear in those properties.
+fn dtb_lists_extension(name: &[u8]) -> bool {
+    let Some(dtb) = crate::DEVICE_TREE_BLOB_REQUEST.response() else {
+        return false;
+    };
+    let base = dtb.dtb_ptr.cast::<u8>();
+    // Safety: Limine hands us a valid, mapped flattened device tree, whose
+    // header starts with the magic and the big-endian total size.
+    let header = unsafe { core::slice::from_raw_parts(base, 8) };
+    if header[..4] != [0xd0, 0x0d, 0xfe, 0xed] {
+        return false;
+    }
+    let size = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    // Safety: as above, the blob is `size` bytes long.
+    let blob = unsafe { core::slice::from_raw_parts(base, size) };
+    blob.windows(name.len()).any(|window| window == name)
+}
+
+/// Returns the `riscv,cbom-block-size` of the device tree if it lists
+/// Zicbom, or 0.
+fn detect_cbom_block_size() -> usize {
+    if !dtb_lists_extension(b"zicbom") {
+        return 0;
+    }
+    crate::memory::kaslr::dtb_property(b"riscv,cbom-block-size\0")
+        .and_then(|value| <[u8; 4]>::try_from(value).ok())
+        .map(|value| u32::from_be_bytes(value) as usize)
+        .filter(|size| size.is_power_of_two())
+        .unwrap_or(0)
+}
+
+/// Writes back and invalidates the data cache blocks covering
+/// `vaddr..vaddr + len` with `cbo.flush`, so memory holds what the caches
+/// did and no dirty block is left to be written back over it later.
+/// Returns `false` without Zicbom, in which case nothing is done.
+pub fn clean_invalidate_dcache(vaddr: usize, len: usize) -> bool {
+    let block = CBOM_BLOCK_SIZE.load(Ordering::Relaxed);
+    if block == 0 {
+        return false;
+    }
+    // Safety: cache block operations on mapped memory do not change its
+    // contents.
+    unsafe {
+        asm!("fence rw, rw", options(nostack));
+        for addr in (vaddr & !(block - 1)..vaddr + len).step_by(block) {
+            // `cbo.flush`, spelled out for assemblers without Zicbom.
+            asm!(".insn i 0x0f, 2, x0, {}, 2", in(reg) addr, options(nostack));
+        }
+        asm!("fence rw, rw", options(nostack));
+    }
+    true
+}
+
+/// Returns the root of the page table loaded in SATP, which translates
+/// every address.
+#[must_use]
+pub fn active_root(_vaddr: usize) -> PhysAddr {
+    PhysAddr::from(satp::read().ppn() << 12)
+}
+
+/// Returns the SATP mode of the paging mode Limine enabled.
+fn satp_mode() -> satp::Mode {
+    if mode::levels() == 3 {
+        satp::Mode::Sv39
+    } else {
+        satp::Mode::Sv48
+    }
+}
+
+/// Load the kernel page table into SATP.
+fn load_page_table() {
     let mapper = crate::memory::PAGE_MAPPER.read();
     let root_paddr = mapper.root_paddr().as_usize();
     let ppn = root_paddr / 4096; // Convert address to Physical Page Number
-    unsafe { satp::set(satp::Mode::Sv48, 0, ppn) };
-    drop(mapper);
-
-    // Setup guard page below kernel stack.
-    let sp: usize;
-    unsafe { asm!("mv {}, sp", out(reg) sp, options(nomem, nostack)) };
-    let stack_top = (sp + 0xFFF) & !0xFFF;
-    let stack_bottom = stack_top.saturating_sub(128 * 1024);
-    let guard_page = stack_bottom.saturating_sub(0x1000);
-    let mut mapper = crate::memory::PAGE_MAPPER.write();
-    let _ = mapper.cursor().unmap(VirtAddr::from(guard_page));
+    unsafe { satp::set(satp_mode(), 0, ppn) };
+    riscv::asm::sfence_vma_all();
+}
+
+/// Number of ASID bits the hart implements, found by `probe_asid_bits`.
+static ASID_BITS: AtomicU32 = AtomicU32::new(0);
+
+/// Finds how many ASID bits the hart implements: writing all ones to the
+/// SATP ASID field leaves only the implemented bits set. Must run on the
+/// kernel page table, before any ASID other than 0 is in use.
+fn probe_asid_bits() -> u32 {
+    let ppn = satp::read().ppn();
+    unsafe { satp::set(satp_mode(), 0xffff, ppn) };
+    let bits = satp::read().asid().count_ones();
+    unsafe { satp::set(satp_mode(), 0, ppn) };
+    riscv::asm::sfence_vma_all();
+    bits
+}
+
+/// Returns the number of address space identifier bits in use.
+#[must_use]
+pub fn asid_bits() -> u32 {
+    ASID_BITS.load(Ordering::Relaxed)
+}
+
+/// Loads the page table rooted at `root` into SATP, tagged with ASID
+/// `asid`. With ASIDs the TLB keeps the entries of other address spaces;
+/// without, it is flushed. Kernel-half entries are shared by every address
+/// space, so the kernel stays mapped.
+///
+/// # Safety
+/// `root` must be the root of a page table that shares the kernel half of
+/// the kernel page table, and must stay alive while it is loaded. TLB
+/// entries tagged with `asid` must belong to this page table.
+pub unsafe fn set_user_page_table(root: PhysAddr, asid: Asid) {
+    unsafe { satp::set(satp_mode(), usize::from(asid), root.as_usize() / 4096) };
+    if asid_bits() == 0 {
+        riscv::asm::sfence_vma_all();
+    }
+}
+
+/// Invalidates this hart's TLB entries for the page at `vaddr`, or for
+/// every page if `None`, in address space `asid`, or in every address space
+/// if `None`. Returns `true` if more than that page was invalidated.
+pub fn flush_tlb_local(asid: Option<Asid>, vaddr: Option<usize>) -> bool {
+    let asid = asid.filter(|_| asid_bits() > 0).map(usize::from);
+    // Safety: `sfence.vma` only orders and invalidates translations.
+    unsafe {
+        match (asid, vaddr) {
+            (Some(asid), Some(vaddr)) => asm!("sfence.vma {}, {}", in(reg) vaddr, in(reg) asid),
+            (Some(asid), None) => asm!("sfence.vma zero, {}", in(reg) asid),
+            (None, Some(vaddr)) => asm!("sfence.vma {}, zero", in(reg) vaddr),
+            (None, None) => asm!("sfence.vma"),
+        }
+    }
+    vaddr.is_none()
+}
+
+/// SBI "RFENCE" extension and its remote `sfence.vma` functions.
+const SBI_EXT_RFENCE: usize = 0x5246_4e43;
+const SBI_REMOTE_SFENCE_VMA: usize = 1;
+const SBI_REMOTE_SFENCE_VMA_ASID: usize = 2;
+
+/// Asks the SBI to run `sfence.vma` for `start..start + size` in address
+/// space `asid`, or in every address space if `None`, on hart `hart_id`; a
+/// `size` of `usize::MAX` flushes everything. The call returns once the
+/// remote hart has flushed. Returns `false` if the SBI refused.
+pub fn remote_sfence_vma(hart_id: u64, asid: Option<Asid>, start: usize, size: usize) -> bool {
+    let asid = asid.filter(|_| asid_bits() > 0);
+    let function = if asid.is_some() {
+        SBI_REMOTE_SFENCE_VMA_ASID
+    } else {
+        SBI_REMOTE_SFENCE_VMA
+    };
+    let error: isize;
+    // Safety: an SBI call only clobbers a0 and a1.
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") 1usize => error,
+            inlateout("a1") hart_id as usize => _,
+            in("a2") start,
+            in("a3") size,
+            in("a4") asid.map_or(0, usize::from),
+            in("a6") function,
+            in("a7") SBI_EXT_RFENCE,
+            options(nostack),
+        );
+    }
+    error == 0
+}
+
+/// Initializes riscv64-specific features.
+pub fn init() {
+    load_page_table();
+    trap::init();
+    // The allocator keeps the ASID of every running hart across a rollover,
+    // so it needs one more than that to hand out; with fewer, do without.
+    let bits = probe_asid_bits();
+    if 1usize << bits > MAX_CPUS + 1 {
+        ASID_BITS.store(bits, Ordering::Relaxed);
+    } else if bits > 0 {
+        log::info!("riscv64: ignoring the {bits} ASID bits the hart implements, too few to use");
+    }
+    SVPBMT.store(dtb_lists_extension(b"svpbmt"), Ordering::Relaxed);
+    CBOM_BLOCK_SIZE.store(detect_cbom_block_size(), Ordering::Relaxed);
 
     log::info!("riscv64 architecture initialized.");
 }
+
+/// Initialization code for an application processor.
+pub fn init_ap() {
+    load_page_table();
+    trap::init();
+}
+
+/// Switches to the stack ending at `stack_top` and jumps to `entry` on it.
+///
+/// # Safety
+/// `stack_top` must be the 16-byte aligned top of a mapped, unused stack.
+/// Nothing on the current stack may be referenced afterwards.
+pub unsafe fn switch_stack(stack_top: usize, entry: extern "C" fn() -> !) -> ! {
+    unsafe {
+        asm!(
+            "mv sp, {stack}",
+            "mv fp, zero",
+            "jr {entry}",
+            stack = in(reg) stack_top,
+            entry = in(reg) entry,
+            options(noreturn)
+        )
+    }
+}
@@ -1,9 +1,217 @@
 //! riscv64-specific paging implementation and initialization.
 
+use crate::memory::mode;
 use crate::memory::paging::AmirOSPagingHandler;
-use page_table_entry::riscv::Rv64PTE;
-use page_table_multiarch::riscv::Sv48PageTable;
+use core::fmt;
+use core::sync::atomic::Ordering;
+use memory_addr::{PhysAddr, VirtAddr};
+use page_table_entry::riscv::PTEFlags;
+use page_table_multiarch::riscv::{Sv39MetaData, Sv48MetaData};
+use page_table_multiarch::{
+    GenericPTE, MappingFlags, PageSize, PageTable64, PageTable64Cursor, PagingResult,
+};
 
-/// A type alias for the riscv64-specific page table, using our OS's handler.
-pub type PageTable = Sv48PageTable<AmirOSPagingHandler>;
-pub type PageTableEntry = Rv64PTE;
+/// Sv39 and Sv48 flush the TLB the same way.
+pub type PagingMetaData = Sv48MetaData<VirtAddr>;
+
+type Sv39PageTable = PageTable64<Sv39MetaData<VirtAddr>, PageTableEntry, AmirOSPagingHandler>;
+type Sv48PageTable = PageTable64<Sv48MetaData<VirtAddr>, PageTableEntry, AmirOSPagingHandler>;
+type Sv39PageTableCursor<'a> =
+    PageTable64Cursor<'a, Sv39MetaData<VirtAddr>, PageTableEntry, AmirOSPagingHandler>;
+type Sv48PageTableCursor<'a> =
+    PageTable64Cursor<'a, Sv48MetaData<VirtAddr>, PageTableEntry, AmirOSPagingHandler>;
+
+/// An Sv39/Sv48 page table entry. Unlike `Rv64PTE` it carries the Svpbmt
+/// memory type: leaf entries mapped `UNCACHED` get `NC` and `DEVICE` ones
+/// get `IO`, and `flags` reports them back, so they survive remapping and
+/// huge page splits. Without Svpbmt the bits are reserved and stay clear;
+/// the platform's PMAs then decide how the memory is accessed.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct PageTableEntry(u64);
+
+impl PageTableEntry {
+    /// Bits 10..54.
+    const PHYS_ADDR_MASK: u64 = (1 << 54) - (1 << 10);
+    const PBMT_MASK: u64 = 0b11 << 61;
+    /// Non-cacheable, idempotent, weakly-ordered main memory.
+    const PBMT_NC: u64 = 0b01 << 61;
+    /// Non-cacheable, non-idempotent, strongly-ordered I/O memory.
+    const PBMT_IO: u64 = 0b10 << 61;
+
+    /// Returns the Svpbmt bits for a leaf entry mapped with `flags`.
+    fn pbmt(flags: MappingFlags) -> u64 {
+        if !super::SVPBMT.load(Ordering::Relaxed) {
+            0
+        } else if flags.contains(MappingFlags::DEVICE) {
+            Self::PBMT_IO
+        } else if flags.contains(MappingFlags::UNCACHED) {
+            Self::PBMT_NC
+        } else {
+            0
+        }
+    }
+
+    fn leaf_bits(flags: MappingFlags) -> u64 {
+        PTEFlags::from(flags).bits() as u64 | Self::pbmt(flags)
+    }
+}
+
+impl GenericPTE for PageTableEntry {
+    fn new_page(paddr: PhysAddr, flags: MappingFlags, _is_huge: bool) -> Self {
+        Self(Self::leaf_bits(flags) | ((paddr.as_usize() as u64 >> 2) & Self::PHYS_ADDR_MASK))
+    }
+
+    fn new_table(paddr: PhysAddr) -> Self {
+        Self(PTEFlags::V.bits() as u64 | ((paddr.as_usize() as u64 >> 2) & Self::PHYS_ADDR_MASK))
+    }
+
+    fn paddr(&self) -> PhysAddr {
+        PhysAddr::from(((self.0 & Self::PHYS_ADDR_MASK) << 2) as usize)
+    }
+
+    fn flags(&self) -> MappingFlags {
+        let flags = PTEFlags::from_bits_truncate(self.0 as usize).into();
+        match self.0 & Self::PBMT_MASK {
+            Self::PBMT_NC => flags | MappingFlags::UNCACHED,
+            Self::PBMT_IO => flags | MappingFlags::DEVICE,
+            _ => flags,
+        }
+    }
+
+    fn set_paddr(&mut self, paddr: PhysAddr) {
+        self.0 = (self.0 & !Self::PHYS_ADDR_MASK)
+            | ((paddr.as_usize() as u64 >> 2) & Self::PHYS_ADDR_MASK);
+    }
+
+    fn set_flags(&mut self, flags: MappingFlags, _is_huge: bool) {
+        self.0 = (self.0 & Self::PHYS_ADDR_MASK) | Self::leaf_bits(flags);
+    }
+
+    fn bits(self) -> usize {
+        self.0 as usize
+    }
+
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+
+    fn is_present(&self) -> bool {
+        PTEFlags::from_bits_truncate(self.0 as usize).contains(PTEFlags::V)
+    }
+
+    fn is_huge(&self) -> bool {
+        PTEFlags::from_bits_truncate(self.0 as usize).intersects(PTEFlags::R | PTEFlags::X)
+    }
+
+    fn clear(&mut self) {
+        self.0 = 0;
+    }
+}
+
+impl fmt::Debug for PageTableEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PageTableEntry")
+            .field("raw", &self.0)
+            .field("paddr", &self.paddr())
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
+
+/// Forwards a call to the table or cursor of whichever mode is active.
+macro_rules! forward {
+    ($value:expr, $inner:ident => $call:expr) => {
+        match $value {
+            Self::Sv39($inner) => $call,
+            Self::Sv48($inner) => $call,
+        }
+    };
+}
+
+/// The riscv64 page table, in the mode Limine enabled: Sv48, or Sv39 on
+/// harts without it. Both use the same entries and differ only in the
+/// number of levels.
+pub enum PageTable {
+    Sv39(Sv39PageTable),
+    Sv48(Sv48PageTable),
+}
+
+impl PageTable {
+    /// Creates an empty page table for the active mode.
+    /// # Errors
+    /// when out of memory for the root table.
+    pub fn try_new() -> PagingResult<Self> {
+        Ok(if mode::levels() == 3 {
+            Self::Sv39(Sv39PageTable::try_new()?)
+        } else {
+            Self::Sv48(Sv48PageTable::try_new()?)
+        })
+    }
+
+    #[must_use]
+    pub const fn root_paddr(&self) -> PhysAddr {
+        forward!(self, table => table.root_paddr())
+    }
+
+    /// Returns the frame, flags and page size `vaddr` is mapped with.
+    /// # Errors
+    /// when `vaddr` is not mapped.
+    pub fn query(&self, vaddr: VirtAddr) -> PagingResult<(PhysAddr, MappingFlags, PageSize)> {
+        forward!(self, table => table.query(vaddr))
+    }
+
+    pub fn cursor(&mut self) -> PageTableCursor<'_> {
+        match self {
+            Self::Sv39(table) => PageTableCursor::Sv39(table.cursor()),
+            Self::Sv48(table) => PageTableCursor::Sv48(table.cursor()),
+        }
+    }
+}
+
+/// A cursor over a `PageTable`, which flushes the local TLB when dropped.
+pub enum PageTableCursor<'a> {
+    Sv39(Sv39PageTableCursor<'a>),
+    Sv48(Sv48PageTableCursor<'a>),
+}
+
+impl PageTableCursor<'_> {
+    /// Maps the page of `size` at `vaddr` to `target`.
+    /// # Errors
+    /// when the page is mapped already or out of memory for page tables.
+    pub fn map(
+        &mut self,
+        vaddr: VirtAddr,
+        target: PhysAddr,
+        size: PageSize,
+        flags: MappingFlags,
+    ) -> PagingResult {
+        forward!(self, cursor => cursor.map(vaddr, target, size, flags))
+    }
+
+    /// Points the page at `vaddr` to `paddr` with `flags`.
+    /// # Errors
+    /// when no table for `vaddr` exists.
+    pub fn remap(
+        &mut self,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        flags: MappingFlags,
+    ) -> PagingResult<PageSize> {
+        forward!(self, cursor => cursor.remap(vaddr, paddr, flags))
+    }
+
+    /// Changes the flags of the page at `vaddr`.
+    /// # Errors
+    /// when `vaddr` is not mapped.
+    pub fn protect(&mut self, vaddr: VirtAddr, flags: MappingFlags) -> PagingResult<PageSize> {
+        forward!(self, cursor => cursor.protect(vaddr, flags))
+    }
+
+    /// Unmaps the page at `vaddr`.
+    /// # Errors
+    /// when `vaddr` is not mapped.
+    pub fn unmap(&mut self, vaddr: VirtAddr) -> PagingResult<(PhysAddr, MappingFlags, PageSize)> {
+        forward!(self, cursor => cursor.unmap(vaddr))
+    }
+}
@@ -0,0 +1,218 @@
+//! Supervisor trap handling.
+//!
+//! `stvec` points at `trap_entry` in direct mode, so every trap lands
+//! there. Traps from user mode switch to the hart's trap stack, whose top
+//! `sscratch` holds; traps from the kernel stay on the interrupted stack.
+//! It saves the registers a Rust function may clobber on that stack, floating-point ones and `fcsr` included when the
+//! F extension is on, along with `sepc` and `sstatus` in case the handler
+//! traps again, and calls `trap_handler`. Page faults are decoded into a
+//! `fault::PageFault` and resolved by `fault::handle`; anything else is
+//! fatal, as no interrupts are enabled yet.
+use crate::memory::fault::{self, Access, PageFault};
+use crate::memory::walk;
+use core::arch::global_asm;
+use page_table_multiarch::MappingFlags;
+use riscv::register::{scause, stval, stvec};
+
+global_asm!(
+    ".section .text",
+    ".balign 4",
+    ".global trap_entry",
+    "trap_entry:",
+    // t0 = trap stack top, sscratch = t0. The two words below the top are
+    // scratch space for this, so a user-mode frame starts under them.
+    "csrrw t0, sscratch, t0",
+    "sd t1, -8(t0)",
+    "csrr t1, sstatus",
+    "andi t1, t1, 0x100",
+    "beqz t1, 2f",
+    "mv t1, sp",
+    "j 3f",
+    "2:",
+    "mv t1, sp",
+    "addi sp, t0, -16",
+    "3:",
+    "addi sp, sp, -320",
+    "sd t1, 144(sp)",
+    "ld t1, -8(t0)",
+    "csrrw t0, sscratch, t0",
+    "sd ra, 0(sp)",
+    "sd t0, 8(sp)",
+    "sd t1, 16(sp)",
+    "sd t2, 24(sp)",
+    "sd a0, 32(sp)",
+    "sd a1, 40(sp)",
+    "sd a2, 48(sp)",
+    "sd a3, 56(sp)",
+    "sd a4, 64(sp)",
+    "sd a5, 72(sp)",
+    "sd a6, 80(sp)",
+    "sd a7, 88(sp)",
+    "sd t3, 96(sp)",
+    "sd t4, 104(sp)",
+    "sd t5, 112(sp)",
+    "sd t6, 120(sp)",
+    "csrr t0, sepc",
+    "sd t0, 128(sp)",
+    "csrr t0, sstatus",
+    "sd t0, 136(sp)",
+    // The floating-point unit is only touched if it is on (`sstatus.FS`).
+    "srli t0, t0, 13",
+    "andi t0, t0, 3",
+    "beqz t0, 1f",
+    "fsd ft0, 160(sp)",
+    "fsd ft1, 168(sp)",
+    "fsd ft2, 176(sp)",
+    "fsd ft3, 184(sp)",
+    "fsd ft4, 192(sp)",
+    "fsd ft5, 200(sp)",
+    "fsd ft6, 208(sp)",
+    "fsd ft7, 216(sp)",
+    "fsd fa0, 224(sp)",
+    "fsd fa1, 232(sp)",
+    "fsd fa2, 240(sp)",
+    "fsd fa3, 248(sp)",
+    "fsd fa4, 256(sp)",
+    "fsd fa5, 264(sp)",
+    "fsd fa6, 272(sp)",
+    "fsd fa7, 280(sp)",
+    "fsd ft8, 288(sp)",
+    "fsd ft9, 296(sp)",
+    "fsd ft10, 304(sp)",
+    "fsd ft11, 312(sp)",
+    "frcsr t0",
+    "sd t0, 152(sp)",
+    "1:",
+    "mv a0, sp",
+    "call {handler}",
+    "ld t0, 136(sp)",
+    "srli t0, t0, 13",
+    "andi t0, t0, 3",
+    "beqz t0, 1f",
+    "fld ft0, 160(sp)",
+    "fld ft1, 168(sp)",
+    "fld ft2, 176(sp)",
+    "fld ft3, 184(sp)",
+    "fld ft4, 192(sp)",
+    "fld ft5, 200(sp)",
+    "fld ft6, 208(sp)",
+    "fld ft7, 216(sp)",
+    "fld fa0, 224(sp)",
+    "fld fa1, 232(sp)",
+    "fld fa2, 240(sp)",
+    "fld fa3, 248(sp)",
+    "fld fa4, 256(sp)",
+    "fld fa5, 264(sp)",
+    "fld fa6, 272(sp)",
+    "fld fa7, 280(sp)",
+    "fld ft8, 288(sp)",
+    "fld ft9, 296(sp)",
+    "fld ft10, 304(sp)",
+    "fld ft11, 312(sp)",
+    "ld t0, 152(sp)",
+    "fscsr t0",
+    "1:",
+    "ld t0, 128(sp)",
+    "csrw sepc, t0",
+    "ld t0, 136(sp)",
+    "csrw sstatus, t0",
+    "ld ra, 0(sp)",
+    "ld t0, 8(sp)",
+    "ld t1, 16(sp)",
+    "ld t2, 24(sp)",
+    "ld a0, 32(sp)",
+    "ld a1, 40(sp)",
+    "ld a2, 48(sp)",
+    "ld a3, 56(sp)",
+    "ld a4, 64(sp)",
+    "ld a5, 72(sp)",
+    "ld a6, 80(sp)",
+    "ld a7, 88(sp)",
+    "ld t3, 96(sp)",
+    "ld t4, 104(sp)",
+    "ld t5, 112(sp)",
+    "ld t6, 120(sp)",
+    "ld sp, 144(sp)",
+    "sret",
+    handler = sym trap_handler,
+);
+
+unsafe extern "C" {
+    fn trap_entry();
+}
+
+/// What `trap_entry` saves: `ra`, `t0`-`t2`, `a0`-`a7` and `t3`-`t6`, the
+/// trap CSRs, then `fcsr`, `ft0`-`ft7`, `fa0`-`fa7` and `ft8`-`ft11`.
+#[repr(C)]
+struct TrapFrame {
+    regs: [usize; 16],
+    sepc: usize,
+    sstatus: usize,
+    /// The interrupted stack pointer.
+    sp: usize,
+    fcsr: usize,
+    fregs: [u64; 20],
+}
+
+/// `sstatus.SPP`: the trap came from supervisor mode.
+const SSTATUS_SPP: usize = 1 << 8;
+const INSTRUCTION_PAGE_FAULT: usize = 12;
+const LOAD_PAGE_FAULT: usize = 13;
+const STORE_PAGE_FAULT: usize = 15;
+
+/// Returns whether `addr` is mapped, but without the permission `access`
+/// needs. RISC-V raises the same page faults for unmapped pages.
+fn denied(addr: usize, access: Access) -> bool {
+    let needed = match access {
+        Access::Read => MappingFlags::READ,
+        Access::Write => MappingFlags::WRITE,
+        Access::Execute => MappingFlags::EXECUTE,
+    };
+    walk::translate_active(addr).is_some_and(|translation| !translation.flags.contains(needed))
+}
+
+extern "C" fn trap_handler(frame: &mut TrapFrame) {
+    let cause = scause::read();
+    let addr = stval::read();
+    let access = match cause.code() {
+        _ if cause.is_interrupt() => None,
+        INSTRUCTION_PAGE_FAULT => Some(Access::Execute),
+        LOAD_PAGE_FAULT => Some(Access::Read),
+        STORE_PAGE_FAULT => Some(Access::Write),
+        _ => None,
+    };
+    let Some(access) = access else {
+        panic!(
+            "unexpected trap, scause {:#x}, stval {addr:#x}, sepc {:#x}",
+            cause.bits(),
+            frame.sepc
+        );
+    };
+    let fault = PageFault {
+        addr,
+        access,
+        present: denied(addr, access),
+        user: frame.sstatus & SSTATUS_SPP == 0,
+    };
+    if let Err(error) = fault::handle(&fault) {
+        panic!(
+            "Page fault at {addr:#x} ({error:?}), {access:?}, sepc {:#x}",
+            frame.sepc
+        );
+    }
+    // The hart may have cached the invalid entry that faulted.
+    super::flush_tlb_local(None, Some(addr));
+}
+
+/// Points `stvec` at `trap_entry` on the calling hart, and `sscratch` at
+/// a new kernel stack for the traps it takes from user mode.
+pub fn init() {
+    let stack_top = crate::memory::alloc_kernel_stack();
+    unsafe {
+        core::arch::asm!("csrw sscratch, {}", in(reg) stack_top, options(nomem, nostack));
+        stvec::write(stvec::Stvec::new(
+            trap_entry as *const () as usize,
+            stvec::TrapMode::Direct,
+        ));
+    }
+}
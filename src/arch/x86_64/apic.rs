@@ -0,0 +1,106 @@
+//! Local APIC, used to send and acknowledge inter-processor interrupts.
+//!
+//! Works in either mode the firmware or bootloader left the APIC in: x2APIC
+//! registers are MSRs, xAPIC registers are memory-mapped and reached
+//! through an `ioremap` mapping shared by all processors (each processor
+//! sees its own APIC at the same physical address).
+use crate::memory::mmio;
+use memory_addr::PhysAddr;
+use spin::Once;
+use x86_64::registers::model_specific::Msr;
+
+/// Vector of the TLB shootdown IPI.
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xf0;
+/// Vector the APIC raises for spurious interrupts.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+/// `IA32_APIC_BASE` bit set when the APIC is in x2APIC mode.
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+/// First x2APIC MSR; register `offset` of the xAPIC is MSR
+/// `X2APIC_MSR_BASE + offset / 16`.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+// Register offsets in the xAPIC page.
+const REG_EOI: usize = 0xb0;
+const REG_SPURIOUS: usize = 0xf0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+
+/// Spurious vector register bit that software-enables the APIC.
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+/// ICR bits: level assert, and delivery still pending (xAPIC only).
+const ICR_ASSERT: u32 = 1 << 14;
+const ICR_PENDING: u32 = 1 << 12;
+
+/// Virtual address of the xAPIC registers, when not in x2APIC mode.
+static XAPIC: Once<usize> = Once::new();
+
+fn x2apic() -> bool {
+    // Safety: IA32_APIC_BASE exists on every x86_64 processor.
+    unsafe { Msr::new(IA32_APIC_BASE).read() & APIC_BASE_X2APIC != 0 }
+}
+
+fn read(reg: usize) -> u32 {
+    match XAPIC.get() {
+        // Safety: the register page is mapped for good by `init`.
+        Some(&base) => unsafe { ((base + reg) as *const u32).read_volatile() },
+        // Safety: x2APIC registers are MSRs.
+        None => unsafe { Msr::new(X2APIC_MSR_BASE + (reg / 16) as u32).read() as u32 },
+    }
+}
+
+fn write(reg: usize, value: u32) {
+    match XAPIC.get() {
+        // Safety: as in `read`.
+        Some(&base) => unsafe { ((base + reg) as *mut u32).write_volatile(value) },
+        // Safety: as in `read`.
+        None => unsafe { Msr::new(X2APIC_MSR_BASE + (reg / 16) as u32).write(u64::from(value)) },
+    }
+}
+
+/// Software-enables the calling processor's APIC. The BSP must call this
+/// before any application processor does.
+/// # Panics
+/// when the xAPIC registers cannot be mapped.
+pub fn init() {
+    if !x2apic() {
+        XAPIC.call_once(|| {
+            // Safety: IA32_APIC_BASE exists on every x86_64 processor.
+            let base = unsafe { Msr::new(IA32_APIC_BASE).read() } & APIC_BASE_ADDRESS_MASK;
+            mmio::ioremap(PhysAddr::from(base as usize), 0x1000)
+                .expect("apic: failed to map the local APIC")
+                .leak() as usize
+        });
+    }
+    write(
+        REG_SPURIOUS,
+        SPURIOUS_APIC_ENABLE | u32::from(SPURIOUS_VECTOR),
+    );
+}
+
+/// Sends a fixed interrupt with `vector` to the processor with local APIC
+/// id `apic_id`.
+pub fn send_ipi(apic_id: u64, vector: u8) {
+    let low = ICR_ASSERT | u32::from(vector);
+    if XAPIC.get().is_some() {
+        write(REG_ICR_HIGH, (apic_id as u32) << 24);
+        write(REG_ICR_LOW, low);
+        while read(REG_ICR_LOW) & ICR_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    } else {
+        // The x2APIC ICR is a single 64-bit MSR with the destination on top.
+        // Safety: x2APIC registers are MSRs.
+        unsafe {
+            Msr::new(X2APIC_MSR_BASE + (REG_ICR_LOW / 16) as u32)
+                .write((apic_id << 32) | u64::from(low));
+        }
+    }
+}
+
+/// Signals the end of the interrupt being handled.
+pub fn eoi() {
+    write(REG_EOI, 0);
+}
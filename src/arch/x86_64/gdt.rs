@@ -51,3 +51,12 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Loads the shared GDT on an application processor. The TSS is not loaded,
+/// as its descriptor is already marked busy by the bootstrap processor.
+pub fn init_ap() {
+    GDT.0.load();
+    unsafe {
+        CS::set_reg(GDT.1.code_selector);
+    }
+}
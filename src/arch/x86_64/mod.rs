@@ -1,100 +1,114 @@
 //! x86_64-specific architecture code.
+use crate::memory::address_space::user_end;
+use crate::memory::asid::{Asid, KERNEL_ASID};
+use crate::memory::walk;
 use core::arch::asm;
+use core::sync::atomic::{AtomicU32, Ordering};
 use memory_addr::{PhysAddr, VirtAddr};
 use page_table_multiarch::{MappingFlags, PageSize};
 use x86_64::instructions;
-use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::instructions::tlb::{self, InvPcidCommand, Pcid};
+use x86_64::registers::control::{Cr3, Cr3Flags, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::GsBase;
+pub mod apic;
 pub mod gdt;
 pub mod idt;
 pub mod paging;
 
 pub type PageTable = paging::PageTable;
 pub type PageTableEntry = paging::PageTableEntry;
+pub type PagingMetaData = paging::PagingMetaData;
 
-/// Walk the currently active 4-level page tables to translate a virtual
-/// address to its physical address. Used before the CR3 switch to find
-/// the physical frames backing the Limine-provided stack.
-fn virt_to_phys(virt: usize) -> Option<usize> {
-    let cr3: usize;
-    unsafe { asm!("mov {}, cr3", out(reg) cr3) };
-    let hhdm = crate::memory::FRAME_ALLOCATOR.read().hhdm_offset;
-
-    let pml4 = unsafe { &*((cr3 + hhdm) as *const [u64; 512]) };
-    let pml4e = pml4[(virt >> 39) & 0x1FF];
-    if pml4e & 1 == 0 {
-        return None;
-    }
-    if pml4e & (1 << 7) != 0 {
-        return Some((pml4e as usize & 0x000FFFFFC0000000) | (virt & 0x3FFFFFFF));
+/// Halts the CPU.
+///
+/// Uses the `hlt` instruction to put the CPU into a low-power state until
+/// the next interrupt, then returns; callers loop around it.
+pub fn holt() {
+    unsafe {
+        asm!("hlt");
     }
+}
 
-    let pdpt = unsafe { &*(((pml4e as usize & 0x000FFFFFFFFFF000) + hhdm) as *const [u64; 512]) };
-    let pdpte = pdpt[(virt >> 30) & 0x1FF];
-    if pdpte & 1 == 0 {
-        return None;
-    }
-    if pdpte & (1 << 7) != 0 {
-        return Some((pdpte as usize & 0x000FFFFFC0000000) | (virt & 0x3FFFFFFF));
-    }
+/// Records `index` as the current processor's index. The index lives in the
+/// GS base MSR, which nothing else uses yet.
+pub fn set_cpu_index(index: usize) {
+    GsBase::write(x86_64::VirtAddr::new(index as u64));
+}
 
-    let pd = unsafe { &*(((pdpte as usize & 0x000FFFFFFFFFF000) + hhdm) as *const [u64; 512]) };
-    let pde = pd[(virt >> 21) & 0x1FF];
-    if pde & 1 == 0 {
-        return None;
-    }
-    if pde & (1 << 7) != 0 {
-        return Some((pde as usize & 0x000FFFFFFFE00000) | (virt & 0x1FFFFF));
-    }
+/// Returns the index recorded by `set_cpu_index` on this processor.
+#[must_use]
+pub fn cpu_index() -> usize {
+    GsBase::read().as_u64() as usize
+}
+
+/// CPUID.01H:ECX.RDRAND.
+const CPUID_RDRAND: u32 = 1 << 30;
 
-    let pt = unsafe { &*(((pde as usize & 0x000FFFFFFFFFF000) + hhdm) as *const [u64; 512]) };
-    let pte = pt[(virt >> 12) & 0x1FF];
-    if pte & 1 == 0 {
+/// Returns 64 random bits from RDRAND, or `None` if the processor lacks it
+/// or it keeps failing.
+#[must_use]
+pub fn random_u64() -> Option<u64> {
+    if core::arch::x86_64::__cpuid(1).ecx & CPUID_RDRAND == 0 {
         return None;
     }
-
-    Some((pte as usize & 0x000FFFFFFFFFF000) | (virt & 0xFFF))
-}
-
-/// Halts the CPU.
-///
-/// This function enters an infinite loop and uses the `hlt` instruction
-/// to put the CPU into a low-power state until the next interrupt.
-pub fn holt() {
-    unsafe {
-        asm!("hlt");
+    // Intel recommends 10 retries before giving up on an underflow.
+    for _ in 0..10 {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!(
+                "rdrand {value}",
+                "setc {ok}",
+                value = out(reg) value,
+                ok = out(reg_byte) ok,
+                options(nomem, nostack),
+            );
+        }
+        if ok != 0 {
+            return Some(value);
+        }
     }
+    None
 }
 
-/// Initialization code for `x86_64`.
-/// this function performs the initialization code for the processor.
-/// # Panics
-/// when initialization fails, we will panic here as the continuation of everything is impossible.
-pub fn init() {
-    instructions::interrupts::disable();
-    gdt::init();
-    idt::init();
+/// Returns the time stamp counter.
+#[must_use]
+pub fn timestamp() -> u64 {
+    // Safety: RDTSC is available on every x86_64 processor.
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
 
-    // Map the Limine-provided stack pages into our new page table so the
-    // stack remains accessible after the CR3 switch.
+/// Map the pages of the stack we are currently running on into the kernel
+/// page table, so the stack remains accessible after the CR3 switch.
+fn map_current_stack() {
     let rsp: usize;
     unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
     let stack_top = (rsp + 0xFFF) & !0xFFF;
     let stack_base = stack_top.saturating_sub(128 * 1024);
     let flags = MappingFlags::READ | MappingFlags::WRITE;
-    {
-        let mut mapper = crate::memory::PAGE_MAPPER.write();
-        let mut addr = stack_base;
-        while addr < stack_top {
-            if let Some(phys) = virt_to_phys(addr) {
-                let vaddr = VirtAddr::from(addr);
-                let paddr = PhysAddr::from(phys);
-                let _ = mapper.cursor().map(vaddr, paddr, PageSize::Size4K, flags);
-            }
-            addr += crate::memory::PAGE_SIZE;
+    let mut mapper = crate::memory::PAGE_MAPPER.write();
+    let mut addr = stack_base;
+    while addr < stack_top {
+        if let Some(translation) = walk::translate_active(addr) {
+            let vaddr = VirtAddr::from(addr);
+            let _ = mapper
+                .cursor()
+                .map(vaddr, translation.paddr, PageSize::Size4K, flags);
         }
+        addr += crate::memory::PAGE_SIZE;
     }
+}
 
-    // page table is ready. load into Cr3
+/// Returns the root of the page table loaded in CR3, which translates
+/// every address.
+#[must_use]
+pub fn active_root(_vaddr: usize) -> PhysAddr {
+    let (frame, _) = Cr3::read_raw();
+    PhysAddr::from(frame.start_address().as_u64() as usize)
+}
+
+/// Load the kernel page table into CR3.
+fn load_page_table() {
     let mapper = crate::memory::PAGE_MAPPER.read();
     let root_paddr = mapper.root_paddr();
     let frame = x86_64::structures::paging::PhysFrame::from_start_address(x86_64::PhysAddr::new(
@@ -104,19 +118,155 @@ pub fn init() {
     // This is the point of no return. After this instruction, the CPU
     // uses our new page table for all memory access.
     unsafe { Cr3::write(frame, Cr3Flags::empty()) };
-    drop(mapper);
-    instructions::interrupts::enable();
+    // Kernel-half entries are global from here on. Setting PGE flushes the
+    // bootloader's global entries too.
+    unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::PAGE_GLOBAL)) };
+}
 
-    // Set up a guard page below the kernel stack to catch stack overflows.
-    // Read the current stack pointer and unmap the page immediately below
-    // the stack's estimated bottom (128 KiB below the top).
-    let rsp: usize;
-    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
-    let stack_top = (rsp + 0xFFF) & !0xFFF;
-    let stack_bottom = stack_top.saturating_sub(128 * 1024);
-    let guard_page = stack_bottom.saturating_sub(0x1000);
-    let mut mapper = crate::memory::PAGE_MAPPER.write();
-    let _ = mapper.cursor().unmap(VirtAddr::from(guard_page));
+/// Flushes every TLB entry of this processor, global ones included, by
+/// toggling CR4.PGE.
+fn flush_global() {
+    // Safety: PGE was set by `load_page_table`; clearing and setting it
+    // again only flushes the TLB.
+    unsafe {
+        Cr4::update(|flags| flags.remove(Cr4Flags::PAGE_GLOBAL));
+        Cr4::update(|flags| flags.insert(Cr4Flags::PAGE_GLOBAL));
+    }
+}
+
+/// Number of PCID bits in use: 12 once PCIDs are enabled, 0 without PCID
+/// or INVPCID support.
+static PCID_BITS: AtomicU32 = AtomicU32::new(0);
+
+/// CPUID.01H:ECX.PCID and CPUID.(EAX=07H, ECX=0):EBX.INVPCID.
+const CPUID_PCID: u32 = 1 << 17;
+const CPUID_INVPCID: u32 = 1 << 10;
+
+/// Enables PCIDs on this processor if it supports both PCID and INVPCID;
+/// without INVPCID another address space's entries could not be
+/// invalidated. Must run while CR3 holds PCID 0.
+fn enable_pcid() {
+    let features = core::arch::x86_64::__cpuid(1);
+    let extended = core::arch::x86_64::__cpuid_count(7, 0);
+    if features.ecx & CPUID_PCID == 0 || extended.ebx & CPUID_INVPCID == 0 {
+        return;
+    }
+    // Safety: CR3 holds PCID 0, so setting PCIDE is allowed.
+    unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::PCID)) };
+    PCID_BITS.store(12, Ordering::Relaxed);
+}
+
+/// Returns the number of address space identifier (PCID) bits in use.
+#[must_use]
+pub fn asid_bits() -> u32 {
+    PCID_BITS.load(Ordering::Relaxed)
+}
+
+fn pcid(asid: Asid) -> Pcid {
+    Pcid::new(asid).expect("x86_64: PCID out of range")
+}
+
+/// Loads the page table rooted at `root` into CR3, tagged with PCID `asid`.
+/// With PCIDs the TLB keeps the entries of other address spaces; without,
+/// it is flushed. Kernel-half entries are shared by every address space, so
+/// the kernel stays mapped.
+///
+/// # Safety
+/// `root` must be the root of a page table that shares the kernel half of
+/// the kernel page table, and must stay alive while it is loaded. TLB
+/// entries tagged with `asid` must belong to this page table.
+pub unsafe fn set_user_page_table(root: PhysAddr, asid: Asid) {
+    let frame = x86_64::structures::paging::PhysFrame::from_start_address(x86_64::PhysAddr::new(
+        root.as_usize() as u64,
+    ))
+    .expect("x86_64: page table root is not page-aligned");
+    if asid_bits() > 0 {
+        unsafe { Cr3::write_pcid_no_flush(frame, pcid(asid)) };
+    } else {
+        unsafe { Cr3::write(frame, Cr3Flags::empty()) };
+    }
+}
+
+/// Invalidates this processor's TLB entries for the page at `vaddr`, or for
+/// every page if `None`, in address space `asid`, or in every address space
+/// if `None`. Returns `true` if more than that page was invalidated, so that
+/// callers can skip the pages that follow.
+///
+/// Kernel-half pages are global, so `invlpg` drops them whatever PCID they
+/// were used under. The lower half of the kernel page table (the identity
+/// map) is not, and is only cached under PCID 0.
+pub fn flush_tlb_local(asid: Option<Asid>, vaddr: Option<usize>) -> bool {
+    let Some(vaddr) = vaddr else {
+        match asid {
+            Some(asid) if asid_bits() > 0 => {
+                // Safety: PCIDs are only enabled when INVPCID is supported.
+                unsafe { tlb::flush_pcid(InvPcidCommand::Single(pcid(asid))) };
+            }
+            // Only user entries are non-global.
+            Some(_) => tlb::flush_all(),
+            None => flush_global(),
+        }
+        return true;
+    };
+    if asid_bits() == 0 || vaddr >= user_end() {
+        tlb::flush(x86_64::VirtAddr::new(vaddr as u64));
+    } else {
+        let asid = asid.unwrap_or(KERNEL_ASID);
+        let command = InvPcidCommand::Address(x86_64::VirtAddr::new(vaddr as u64), pcid(asid));
+        // Safety: as above.
+        unsafe { tlb::flush_pcid(command) };
+    }
+    false
+}
+
+/// Initialization code for `x86_64`.
+/// this function performs the initialization code for the processor.
+/// # Panics
+/// when initialization fails, we will panic here as the continuation of everything is impossible.
+pub fn init() {
+    instructions::interrupts::disable();
+    gdt::init();
+    idt::init();
+
+    // page table is ready. map the Limine-provided stack and load into Cr3
+    map_current_stack();
+    load_page_table();
+    enable_pcid();
+    apic::init();
+    instructions::interrupts::enable();
 
     log::info!("x86_64 architecture initialized.");
 }
+
+/// Initialization code for an application processor.
+/// Loads the kernel GDT, IDT and page table so the processor no longer
+/// depends on anything the bootloader set up for it.
+pub fn init_ap() {
+    gdt::init_ap();
+    idt::init_ap();
+    map_current_stack();
+    load_page_table();
+    enable_pcid();
+    apic::init();
+    // Take TLB shootdown IPIs from here on.
+    instructions::interrupts::enable();
+}
+
+/// Switches to the stack ending at `stack_top` and calls `entry` on it.
+///
+/// # Safety
+/// `stack_top` must be the 16-byte aligned top of a mapped, unused stack.
+/// Nothing on the current stack may be referenced afterwards.
+pub unsafe fn switch_stack(stack_top: usize, entry: extern "C" fn() -> !) -> ! {
+    unsafe {
+        asm!(
+            "mov rsp, {stack}",
+            "xor rbp, rbp",
+            "call {entry}",
+            "ud2",
+            stack = in(reg) stack_top,
+            entry = in(reg) entry,
+            options(noreturn)
+        )
+    }
+}
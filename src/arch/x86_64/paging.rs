@@ -1,9 +1,206 @@
 //! x86_64-specific paging implementation and initialization.
 
+use crate::memory::address_space::user_end;
 use crate::memory::paging::AmirOSPagingHandler;
-use page_table_entry::x86_64::X64PTE;
-use page_table_multiarch::x86_64::X64PageTable;
+use core::fmt;
+use memory_addr::{PhysAddr, VirtAddr};
+use page_table_entry::x86_64::PTF;
+use page_table_multiarch::{
+    GenericPTE, MappingFlags, PageSize, PageTable64, PageTable64Cursor, PagingResult,
+};
 
-/// type aliases for `x86_64` paging
-pub type PageTable = X64PageTable<AmirOSPagingHandler>;
-pub type PageTableEntry = X64PTE;
+type X64PageTable = PageTable64<PagingMetaData, PageTableEntry, AmirOSPagingHandler>;
+type X64PageTableCursor<'a> =
+    PageTable64Cursor<'a, PagingMetaData, PageTableEntry, AmirOSPagingHandler>;
+
+/// Marks a leaf mapping global, so its TLB entries are kept across address
+/// space switches and shared by every PCID. Not one of the generic flags:
+/// `PageTableCursor` adds it to kernel-half mappings, `PageTableEntry` turns
+/// it into `PTF::GLOBAL` and reports it back, so it survives remapping and
+/// huge page splits and merges.
+const GLOBAL: MappingFlags = MappingFlags::from_bits_retain(1 << 6);
+
+/// Returns `flags` with `GLOBAL` added if `vaddr` is in the kernel half,
+/// which every address space shares. The lower half is private to each
+/// address space, including the kernel page table's identity map.
+fn with_global(vaddr: VirtAddr, flags: MappingFlags) -> MappingFlags {
+    if vaddr.as_usize() >= user_end() && !flags.contains(MappingFlags::USER) {
+        flags | GLOBAL
+    } else {
+        flags
+    }
+}
+
+/// Like `X64PagingMetaData`, but a full flush also drops global entries.
+pub struct PagingMetaData;
+
+impl page_table_multiarch::PagingMetaData for PagingMetaData {
+    const LEVELS: usize = 4;
+    const PA_MAX_BITS: usize = 52;
+    const VA_MAX_BITS: usize = 48;
+
+    type VirtAddr = VirtAddr;
+
+    fn flush_tlb(vaddr: Option<VirtAddr>) {
+        super::flush_tlb_local(None, vaddr.map(VirtAddr::as_usize));
+    }
+}
+
+/// An x86_64 page table entry. Unlike `X64PTE` it carries the global bit,
+/// as `GLOBAL` in its flags.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct PageTableEntry(u64);
+
+impl PageTableEntry {
+    /// Bits 12..52.
+    const PHYS_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+    fn leaf_bits(flags: MappingFlags, is_huge: bool) -> u64 {
+        let mut bits = PTF::from(flags);
+        if is_huge {
+            bits |= PTF::HUGE_PAGE;
+        }
+        if flags.contains(GLOBAL) {
+            bits |= PTF::GLOBAL;
+        }
+        bits.bits()
+    }
+}
+
+impl GenericPTE for PageTableEntry {
+    fn new_page(paddr: PhysAddr, flags: MappingFlags, is_huge: bool) -> Self {
+        Self(Self::leaf_bits(flags, is_huge) | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK))
+    }
+
+    fn new_table(paddr: PhysAddr) -> Self {
+        let flags = PTF::PRESENT | PTF::WRITABLE | PTF::USER_ACCESSIBLE;
+        Self(flags.bits() | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK))
+    }
+
+    fn paddr(&self) -> PhysAddr {
+        PhysAddr::from((self.0 & Self::PHYS_ADDR_MASK) as usize)
+    }
+
+    fn flags(&self) -> MappingFlags {
+        let bits = PTF::from_bits_truncate(self.0);
+        let flags = MappingFlags::from(bits);
+        if bits.contains(PTF::GLOBAL) {
+            flags | GLOBAL
+        } else {
+            flags
+        }
+    }
+
+    fn set_paddr(&mut self, paddr: PhysAddr) {
+        self.0 =
+            (self.0 & !Self::PHYS_ADDR_MASK) | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK);
+    }
+
+    fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
+        self.0 = (self.0 & Self::PHYS_ADDR_MASK) | Self::leaf_bits(flags, is_huge);
+    }
+
+    fn bits(self) -> usize {
+        self.0 as usize
+    }
+
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+
+    fn is_present(&self) -> bool {
+        PTF::from_bits_truncate(self.0).contains(PTF::PRESENT)
+    }
+
+    fn is_huge(&self) -> bool {
+        PTF::from_bits_truncate(self.0).contains(PTF::HUGE_PAGE)
+    }
+
+    fn clear(&mut self) {
+        self.0 = 0;
+    }
+}
+
+impl fmt::Debug for PageTableEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PageTableEntry")
+            .field("raw", &self.0)
+            .field("paddr", &self.paddr())
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
+
+/// The x86_64 page table. Kernel-half mappings made through its cursor are
+/// global.
+pub struct PageTable(X64PageTable);
+
+impl PageTable {
+    /// Creates an empty page table.
+    /// # Errors
+    /// when out of memory for the root table.
+    pub fn try_new() -> PagingResult<Self> {
+        X64PageTable::try_new().map(Self)
+    }
+
+    #[must_use]
+    pub const fn root_paddr(&self) -> PhysAddr {
+        self.0.root_paddr()
+    }
+
+    /// Returns the frame, flags and page size `vaddr` is mapped with.
+    /// # Errors
+    /// when `vaddr` is not mapped.
+    pub fn query(&self, vaddr: VirtAddr) -> PagingResult<(PhysAddr, MappingFlags, PageSize)> {
+        self.0.query(vaddr)
+    }
+
+    pub fn cursor(&mut self) -> PageTableCursor<'_> {
+        PageTableCursor(self.0.cursor())
+    }
+}
+
+/// A cursor over a `PageTable`, which flushes the local TLB when dropped.
+pub struct PageTableCursor<'a>(X64PageTableCursor<'a>);
+
+impl PageTableCursor<'_> {
+    /// Maps the page of `size` at `vaddr` to `target`.
+    /// # Errors
+    /// when the page is mapped already or out of memory for page tables.
+    pub fn map(
+        &mut self,
+        vaddr: VirtAddr,
+        target: PhysAddr,
+        size: PageSize,
+        flags: MappingFlags,
+    ) -> PagingResult {
+        self.0.map(vaddr, target, size, with_global(vaddr, flags))
+    }
+
+    /// Points the page at `vaddr` to `paddr` with `flags`.
+    /// # Errors
+    /// when no table for `vaddr` exists.
+    pub fn remap(
+        &mut self,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        flags: MappingFlags,
+    ) -> PagingResult<PageSize> {
+        self.0.remap(vaddr, paddr, with_global(vaddr, flags))
+    }
+
+    /// Changes the flags of the page at `vaddr`.
+    /// # Errors
+    /// when `vaddr` is not mapped.
+    pub fn protect(&mut self, vaddr: VirtAddr, flags: MappingFlags) -> PagingResult<PageSize> {
+        self.0.protect(vaddr, with_global(vaddr, flags))
+    }
+
+    /// Unmaps the page at `vaddr`.
+    /// # Errors
+    /// when `vaddr` is not mapped.
+    pub fn unmap(&mut self, vaddr: VirtAddr) -> PagingResult<(PhysAddr, MappingFlags, PageSize)> {
+        self.0.unmap(vaddr)
+    }
+}
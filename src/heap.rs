@@ -1,72 +1,365 @@
+use buddy_system_allocator::Heap as BuddyHeap;
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr::NonNull;
 use core::sync::atomic::{AtomicBool, Ordering};
-use memory_addr::{PhysAddr, VirtAddr};
-use page_table_multiarch::{MappingFlags, PageSize};
-use slab_allocator_rs::{Heap as SlabHeap, HeapAllocator, NUM_OF_SLABS};
+use page_table_multiarch::MappingFlags;
 use spin::Mutex;
 
-use crate::memory::PAGE_SIZE;
+use crate::arch;
+use crate::memory::frame_cache::MAX_CPUS;
+use crate::memory::stats::FramePurpose;
+use crate::memory::tlb::TlbBatch;
+use crate::memory::{PAGE_SIZE, PAGE_SIZE_2M, early, huge, walk};
 
-/// Number of pages to grow each slab by on allocation failure.
+/// Object sizes served from per-page slabs. Larger requests, and requests
+/// aligned beyond their size class, go to the buddy allocator.
+const CLASS_SIZES: [usize; 7] = [64, 128, 256, 512, 1024, 2048, 4096];
+const NUM_CLASSES: usize = CLASS_SIZES.len();
+/// The heap is split in equal partitions: one per size class, then one for
+/// the buddy allocator.
+const NUM_PARTITIONS: usize = NUM_CLASSES + 1;
+/// Smallest amount to grow the buddy allocator by.
 const GROW_CHUNK: usize = 4 * PAGE_SIZE; // 16 KiB
+/// Objects a magazine can hold.
+const MAGAZINE_SIZE: usize = 32;
+/// Objects moved between a magazine and its size class at once.
+const BATCH: usize = MAGAZINE_SIZE / 2;
+/// Object pairs `self_test` allocates per size class.
+const SELF_TEST_PAIRS: usize = 128;
 
+/// Backs the pages of `start..start + size` that are not mapped yet, with
+/// huge pages where the range is large and aligned enough.
 fn ensure_range_mapped(start: *mut u8, size: usize) -> bool {
-    use free_list::PageLayout;
-
     let start_page = (start as usize) & !(PAGE_SIZE - 1);
     let end_page = ((start as usize + size - 1) & !(PAGE_SIZE - 1)) + PAGE_SIZE;
 
-    let Ok(layout) = PageLayout::from_size_align(PAGE_SIZE, PAGE_SIZE) else {
+    let Ok(mapped) = huge::map_zeroed(
+        start_page,
+        end_page,
+        MappingFlags::READ | MappingFlags::WRITE,
+        FramePurpose::Heap,
+    ) else {
         return false;
     };
+    // 2 MiB ranges of small pages the new pages completed may map a
+    // contiguous block, which then fits a single huge page. Single pages,
+    // such as a new slab page, are left alone: checking the window after
+    // each of them costs more than the merge gains.
+    if mapped && end_page - start_page > PAGE_SIZE {
+        let mut window = start_page & !(PAGE_SIZE_2M - 1);
+        while window < end_page {
+            huge::merge(window);
+            window += PAGE_SIZE_2M;
+        }
+    }
+    true
+}
 
-    let mut page = start_page;
-    while page < end_page {
-        let page_vaddr = VirtAddr::from(page);
-
-        // Allocate a physical frame. We must do this *before* locking
-        // PAGE_MAPPER because cursor.map() may internally lock
-        // FRAME_ALLOCATOR (via AmirOSPagingHandler::alloc_frames) to
-        // allocate page-table pages.
-        let paddr = {
-            let mut frame_alloc = crate::memory::FRAME_ALLOCATOR.write();
-            match frame_alloc.allocate(layout) {
-                Ok(range) => PhysAddr::from(range.start()),
-                Err(_) => return false,
-            }
+/// Unmaps the pages of `start..end` and frees their frames once no
+/// processor can reach them any more. Huge pages that reach past the range
+/// are split first, so the rest of them stays mapped.
+fn unmap_range(start: usize, end: usize) {
+    let mut batch = TlbBatch::kernel();
+    huge::unmap(start, end, &mut batch, Some(FramePurpose::Heap));
+    batch.flush();
+}
+
+/// Returns the size class serving `layout`, if any.
+fn class_index(layout: &Layout) -> Option<usize> {
+    CLASS_SIZES
+        .iter()
+        .position(|&size| layout.size() <= size && layout.align() <= size)
+}
+
+/// Descriptor of a page in a size class partition. A zeroed descriptor is a
+/// page that was never carved.
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct PageInfo {
+    /// Objects handed out from the page.
+    live: u16,
+    /// Offset of the first free object plus one, 0 if none is free. Each
+    /// free object starts with the same encoding of the next one.
+    free: u16,
+    /// Next page in the partial or the reclaimed list, as a page number.
+    next: u32,
+    /// Previous page in the partial list.
+    prev: u32,
+}
+
+/// One size class. Its partition starts with a descriptor for each of its
+/// pages, which the fault handler backs on demand like the rest of the
+/// heap, followed by the pages objects are carved from.
+///
+/// A page is mapped while it holds live objects. Pages with free objects
+/// as well are on the partial list, which allocations take from; a page
+/// that loses its last live object is unmapped, its frame freed, and it
+/// goes on the reclaimed list to be carved again before new pages are.
+/// Free objects are only linked within their page, so unmapping one never
+/// breaks a list.
+struct SizeClass {
+    /// Size of each object.
+    size: usize,
+    /// First address of the partition and of the descriptor table.
+    base: usize,
+    /// First page never handed out.
+    next: usize,
+    /// End of the partition.
+    end: usize,
+    /// Head of the partial list, as a page number, 0 if empty.
+    partial: u32,
+    /// Head of the reclaimed list, as a page number, 0 if empty.
+    reclaimed: u32,
+}
+
+impl SizeClass {
+    const fn empty() -> Self {
+        Self {
+            size: 0,
+            base: 0,
+            next: 0,
+            end: 0,
+            partial: 0,
+            reclaimed: 0,
+        }
+    }
+
+    fn init(&mut self, size: usize, base: usize, partition_size: usize) {
+        let table =
+            (partition_size / PAGE_SIZE * size_of::<PageInfo>()).next_multiple_of(PAGE_SIZE_2M);
+        *self = Self {
+            size,
+            base,
+            next: base + table,
+            end: base + partition_size,
+            partial: 0,
+            reclaimed: 0,
         };
+    }
 
-        // Map the page under PAGE_MAPPER. If another thread or the page
-        // fault handler already mapped this page (race window between the
-        // allocation above and here), cursor.map() returns AlreadyMapped
-        // which is safe to ignore — we just leak this one frame rather
-        // than risk a TOCTOU overwrite.
+    /// Returns the number lists use for `page`, counting from 1.
+    fn number(&self, page: usize) -> u32 {
+        u32::try_from((page - self.base) / PAGE_SIZE + 1).expect("heap: partition too large")
+    }
+
+    /// Returns the page numbered `number`.
+    fn page(&self, number: u32) -> usize {
+        self.base + (number as usize - 1) * PAGE_SIZE
+    }
+
+    /// Returns the descriptor of `page`.
+    fn info(&mut self, page: usize) -> &mut PageInfo {
+        let index = (page - self.base) / PAGE_SIZE;
+        // Safety: the table lies in the heap, whose pages are mapped on
+        // first touch, and is only reached through the locked class.
+        unsafe { &mut *(self.base as *mut PageInfo).add(index) }
+    }
+
+    /// Puts `page` at the head of the partial list.
+    fn push_partial(&mut self, page: usize) {
+        let number = self.number(page);
+        let head = self.partial;
+        if head != 0 {
+            let head_page = self.page(head);
+            self.info(head_page).prev = number;
+        }
+        let info = self.info(page);
+        info.next = head;
+        info.prev = 0;
+        self.partial = number;
+    }
+
+    /// Takes `page` off the partial list.
+    fn unlink_partial(&mut self, page: usize) {
+        let PageInfo { next, prev, .. } = *self.info(page);
+        if prev == 0 {
+            self.partial = next;
+        } else {
+            let prev_page = self.page(prev);
+            self.info(prev_page).next = next;
+        }
+        if next != 0 {
+            let next_page = self.page(next);
+            self.info(next_page).prev = prev;
+        }
+    }
+
+    /// Hands out free objects from partial pages into `objects` until it
+    /// is full or no page has any left. Returns how many it took.
+    fn pop_objects(&mut self, objects: &mut [usize]) -> usize {
+        let mut count = 0;
+        while count < objects.len()
+            && let Some(object) = self.pop_object()
         {
-            let mut mapper = crate::memory::PAGE_MAPPER.write();
-            let _ = mapper.cursor().map(
-                page_vaddr,
-                paddr,
-                PageSize::Size4K,
-                MappingFlags::READ | MappingFlags::WRITE,
-            );
+            objects[count] = object;
+            count += 1;
+        }
+        count
+    }
+
+    /// Hands out a free object from the first partial page.
+    fn pop_object(&mut self) -> Option<usize> {
+        if self.partial == 0 {
+            return None;
         }
+        let page = self.page(self.partial);
+        let info = self.info(page);
+        let object = page + usize::from(info.free - 1);
+        // Safety: the page is mapped while it has live objects, and its
+        // free objects hold the link to the next one.
+        info.free = unsafe { *(object as *const u16) };
+        info.live += 1;
+        if info.free == 0 {
+            self.unlink_partial(page);
+        }
+        Some(object)
+    }
 
-        page += PAGE_SIZE;
+    /// Returns a page to carve objects from: a reclaimed one, or else the
+    /// next one of the partition. `None` once the partition is used up.
+    fn take_page(&mut self) -> Option<usize> {
+        if self.reclaimed != 0 {
+            let page = self.page(self.reclaimed);
+            self.reclaimed = self.info(page).next;
+            return Some(page);
+        }
+        if self.next + PAGE_SIZE > self.end {
+            return None;
+        }
+        let page = self.next;
+        self.next += PAGE_SIZE;
+        Some(page)
     }
 
-    true
+    /// Records that `page` was carved by `carve` and its first object
+    /// handed out.
+    fn add_page(&mut self, page: usize) {
+        let has_free = self.size < PAGE_SIZE;
+        *self.info(page) = PageInfo {
+            live: 1,
+            free: if has_free { self.size as u16 + 1 } else { 0 },
+            ..PageInfo::default()
+        };
+        if has_free {
+            self.push_partial(page);
+        }
+    }
+
+    /// Puts `object` back on its page. Returns the page if that was its
+    /// last live object: it is then on no list, and the caller unmaps it
+    /// and passes it to `reclaim`.
+    fn free_object(&mut self, object: usize) -> Option<usize> {
+        let page = object & !(PAGE_SIZE - 1);
+        let info = self.info(page);
+        info.live -= 1;
+        let was_full = info.free == 0;
+        if info.live == 0 {
+            if !was_full {
+                self.unlink_partial(page);
+            }
+            return Some(page);
+        }
+        // Safety: `object` was handed out from the page, which is mapped
+        // as it still has live objects.
+        unsafe { *(object as *mut u16) = info.free };
+        info.free = (object - page) as u16 + 1;
+        if was_full {
+            self.push_partial(page);
+        }
+        None
+    }
+
+    /// Puts `page`, now unmapped, on the reclaimed list.
+    fn reclaim(&mut self, page: usize) {
+        let number = self.number(page);
+        *self.info(page) = PageInfo {
+            next: self.reclaimed,
+            ..PageInfo::default()
+        };
+        self.reclaimed = number;
+    }
+}
+
+/// Links the objects of the freshly mapped `page` after the first into its
+/// free list.
+fn carve(page: usize, size: usize) {
+    for offset in (size..PAGE_SIZE).step_by(size) {
+        let next = if offset + size < PAGE_SIZE {
+            offset + size + 1
+        } else {
+            0
+        };
+        // Safety: the caller mapped the page and owns it.
+        unsafe { *((page + offset) as *mut u16) = next as u16 };
+    }
+}
+
+/// The buddy allocator for requests no size class serves, and the part of
+/// its partition not handed to it yet.
+struct LargeHeap {
+    buddy: BuddyHeap<32>,
+    next: usize,
+    end: usize,
+}
+
+impl LargeHeap {
+    /// Hands the buddy allocator a naturally aligned block big enough for
+    /// `layout`. Returns `false` when the partition is used up.
+    fn grow(&mut self, layout: &Layout) -> bool {
+        let size = layout
+            .size()
+            .max(layout.align())
+            .next_power_of_two()
+            .max(GROW_CHUNK);
+        let start = self.next.next_multiple_of(size);
+        if start + size > self.end {
+            return false;
+        }
+        self.next = start + size;
+        // The buddy allocator writes its free-list links into the new
+        // block, and the page-fault handler maps those pages on demand.
+        unsafe { self.buddy.add_to_heap(start, start + size) };
+        true
+    }
+}
+
+/// A processor's stack of free objects of one size class.
+struct Magazine {
+    objects: [usize; MAGAZINE_SIZE],
+    count: usize,
+}
+
+/// A processor's magazines, one per size class. Objects in a magazine are
+/// still live as far as their page is concerned, so they keep it mapped.
+struct CpuCache {
+    magazines: [Magazine; NUM_CLASSES],
+}
+
+impl CpuCache {
+    const fn new() -> Self {
+        Self {
+            magazines: [const {
+                Magazine {
+                    objects: [0; MAGAZINE_SIZE],
+                    count: 0,
+                }
+            }; NUM_CLASSES],
+        }
+    }
 }
 
 pub struct GlobalHeap {
-    heap: Mutex<Option<SlabHeap>>,
+    classes: [Mutex<SizeClass>; NUM_CLASSES],
+    large: Mutex<LargeHeap>,
+    /// Per-processor magazines in front of `classes`. Their locks guard
+    /// against an interrupt handler on the same processor re-entering the
+    /// cache and against `drain_caches` on another one; whoever finds a
+    /// cache locked goes to the size class directly or skips it.
+    caches: [Mutex<CpuCache>; MAX_CPUS],
     initialized: AtomicBool,
-    /// Next virtual address to grow each slab into, indexed by
-    /// `HeapAllocator` discriminant (0=64B, 1=128B, ..., 6=4096B, 7=buddy).
-    next_addr: Mutex<[usize; NUM_OF_SLABS + 1]>,
 }
 
-// Safety: GlobalHeap contains a Mutex (which is already Send+Sync) and an
+// Safety: GlobalHeap contains Mutexes (which are already Send+Sync) and an
 // AtomicBool. All fields are safe to send/share between threads.
 unsafe impl Send for GlobalHeap {}
 // Safety: same as Send — Mutex provides internal synchronization, AtomicBool
@@ -83,9 +376,14 @@ impl GlobalHeap {
     #[must_use]
     pub const fn new() -> Self {
         Self {
-            heap: Mutex::new(None),
+            classes: [const { Mutex::new(SizeClass::empty()) }; NUM_CLASSES],
+            large: Mutex::new(LargeHeap {
+                buddy: BuddyHeap::empty(),
+                next: 0,
+                end: 0,
+            }),
+            caches: [const { Mutex::new(CpuCache::new()) }; MAX_CPUS],
             initialized: AtomicBool::new(false),
-            next_addr: Mutex::new([0; NUM_OF_SLABS + 1]),
         }
     }
 
@@ -94,129 +392,291 @@ impl GlobalHeap {
             return;
         }
 
-        let partition_size = heap_size / NUM_OF_SLABS;
-
-        // Initialize next_addr for each slab's partition.
+        // Nothing is written to the heap here: pages are carved, and the
+        // buddy allocator grown, as allocations need them.
+        let partition_size = heap_size / NUM_PARTITIONS;
+        for (i, class) in self.classes.iter().enumerate() {
+            class.lock().init(
+                CLASS_SIZES[i],
+                heap_start + i * partition_size,
+                partition_size,
+            );
+        }
         {
-            let mut next = self.next_addr.lock();
-            for i in 0..=NUM_OF_SLABS {
-                next[i] = heap_start + i * partition_size;
-            }
+            let mut large = self.large.lock();
+            large.next = heap_start + NUM_CLASSES * partition_size;
+            large.end = large.next + partition_size;
         }
-
-        // SlabHeap::new() writes intrusive free-list metadata across the slab
-        // regions. The heap virtual addresses have no physical backing yet, so
-        // each write will trigger a page fault. On x86_64 the page-fault
-        // handler lazily allocates a physical frame and maps it on demand.
-        *self.heap.lock() = unsafe { Some(SlabHeap::new(heap_start, heap_size)) };
         self.initialized.store(true, Ordering::Release);
     }
 
-    /// Grow a slab by `GROW_CHUNK` pages when `allocate()` fails.
-    /// Returns `true` if the grow succeeded and the caller should retry.
-    fn grow_heap(&self, allocator: HeapAllocator) -> bool {
-        let idx = allocator as usize;
-        let partition_size = crate::allocator::HEAP_SIZE / NUM_OF_SLABS;
-        let partition_end = crate::allocator::HEAP_START + (idx + 1) * partition_size;
-
-        // Pick the next address and advance it, but don't exceed the partition.
-        let addr = {
-            let mut next = self.next_addr.lock();
-            let a = next[idx];
-            if a + GROW_CHUNK > partition_end {
-                return false;
+    /// Allocates an object of size class `index`.
+    fn alloc_object(&self, index: usize) -> *mut u8 {
+        let class = &self.classes[index];
+        let page = {
+            let mut class = class.lock();
+            if let Some(object) = class.pop_object() {
+                return object as *mut u8;
             }
-            next[idx] = a + GROW_CHUNK;
-            a
+            class.take_page()
         };
+        let Some(page) = page else {
+            return core::ptr::null_mut();
+        };
+        // The class lock is dropped while mapping the page: that may flush
+        // TLBs on other processors, which could be waiting for the lock.
+        // The page is on no list in the meantime, so nobody else sees it.
+        if !ensure_range_mapped(page as *mut u8, PAGE_SIZE) {
+            class.lock().reclaim(page);
+            return core::ptr::null_mut();
+        }
+        carve(page, CLASS_SIZES[index]);
+        class.lock().add_page(page);
+        page as *mut u8
+    }
 
-        // grow() writes to the new memory region to initialize free-list
-        // metadata. These writes are serviced by the page-fault handler
-        // which maps physical frames on demand.
-        unsafe {
-            if let Some(ref mut heap) = *self.heap.lock() {
-                heap.grow(addr, GROW_CHUNK, allocator);
-            } else {
-                return false;
+    /// Frees `objects` of size class `index` under one acquisition of its
+    /// lock, and the pages left without live objects with them, under one
+    /// TLB shootdown.
+    fn free_objects(&self, index: usize, objects: &[usize]) {
+        let class = &self.classes[index];
+        let mut empty = [0; MAGAZINE_SIZE];
+        let mut count = 0;
+        {
+            let mut class = class.lock();
+            for &object in objects {
+                if let Some(page) = class.free_object(object) {
+                    empty[count] = page;
+                    count += 1;
+                }
             }
         }
+        if count == 0 {
+            return;
+        }
+        let mut batch = TlbBatch::kernel();
+        for &page in &empty[..count] {
+            huge::unmap(page, page + PAGE_SIZE, &mut batch, Some(FramePurpose::Heap));
+        }
+        batch.flush();
+        let mut class = class.lock();
+        for &page in &empty[..count] {
+            class.reclaim(page);
+        }
+    }
 
-        true
+    /// Allocates an object of size class `index` from the current
+    /// processor's magazine, refilling it with up to `BATCH` objects when
+    /// it is empty.
+    fn alloc_cached(&self, index: usize) -> *mut u8 {
+        let Some(mut cache) = self.caches.get(arch::cpu_index()).and_then(Mutex::try_lock) else {
+            return self.alloc_object(index);
+        };
+        let magazine = &mut cache.magazines[index];
+        if magazine.count == 0 {
+            magazine.count = self.classes[index]
+                .lock()
+                .pop_objects(&mut magazine.objects[..BATCH]);
+            if magazine.count == 0 {
+                // No partial page left: carve a new one, whose other
+                // objects are there for the next refill.
+                return self.alloc_object(index);
+            }
+        }
+        magazine.count -= 1;
+        magazine.objects[magazine.count] as *mut u8
     }
-}
 
-unsafe impl GlobalAlloc for GlobalHeap {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        if !self.initialized.load(Ordering::Relaxed) || layout.size() == 0 {
-            return core::ptr::null_mut();
+    /// Frees an object of size class `index` into the current processor's
+    /// magazine, flushing `BATCH` objects back to the size class when it is
+    /// full.
+    fn dealloc_cached(&self, index: usize, ptr: *mut u8) {
+        let Some(mut cache) = self.caches.get(arch::cpu_index()).and_then(Mutex::try_lock) else {
+            self.free_objects(index, &[ptr as usize]);
+            return;
+        };
+        let magazine = &mut cache.magazines[index];
+        if magazine.count == MAGAZINE_SIZE {
+            let keep = MAGAZINE_SIZE - BATCH;
+            self.free_objects(index, &magazine.objects[keep..]);
+            magazine.count = keep;
         }
+        magazine.objects[magazine.count] = ptr as usize;
+        magazine.count += 1;
+    }
 
-        if let Some(ref mut heap) = *self.heap.lock() {
-            match heap.allocate(layout) {
-                Ok(nptr) => {
-                    let ptr = nptr.as_ptr();
-                    if ensure_range_mapped(ptr, layout.size()) {
-                        ptr
-                    } else {
-                        core::ptr::null_mut()
-                    }
-                }
-                Err(()) => {
-                    // Slab is full — grow it by GROW_CHUNK and retry once.
-                    let allocator = SlabHeap::layout_to_allocator(&layout);
-                    let _ = heap;
-                    if self.grow_heap(allocator) {
-                        return unsafe { self.alloc(layout) };
-                    }
-                    core::ptr::null_mut()
-                }
+    /// Returns every object in the magazines of `cache` to its size class.
+    /// The objects are taken out under the cache lock and freed once it is
+    /// dropped, as freeing may wait for a TLB shootdown on the processor
+    /// the cache belongs to.
+    fn drain(&self, cache: &Mutex<CpuCache>) {
+        for index in 0..NUM_CLASSES {
+            let mut objects = [0; MAGAZINE_SIZE];
+            let count = {
+                let Some(mut cache) = cache.try_lock() else {
+                    return;
+                };
+                let magazine = &mut cache.magazines[index];
+                let count = magazine.count;
+                objects[..count].copy_from_slice(&magazine.objects[..count]);
+                magazine.count = 0;
+                count
+            };
+            self.free_objects(index, &objects[..count]);
+        }
+    }
+
+    /// Returns every object in the current processor's magazines to its
+    /// size class, so that pages they kept mapped can be reclaimed.
+    pub fn drain_cpu_cache(&self) {
+        if let Some(cache) = self.caches.get(arch::cpu_index()) {
+            self.drain(cache);
+        }
+    }
+
+    /// Returns every object in every processor's magazines to its size
+    /// class. A magazine its processor is using at that moment is skipped.
+    pub fn drain_caches(&self) {
+        for cache in &self.caches {
+            self.drain(cache);
+        }
+    }
+
+    /// Allocates `layout` from its size class or the buddy allocator.
+    fn alloc_layout(&self, layout: Layout) -> *mut u8 {
+        match class_index(&layout) {
+            Some(index) => self.alloc_cached(index),
+            None => self.alloc_large(layout),
+        }
+    }
+
+    /// Allocates from the buddy allocator, growing it if it has no block
+    /// big enough.
+    fn alloc_large(&self, layout: Layout) -> *mut u8 {
+        let allocated = {
+            let mut large = self.large.lock();
+            match large.buddy.alloc(layout) {
+                Ok(ptr) => Ok(ptr),
+                Err(()) if large.grow(&layout) => large.buddy.alloc(layout),
+                Err(()) => Err(()),
             }
-        } else {
-            core::ptr::null_mut()
+        };
+        // As for size classes, the lock is dropped before backing the
+        // range.
+        match allocated {
+            Ok(nptr) if ensure_range_mapped(nptr.as_ptr(), layout.size()) => nptr.as_ptr(),
+            Ok(nptr) => {
+                self.large.lock().buddy.dealloc(nptr, layout);
+                core::ptr::null_mut()
+            }
+            Err(()) => core::ptr::null_mut(),
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        if !self.initialized.load(Ordering::Relaxed) || ptr.is_null() || layout.size() == 0 {
-            return;
+    /// Frees a block of the buddy allocator. Its pages belong to it alone,
+    /// so they are all unmapped; the buddy allocator then writes its link
+    /// into the first one, which the page-fault handler maps again.
+    fn dealloc_large(&self, ptr: *mut u8, layout: Layout) {
+        let start = ptr as usize;
+        let end = (start + layout.size()).next_multiple_of(PAGE_SIZE);
+        unmap_range(start, end);
+        if let Some(nptr) = NonNull::new(ptr) {
+            self.large.lock().buddy.dealloc(nptr, layout);
         }
+    }
 
-        let start_page = (ptr as usize) & !(PAGE_SIZE - 1);
-        let end_page = ((ptr as usize + layout.size() - 1) & !(PAGE_SIZE - 1)) + PAGE_SIZE;
-
-        // Unmap each page and free its physical frame back to the frame
-        // allocator. We must not hold PAGE_MAPPER when locking
-        // FRAME_ALLOCATOR (cursor.map/unmap may need FRAME_ALLOCATOR
-        // internally for page-table page cleanup).
-        let mut page = start_page;
-        while page < end_page {
-            let paddr = {
-                let mut mapper = crate::memory::PAGE_MAPPER.write();
-                match mapper.cursor().unmap(VirtAddr::from(page)) {
-                    Ok((paddr, _, _)) => paddr,
-                    Err(_) => {
-                        page += PAGE_SIZE;
-                        continue;
-                    }
+    /// Allocates interleaved pairs of objects from every size class that
+    /// shares pages and fills them, frees one object of each pair and
+    /// checks that the others kept their contents, then frees those too
+    /// and checks that every page left mapped still holds a live object.
+    /// The current processor's magazines are drained before the check.
+    ///
+    /// # Panics
+    /// If an allocation fails, an object lost its contents or a page with
+    /// no live object stayed mapped.
+    pub fn self_test(&self) {
+        let mut reclaimed = 0;
+        for (index, &size) in CLASS_SIZES.iter().enumerate() {
+            if size == PAGE_SIZE {
+                continue;
+            }
+            let layout = Layout::from_size_align(size, size).expect("heap: bad self-test layout");
+            let pattern = |i: usize| (i as u8) | 1;
+            let mut objects = [core::ptr::null_mut::<u8>(); 2 * SELF_TEST_PAIRS];
+            for (i, object) in objects.iter_mut().enumerate() {
+                let ptr = unsafe { self.alloc(layout) };
+                assert!(!ptr.is_null(), "heap: self-test out of memory");
+                unsafe { ptr.write_bytes(pattern(i), size) };
+                *object = ptr;
+            }
+            for &ptr in objects.iter().skip(1).step_by(2) {
+                unsafe { self.dealloc(ptr, layout) };
+            }
+            for (i, &ptr) in objects.iter().enumerate().step_by(2) {
+                let bytes = unsafe { core::slice::from_raw_parts(ptr, size) };
+                assert!(
+                    bytes.iter().all(|&byte| byte == pattern(i)),
+                    "heap: {size}-byte object at {ptr:p} lost its contents"
+                );
+            }
+            for &ptr in objects.iter().step_by(2) {
+                unsafe { self.dealloc(ptr, layout) };
+            }
+            self.drain_cpu_cache();
+            let mut pages = objects.map(|ptr| ptr as usize & !(PAGE_SIZE - 1));
+            pages.sort_unstable();
+            let mut previous = 0;
+            for &page in &pages {
+                if page == previous {
+                    continue;
                 }
-            };
-
-            {
-                let mut frame_alloc = crate::memory::FRAME_ALLOCATOR.write();
-                let start = paddr.as_usize();
-                let end = start + PAGE_SIZE;
-                if let Ok(page_range) = (start..end).try_into() {
-                    frame_alloc.deallocate(page_range);
+                previous = page;
+                if walk::translate_active(page).is_none() {
+                    reclaimed += 1;
+                    continue;
                 }
+                assert!(
+                    self.classes[index].lock().info(page).live != 0,
+                    "heap: page {page:#x} stayed mapped without live objects"
+                );
             }
+        }
+        assert!(reclaimed != 0, "heap: self-test reclaimed no page");
+        log::info!("heap: self-test passed, {reclaimed} pages reclaimed");
+    }
+}
 
-            page += PAGE_SIZE;
+unsafe impl GlobalAlloc for GlobalHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() == 0 {
+            return core::ptr::null_mut();
         }
+        if !self.initialized.load(Ordering::Acquire) {
+            return early::alloc(layout);
+        }
+        let ptr = self.alloc_layout(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+        // Out of memory or of room in the partition: objects sitting in
+        // magazines keep their pages mapped, so hand them back and retry.
+        self.drain_caches();
+        self.alloc_layout(layout)
+    }
 
-        if let Some(nptr) = NonNull::new(ptr)
-            && let Some(ref mut heap) = *self.heap.lock()
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if !self.initialized.load(Ordering::Relaxed) || ptr.is_null() || layout.size() == 0 {
+            return;
+        }
+        // Early allocations live in the HHDM and are never freed.
+        if !(crate::allocator::heap_start()..=crate::allocator::heap_end())
+            .contains(&(ptr as usize))
         {
-            unsafe { heap.deallocate(nptr, layout) };
+            return;
+        }
+        match class_index(&layout) {
+            Some(index) => self.dealloc_cached(index, ptr),
+            None => self.dealloc_large(ptr, layout),
         }
     }
 }
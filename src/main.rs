@@ -4,6 +4,7 @@
 #![cfg_attr(target_arch = "x86_64", feature(abi_x86_interrupt))]
 
 //module declarations
+pub mod acpi_handler;
 pub mod allocator;
 pub mod arch;
 pub mod heap;
@@ -14,25 +15,16 @@ pub mod serial;
 extern crate alloc;
 //crate imports and usages
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use limine::BaseRevision;
-#[cfg(any(
-    target_arch = "x86_64",
-    target_arch = "riscv64",
-    target_arch = "aarch64"
-))]
 use limine::paging::PagingMode;
 #[cfg(target_arch = "riscv64")]
 use limine::request::BspHartidRequest;
-#[cfg(any(
-    target_arch = "x86_64",
-    target_arch = "riscv64",
-    target_arch = "aarch64"
-))]
-use limine::request::PagingModeRequest;
 use limine::request::{
     BootloaderInfoRequest, DateAtBootRequest, DtbRequest, EfiMemmapRequest, EfiRequest,
-    ExecutableAddressRequest, ExecutableFileRequest, FirmwareTypeRequest, FramebufferRequest,
-    HhdmRequest, MemmapRequest, MpRequest, RsdpRequest, SmbiosRequest, StackSizeRequest,
+    ExecutableAddressRequest, ExecutableCmdlineRequest, ExecutableFileRequest, FirmwareTypeRequest,
+    FramebufferRequest, HhdmRequest, MemmapRequest, MpRequest, PagingModeRequest, RsdpRequest,
+    SmbiosRequest, StackSizeRequest,
 };
 use limine::{RequestsEndMarker, RequestsStartMarker};
 
@@ -66,18 +58,45 @@ static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
 #[unsafe(link_section = ".limine_requests")]
 static FRAMEBUFFER_REQUEST: FramebufferRequest = FramebufferRequest::new();
 
-// paging mode
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+// paging mode: preferred, most and fewest levels. The most is capped at
+// four, the deepest `page_table_multiarch` has tables for, so 5-level
+// paging and Sv57 are not negotiated yet. `memory::mode` reads back the
+// mode Limine chose.
+#[cfg(target_arch = "x86_64")]
 #[used]
 #[unsafe(link_section = ".limine_requests")]
-static PAGING_MODE_REQUEST: PagingModeRequest =
-    PagingModeRequest::new_exact(PagingMode::X86_64_4LVL);
+static PAGING_MODE_REQUEST: PagingModeRequest = PagingModeRequest::new(
+    PagingMode::X86_64_4LVL,
+    PagingMode::X86_64_4LVL,
+    PagingMode::MIN,
+);
+
+#[cfg(target_arch = "aarch64")]
+#[used]
+#[unsafe(link_section = ".limine_requests")]
+static PAGING_MODE_REQUEST: PagingModeRequest = PagingModeRequest::new(
+    PagingMode::AARCH64_4LVL,
+    PagingMode::AARCH64_4LVL,
+    PagingMode::MIN,
+);
 
 #[cfg(target_arch = "riscv64")]
 #[used]
 #[unsafe(link_section = ".limine_requests")]
-static PAGING_MODE_REQUEST: PagingModeRequest =
-    PagingModeRequest::new_exact(PagingMode::RISCV_SV48);
+static PAGING_MODE_REQUEST: PagingModeRequest = PagingModeRequest::new(
+    PagingMode::RISCV_SV48,
+    PagingMode::RISCV_SV48,
+    PagingMode::MIN,
+);
+
+#[cfg(target_arch = "loongarch64")]
+#[used]
+#[unsafe(link_section = ".limine_requests")]
+static PAGING_MODE_REQUEST: PagingModeRequest = PagingModeRequest::new(
+    PagingMode::LOONGARCH64_4LVL,
+    PagingMode::LOONGARCH64_4LVL,
+    PagingMode::MIN,
+);
 
 // bootstrap all cores on the system
 #[used]
@@ -124,6 +143,11 @@ static DATE_AT_BOOT_REQUEST: DateAtBootRequest = DateAtBootRequest::new();
 #[unsafe(link_section = ".limine_requests")]
 static EXECUTABLE_ADDRESS_REQUEST: ExecutableAddressRequest = ExecutableAddressRequest::new();
 
+// kernel command line
+#[used]
+#[unsafe(link_section = ".limine_requests")]
+static EXECUTABLE_CMDLINE_REQUEST: ExecutableCmdlineRequest = ExecutableCmdlineRequest::new();
+
 // device tree blob
 #[used]
 #[unsafe(link_section = ".limine_requests")]
@@ -148,15 +172,22 @@ static _END_MARKER: RequestsEndMarker = RequestsEndMarker::new();
 /// if anything fails in the kernel, we will panic and halt
 #[unsafe(no_mangle)]
 pub extern "C" fn main() -> ! {
-    // Serial init is deferred to after memory::init() so that MMIO-based
-    // serial ports (riscv64, aarch64, loongarch64) are accessible via the
-    // HHDM. On x86_64 (PIO) the order doesn't matter.
-    // serial::init() is called below after memory init.
+    // The BSP is processor 0; the frame caches rely on this being set
+    // before the first allocation.
+    arch::set_cpu_index(0);
     assert!(
         BASE_REVISION.is_supported(),
         "boot loader base revision not supported!."
     );
     log::info!("base revision supported");
+    // Frames and `Box` are usable from here on, long before the frame
+    // allocator and the heap exist.
+    memory::early_init(
+        MEMORY_MAP_REQUEST
+            .response()
+            .expect("main: failed to get memory map response")
+            .entries(),
+    );
     if let Some(info) = BOOTLOADER_INFO_REQUEST.response() {
         log::info!("Booted by: {} v{}", info.name(), info.version());
     } else {
@@ -172,50 +203,123 @@ pub extern "C" fn main() -> ! {
             .entries(),
     );
     log::info!("memory manager initialized.");
-    // Now that memory is set up, initialize serial (safe for MMIO-based ports).
-    serial::init();
-    log::info!("logger initialized");
+    // The serial port is PIO on x86_64, so it can log arch::init() too.
+    #[cfg(target_arch = "x86_64")]
+    {
+        serial::init();
+        log::info!("logger initialized");
+    }
     arch::init();
+    // MMIO-based serial ports (riscv64, aarch64, loongarch64) are reached
+    // through an `ioremap` mapping in the kernel page table, active now.
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        serial::init();
+        log::info!("logger initialized");
+    }
+    memory::kaslr::report();
     log::info!("architecture initialization complete.");
     allocator::init();
     log::info!("allocator initialized.");
+    allocator::self_test();
     let tmp = alloc::boxed::Box::new(42);
     log::info!("{tmp}");
 
     if let Some(mp_response) = MP_REQUEST.response() {
-        // Get the BSP's unique ID in an architecture-agnostic way.
-        /*let bsp_id =
-        {
-        #[cfg(target_arch = "x86_64")]
-        { mp_response.bsp_lapic_id() }
-        #[cfg(target_arch = "riscv64")]
-        { crate::BSP_HARTID_REQUEST.get_response().unwrap().id() }
-        #[cfg(target_arch = "aarch64")]
-        { mp_response.bsp_mpidr() }
-        };*/
         log::info!("SMP support detected.");
         #[allow(unused_variables)]
         for cpu in mp_response.cpus() {
-            /*let cpu_id =
-            {
-            #[cfg(target_arch = "x86_64")]
-            { cpu.lapic_id }
-            #[cfg(target_arch = "riscv64")]
-            { cpu.hartid }
-            #[cfg(target_arch = "aarch64")]
-            { cpu.mpidr }
-            };*/
-            /*if cpu_id == bsp_id
-            {
-            continue;
-            }*/
             #[cfg(not(target_arch = "loongarch64"))]
-            cpu.bootstrap(os_loop, 0);
+            if hw_id(cpu) == bsp_hw_id(mp_response) {
+                memory::numa::register_cpu(0, hw_id(cpu));
+                memory::tlb::cpu_online(0, hw_id(cpu));
+            } else if AP_STARTED.load(Ordering::Relaxed) + 1 >= memory::frame_cache::MAX_CPUS {
+                // Per-processor state is sized for MAX_CPUS processors.
+                log::warn!(
+                    "not starting CPU {:#x}: at most {} CPUs are supported",
+                    hw_id(cpu),
+                    memory::frame_cache::MAX_CPUS
+                );
+            } else {
+                // APs are numbered from 1 in the order they are started.
+                let index = AP_STARTED.fetch_add(1, Ordering::Relaxed) + 1;
+                memory::numa::register_cpu(index, hw_id(cpu));
+                cpu.bootstrap(os_loop, index as u64);
+            }
             #[cfg(target_arch = "loongarch64")]
             log::warn!("SMP not yet supported on loongarch64");
         }
     }
+
+    // Leave the bootloader-provided stack so it can be reclaimed.
+    let stack_top = memory::alloc_kernel_stack();
+    unsafe { arch::switch_stack(stack_top, bsp_main) }
+}
+
+/// Number of application processors we asked the bootloader to start.
+static AP_STARTED: AtomicUsize = AtomicUsize::new(0);
+/// Number of application processors running on the kernel page table and
+/// their own kernel stack.
+static AP_ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the hardware id of `cpu`: its local APIC id, hart id or MPIDR.
+#[cfg(not(target_arch = "loongarch64"))]
+fn hw_id(cpu: &limine::mp::MpInfo) -> u64 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        u64::from(cpu.lapic_id)
+    }
+    #[cfg(target_arch = "riscv64")]
+    {
+        cpu.hartid
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        cpu.mpidr
+    }
+}
+
+/// Returns the hardware id of the bootstrap processor we are running on.
+#[cfg(not(target_arch = "loongarch64"))]
+fn bsp_hw_id(mp_response: &limine::mp::MpRespData) -> u64 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        u64::from(mp_response.bsp_lapic_id)
+    }
+    #[cfg(target_arch = "riscv64")]
+    {
+        mp_response.bsp_hartid
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        mp_response.bsp_mpidr
+    }
+}
+
+/// Continuation of `main` on a kernel stack. Once every application processor
+/// has left the bootloader's page tables and stacks, the memory they occupied
+/// is handed back to the frame allocator.
+extern "C" fn bsp_main() -> ! {
+    while AP_ONLINE.load(Ordering::Acquire) < AP_STARTED.load(Ordering::Relaxed) {
+        core::hint::spin_loop();
+    }
+    // Safety: all processors now run on the kernel page table and kernel
+    // stacks, so the identity map that carried them over is no longer used.
+    unsafe {
+        memory::remove_identity_map(
+            MEMORY_MAP_REQUEST
+                .response()
+                .expect("main: failed to get memory map response")
+                .entries(),
+        );
+    }
+    // Safety: as above, and no Limine response is referenced past this
+    // point.
+    unsafe { memory::reclaim_bootloader_memory() };
+    memory::log_summary();
+    memory::vmm::dump();
     loop {
+        memory::zero::refill();
         arch::holt();
     }
 }
@@ -224,14 +328,33 @@ pub extern "C" fn main() -> ! {
 ///
 /// # Safety
 ///
-/// - `_cpu` must be a valid `&MpInfo` provided by the bootloader.
+/// - `cpu` must be a valid `&MpInfo` provided by the bootloader.
 /// - May only be called once per AP core, from the AP bootstrap context.
 /// - The kernel's page table, GDT, IDT, and heap must already be initialized
 ///   on the BSP before any AP is bootstrapped.
 #[allow(clippy::missing_safety_doc)]
-pub unsafe extern "C" fn os_loop(_cpu: &limine::mp::MpInfo) -> ! {
+pub unsafe extern "C" fn os_loop(cpu: &limine::mp::MpInfo) -> ! {
+    arch::set_cpu_index(
+        usize::try_from(cpu.extra_argument()).expect("main: invalid processor index"),
+    );
+    arch::init_ap();
+    #[cfg(not(target_arch = "loongarch64"))]
+    memory::tlb::cpu_online(arch::cpu_index(), hw_id(cpu));
+    let stack_top = memory::alloc_kernel_stack();
+    unsafe { arch::switch_stack(stack_top, ap_main) }
+}
+
+/// Continuation of `os_loop` on a kernel stack.
+extern "C" fn ap_main() -> ! {
     log::info!("processor started.");
+    AP_ONLINE.fetch_add(1, Ordering::Release);
+    // Drop any identity-map translations the BSP is about to remove.
+    while !memory::identity_map_removed() {
+        core::hint::spin_loop();
+    }
+    memory::flush_tlb();
     loop {
+        memory::zero::refill();
         arch::holt();
     }
 }
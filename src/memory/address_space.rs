@@ -0,0 +1,628 @@
+//! Per-process address spaces.
+//!
+//! An `AddressSpace` owns a root page table of its own. The bottom of the
+//! lower half, `USER_START..user_end()`, is private to it and holds user
+//! mappings; every root entry above that (the area holding the kernel heap
+//! and everything after it, including the whole higher half) is copied from
+//! the kernel page table, so the kernel is mapped the same way in every
+//! address space.
+//!
+//! Copying a root entry only shares what hangs below it. To keep later
+//! kernel mappings visible everywhere, `populate_kernel_entries` gives every
+//! root entry covered by a kernel region a next-level table when the region
+//! is reserved, before any address space copies it; the kernel never
+//! changes its root entries after that.
+//!
+//! User memory is populated by page faults, which `fault::handle` forwards
+//! to `handle_fault` for the address space the processor runs on:
+//! - anonymous mappings and stacks are demand-zero: a zeroed frame is
+//!   mapped on first touch;
+//! - `fork` shares every mapped frame read-only between both address
+//!   spaces, and a write to such a page copies it (or just makes it
+//!   writable again once nobody else shares it);
+//! - a stack mapping grows down, page by page, when something below it is
+//!   touched, down to its limit and never closer than a guard gap to the
+//!   mapping below.
+use crate::allocator::heap_area_start;
+use crate::arch;
+use crate::memory::allocator::AllocError;
+use crate::memory::asid::{self, AsidContext, KERNEL_ASID};
+use crate::memory::fault::{Access, FaultError, PageFault};
+use crate::memory::frame_cache::MAX_CPUS;
+use crate::memory::page_desc::{self, PageDescriptor};
+use crate::memory::paging::AmirOSPagingHandler;
+use crate::memory::stats::FramePurpose;
+use crate::memory::tlb::{CpuMask, TlbBatch};
+use crate::memory::{
+    PAGE_MAPPER, PAGE_SIZE, PageTable, PageTableEntry, frame_cache, hhdm_offset, mode, zero,
+};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use meminterval::IntervalTree;
+use memory_addr::{PhysAddr, VirtAddr};
+use page_table_multiarch::{GenericPTE, MappingFlags, PageSize, PagingHandler};
+use spin::Mutex;
+
+/// Number of entries in a page table.
+const ROOT_ENTRIES: usize = 512;
+
+/// Lowest user address; the first page stays unmapped to catch null
+/// pointers.
+pub const USER_START: usize = PAGE_SIZE;
+
+/// Returns the end of the user part of the lower half: the bottom of the
+/// area the kernel heap is placed in, halfway up the lower half and so on
+/// a root entry boundary in every paging mode. All root entries from there
+/// on are shared with the kernel.
+#[must_use]
+pub fn user_end() -> usize {
+    heap_area_start()
+}
+
+/// Unmapped gap a stack never grows into above the mapping below it.
+const STACK_GUARD_GAP: usize = PAGE_SIZE;
+
+fn root_index(vaddr: usize) -> usize {
+    (vaddr / mode::root_entry_span()) % ROOT_ENTRIES
+}
+
+/// Returns the first root entry shared with the kernel page table.
+fn first_kernel_entry() -> usize {
+    root_index(user_end())
+}
+
+/// Returns the root entries of the page table rooted at `root`.
+///
+/// # Safety
+/// `root` must be the root of a live page table, and the caller must
+/// exclusively own it or hold the lock protecting it.
+unsafe fn root_entries<'a>(root: PhysAddr) -> &'a mut [PageTableEntry] {
+    let ptr = (root.as_usize() + hhdm_offset()) as *mut PageTableEntry;
+    // Safety: page tables are reachable through the HHDM and the root is a
+    // whole page of entries.
+    unsafe { core::slice::from_raw_parts_mut(ptr, ROOT_ENTRIES) }
+}
+
+/// Gives every root entry of the kernel page table that covers
+/// `start..end` a next-level table, so that address spaces created later
+/// share whatever the kernel maps there. Entries below `user_end()` are left
+/// alone.
+/// # Panics
+/// when out of memory for page tables.
+pub fn populate_kernel_entries(start: usize, end: usize) {
+    if end <= start {
+        return;
+    }
+    let mapper = PAGE_MAPPER.write();
+    // Safety: the kernel page table is live and locked.
+    let entries = unsafe { root_entries(mapper.root_paddr()) };
+    let first = root_index(start).max(first_kernel_entry());
+    for entry in entries.iter_mut().take(root_index(end - 1) + 1).skip(first) {
+        if entry.is_unused() {
+            let table = AmirOSPagingHandler::alloc_frame()
+                .expect("address space: out of memory for a kernel page table");
+            // Safety: the frame was just allocated.
+            unsafe { zero::zero_frames(table.as_usize(), PAGE_SIZE) };
+            *entry = PageTableEntry::new_table(table);
+        }
+    }
+}
+
+/// The address space each processor has loaded, or null for the kernel
+/// page table.
+static LOADED: [AtomicPtr<Shared>; MAX_CPUS] =
+    [const { AtomicPtr::new(core::ptr::null_mut()) }; MAX_CPUS];
+
+/// What a user mapping holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingKind {
+    /// Demand-zero anonymous memory.
+    Anonymous,
+    /// Demand-zero memory that grows down to `limit` when touched below its
+    /// start.
+    Stack { limit: usize },
+}
+
+/// A range of user memory mapped into an address space.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UserMapping {
+    pub start: usize,
+    pub end: usize,
+    pub flags: MappingFlags,
+    pub kind: MappingKind,
+}
+
+/// The parts of an address space that processors running on it reach
+/// through `LOADED`. Boxed so that its address survives moves of the
+/// address space.
+struct Shared {
+    /// Processors that may cache translations of the address space, as
+    /// `CpuMask` bits.
+    cpus: AtomicU64,
+    /// Identifier that tags its TLB entries.
+    asid: AsidContext,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    table: PageTable,
+    /// Every user mapping, keyed by its range.
+    mappings: IntervalTree<usize, UserMapping>,
+}
+
+pub struct AddressSpace {
+    shared: Box<Shared>,
+}
+
+impl Shared {
+    fn cpus(&self) -> CpuMask {
+        CpuMask::from_bits(self.cpus.load(Ordering::Acquire))
+    }
+
+    /// Returns a batch for invalidating translations of this address space.
+    fn tlb_batch(&self) -> TlbBatch {
+        // Only processors that have used the address space may cache its
+        // translations.
+        TlbBatch::new(self.cpus(), self.asid.asid())
+    }
+}
+
+impl AddressSpace {
+    /// Creates an address space with no user mappings that shares the
+    /// kernel half of the kernel page table.
+    /// # Errors
+    /// when out of memory for the root table.
+    pub fn new() -> Result<Self, AllocError> {
+        let table = PageTable::try_new().map_err(|_| AllocError)?;
+        let kernel = PAGE_MAPPER.read();
+        // Safety: the kernel page table is live and locked, and the new
+        // table is ours.
+        let (shared, own) = unsafe {
+            (
+                root_entries(kernel.root_paddr()),
+                root_entries(table.root_paddr()),
+            )
+        };
+        let first = first_kernel_entry();
+        own[first..].copy_from_slice(&shared[first..]);
+        drop(kernel);
+        Ok(Self {
+            shared: Box::new(Shared {
+                cpus: AtomicU64::new(0),
+                asid: AsidContext::new(),
+                inner: Mutex::new(Inner {
+                    table,
+                    mappings: IntervalTree::new(),
+                }),
+            }),
+        })
+    }
+
+    /// Returns the physical address of the root page table.
+    #[must_use]
+    pub fn root_paddr(&self) -> PhysAddr {
+        self.shared.inner.lock().table.root_paddr()
+    }
+
+    /// Reserves `size` bytes of demand-zero memory at `vaddr`, accessible
+    /// from user mode with `flags`. Pages are backed when first touched.
+    /// # Errors
+    /// when `vaddr` is not page-aligned, or the range is not inside
+    /// `USER_START..user_end()` or overlaps an existing mapping.
+    pub fn map_anonymous(
+        &mut self,
+        vaddr: VirtAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> Result<(), AllocError> {
+        let (start, end) = user_range(vaddr, size)?;
+        self.shared
+            .inner
+            .lock()
+            .insert(start, end, flags, MappingKind::Anonymous)
+    }
+
+    /// Reserves a stack ending at `top` that starts out one page deep and
+    /// grows down on demand to at most `max_size` bytes.
+    /// # Errors
+    /// when `top` is not page-aligned, the stack would not fit inside
+    /// `USER_START..user_end()`, or its full extent or the guard gap below it
+    /// overlaps an existing mapping.
+    pub fn map_stack(
+        &mut self,
+        top: VirtAddr,
+        max_size: usize,
+        flags: MappingFlags,
+    ) -> Result<(), AllocError> {
+        let max_size = max_size.next_multiple_of(PAGE_SIZE).max(PAGE_SIZE);
+        let limit = top.as_usize().checked_sub(max_size).ok_or(AllocError)?;
+        let (limit, end) = user_range(VirtAddr::from(limit), max_size)?;
+        let mut inner = self.shared.inner.lock();
+        if inner
+            .mappings
+            .query(limit.saturating_sub(STACK_GUARD_GAP)..end)
+            .next()
+            .is_some()
+        {
+            return Err(AllocError);
+        }
+        inner.insert(end - PAGE_SIZE, end, flags, MappingKind::Stack { limit })
+    }
+
+    /// Unmaps the mapping starting at `vaddr` (for a stack, its current
+    /// lowest page) and frees its memory. Returns the mapping, or `None` if
+    /// no mapping starts there.
+    pub fn unmap(&mut self, vaddr: VirtAddr) -> Option<UserMapping> {
+        let start = vaddr.as_usize();
+        let mut inner = self.shared.inner.lock();
+        let mapping = inner
+            .mappings
+            .query(start..start + 1)
+            .map(|entry| *entry.value)
+            .find(|mapping| mapping.start == start)?;
+        inner.mappings.delete(mapping.start..mapping.end);
+        let mut batch = self.shared.tlb_batch();
+        inner.unmap_pages(mapping.start, mapping.end, &mut batch);
+        drop(inner);
+        batch.flush();
+        Some(mapping)
+    }
+
+    /// Creates a copy of this address space that shares every mapped frame
+    /// copy-on-write: both sides map it read-only and the first write on
+    /// either side copies it.
+    /// # Errors
+    /// when out of memory for page tables.
+    pub fn fork(&self) -> Result<Self, AllocError> {
+        let child = Self::new()?;
+        let mut parent = self.shared.inner.lock();
+        let mut own = child.shared.inner.lock();
+        // The parent loses write access, so no processor may keep a writable
+        // translation of it.
+        let mut batch = self.shared.tlb_batch();
+        let mappings: Vec<UserMapping> = parent.mappings().collect();
+        for mapping in mappings {
+            own.mappings.insert(mapping.start..mapping.end, mapping);
+            let shared_flags = mapping.flags - MappingFlags::WRITE;
+            for page in (mapping.start..mapping.end).step_by(PAGE_SIZE) {
+                let vaddr = VirtAddr::from(page);
+                let Ok((paddr, flags, _)) = parent.table.query(vaddr) else {
+                    continue;
+                };
+                if flags.contains(MappingFlags::WRITE) {
+                    let _ = parent.table.cursor().protect(vaddr, shared_flags);
+                    parent.set_not_global(page);
+                    batch.invalidate(page, PAGE_SIZE);
+                }
+                let mapped = own
+                    .table
+                    .cursor()
+                    .map(vaddr, paddr, PageSize::Size4K, shared_flags);
+                if mapped.is_err() {
+                    // The child unmaps what it got when dropped on return.
+                    drop(own);
+                    drop(parent);
+                    batch.flush();
+                    return Err(AllocError);
+                }
+                own.set_not_global(page);
+                if let Some(desc) = page_desc::lookup(paddr) {
+                    desc.get();
+                }
+            }
+        }
+        drop(own);
+        drop(parent);
+        batch.flush();
+        Ok(child)
+    }
+
+    /// Returns the processors that may cache translations of the address
+    /// space: the ones that have it loaded and, when TLB entries are tagged
+    /// with address space identifiers, every one that ever had.
+    #[must_use]
+    pub fn cpus(&self) -> CpuMask {
+        self.shared.cpus()
+    }
+
+    /// Returns the user mappings, in no particular order.
+    #[must_use]
+    pub fn mappings(&self) -> Vec<UserMapping> {
+        self.shared.inner.lock().mappings().collect()
+    }
+
+    /// Translates `vaddr` in this address space to the physical address and
+    /// flags it is mapped with. Pages that were never touched are not
+    /// mapped.
+    #[must_use]
+    pub fn translate(&self, vaddr: VirtAddr) -> Option<(PhysAddr, MappingFlags)> {
+        let (paddr, flags, _) = self.shared.inner.lock().table.query(vaddr).ok()?;
+        Some((paddr, flags))
+    }
+
+    /// Switches the calling processor to this address space: CR3 on
+    /// x86_64, SATP on riscv64, TTBR0 on aarch64 and PGDL on loongarch64.
+    /// Kernel threads may do this to borrow a user address space.
+    ///
+    /// # Safety
+    /// The address space must not be dropped while any processor uses it;
+    /// switch back with `activate_kernel` first.
+    pub unsafe fn activate(&self) {
+        let cpu = arch::cpu_index();
+        // Shootdowns must reach us before the first translation is cached.
+        self.shared
+            .cpus
+            .fetch_or(CpuMask::single(cpu).bits(), Ordering::AcqRel);
+        let asid = asid::switch_to(&self.shared.asid);
+        // Safety: the table shares the kernel half and outlives its use,
+        // as the caller promises, and `asid` is ours.
+        unsafe { arch::set_user_page_table(self.root_paddr(), asid) };
+        switched(cpu, &raw const *self.shared);
+    }
+}
+
+/// Checks that `size` bytes at `vaddr` are a page-aligned user range and
+/// returns its bounds, rounded up to whole pages.
+fn user_range(vaddr: VirtAddr, size: usize) -> Result<(usize, usize), AllocError> {
+    let start = vaddr.as_usize();
+    let end = start
+        .checked_add(size)
+        .ok_or(AllocError)?
+        .next_multiple_of(PAGE_SIZE);
+    if !start.is_multiple_of(PAGE_SIZE) || size == 0 || start < USER_START || end > user_end() {
+        return Err(AllocError);
+    }
+    Ok((start, end))
+}
+
+impl Inner {
+    fn insert(
+        &mut self,
+        start: usize,
+        end: usize,
+        flags: MappingFlags,
+        kind: MappingKind,
+    ) -> Result<(), AllocError> {
+        if self.mappings.query(start..end).next().is_some() {
+            return Err(AllocError);
+        }
+        let flags = flags | MappingFlags::USER;
+        self.mappings.insert(
+            start..end,
+            UserMapping {
+                start,
+                end,
+                flags,
+                kind,
+            },
+        );
+        Ok(())
+    }
+
+    fn mappings(&self) -> impl Iterator<Item = UserMapping> + '_ {
+        self.mappings
+            .query(USER_START..user_end())
+            .map(|entry| *entry.value)
+    }
+
+    fn find(&self, vaddr: usize) -> Option<UserMapping> {
+        self.mappings
+            .query(vaddr..vaddr + 1)
+            .map(|entry| *entry.value)
+            .next()
+    }
+
+    /// Tags the entry just written for `page` with this address space's
+    /// ASID on aarch64, where `A64PTE` makes every mapping global.
+    fn set_not_global(&self, page: usize) {
+        // Safety: the table is locked by the caller.
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            arch::set_not_global(self.table.root_paddr(), page);
+        }
+        #[cfg(not(target_arch = "aarch64"))]
+        let _ = page;
+    }
+
+    /// Unmaps the pages of `start..end`, recording them and the frames to
+    /// free in `batch`, which the caller flushes once the lock is dropped.
+    fn unmap_pages(&mut self, start: usize, end: usize, batch: &mut TlbBatch) {
+        let mut cursor = self.table.cursor();
+        for page in (start..end).step_by(PAGE_SIZE) {
+            if let Ok((paddr, _, _)) = cursor.unmap(VirtAddr::from(page)) {
+                batch.invalidate(page, PAGE_SIZE);
+                // Frames shared copy-on-write stay with the other sharers.
+                if page_desc::lookup(paddr).is_none_or(PageDescriptor::release) {
+                    batch.free_after(paddr, FramePurpose::User);
+                }
+            }
+        }
+    }
+
+    /// Maps a zeroed frame at `page` with `flags`.
+    fn map_zeroed(&mut self, page: usize, flags: MappingFlags) -> Result<(), FaultError> {
+        let paddr =
+            zero::alloc_zeroed_frame(FramePurpose::User).map_err(|_| FaultError::OutOfMemory)?;
+        let mapped = self
+            .table
+            .cursor()
+            .map(VirtAddr::from(page), paddr, PageSize::Size4K, flags);
+        if mapped.is_err() {
+            frame_cache::free_frame(paddr, FramePurpose::User);
+            return Err(FaultError::OutOfMemory);
+        }
+        self.set_not_global(page);
+        Ok(())
+    }
+
+    /// Grows the stack mapping just above `vaddr` down to the page holding
+    /// it, if that stays within the stack's limit and clear of the guard gap
+    /// above the next mapping below.
+    fn grow_stack(&mut self, vaddr: usize) -> Option<UserMapping> {
+        let page = vaddr & !(PAGE_SIZE - 1);
+        let stack = self.mappings().find(|mapping| match mapping.kind {
+            MappingKind::Stack { limit } => (limit..mapping.start).contains(&page),
+            MappingKind::Anonymous => false,
+        })?;
+        if self
+            .mappings
+            .query(page.saturating_sub(STACK_GUARD_GAP)..stack.start)
+            .next()
+            .is_some()
+        {
+            return None;
+        }
+        let grown = UserMapping {
+            start: page,
+            ..stack
+        };
+        self.mappings.delete(stack.start..stack.end);
+        self.mappings.insert(grown.start..grown.end, grown);
+        Some(grown)
+    }
+
+    /// Resolves `fault` in this address space. Translations to invalidate
+    /// and frames to free go in `batch`, which the caller flushes once the
+    /// lock is dropped.
+    fn resolve(
+        &mut self,
+        shared: &Shared,
+        fault: &PageFault,
+        batch: &mut TlbBatch,
+    ) -> Result<(), FaultError> {
+        let mapping = self
+            .find(fault.addr)
+            .or_else(|| self.grow_stack(fault.addr))
+            .ok_or(FaultError::NoRegion)?;
+        let needed = match fault.access {
+            Access::Read => MappingFlags::READ,
+            Access::Write => MappingFlags::WRITE,
+            Access::Execute => MappingFlags::EXECUTE,
+        };
+        if !mapping.flags.contains(needed) {
+            return Err(FaultError::Protection);
+        }
+        let page = fault.addr & !(PAGE_SIZE - 1);
+        let Ok((paddr, flags, _)) = self.table.query(VirtAddr::from(page)) else {
+            return self.map_zeroed(page, mapping.flags);
+        };
+        if fault.access == Access::Write && !flags.contains(MappingFlags::WRITE) {
+            return self.break_cow(page, paddr, mapping.flags, batch);
+        }
+        // Another processor resolved it first, or this one still had a
+        // stale translation cached.
+        arch::flush_tlb_local(shared.asid.asid(), Some(page));
+        Ok(())
+    }
+
+    /// Gives `page`, which shares the frame at `paddr` copy-on-write, a
+    /// writable frame of its own.
+    fn break_cow(
+        &mut self,
+        page: usize,
+        paddr: PhysAddr,
+        flags: MappingFlags,
+        batch: &mut TlbBatch,
+    ) -> Result<(), FaultError> {
+        let vaddr = VirtAddr::from(page);
+        let desc = page_desc::lookup(paddr);
+        if desc.is_none_or(|desc| desc.refcount() == 1) {
+            // Nobody else shares it any more; take it over.
+            let _ = self.table.cursor().protect(vaddr, flags);
+        } else {
+            let copy = frame_cache::alloc_frame(FramePurpose::User)
+                .map_err(|_| FaultError::OutOfMemory)?;
+            let hhdm = hhdm_offset();
+            // Safety: both frames are RAM reached through the HHDM, and the
+            // copy was just allocated.
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    (paddr.as_usize() + hhdm) as *const u8,
+                    (copy.as_usize() + hhdm) as *mut u8,
+                    PAGE_SIZE,
+                );
+            }
+            if self.table.cursor().remap(vaddr, copy, flags).is_err() {
+                frame_cache::free_frame(copy, FramePurpose::User);
+                return Err(FaultError::OutOfMemory);
+            }
+            // Other threads of this address space must stop reading the
+            // shared frame before it may be freed.
+            if desc.is_some_and(PageDescriptor::release) {
+                batch.free_after(paddr, FramePurpose::User);
+            }
+        }
+        self.set_not_global(page);
+        batch.invalidate(page, PAGE_SIZE);
+        Ok(())
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let mut inner = self.shared.inner.lock();
+        let mut batch = self.shared.tlb_batch();
+        let mappings: Vec<UserMapping> = inner.mappings().collect();
+        for mapping in mappings {
+            inner.unmap_pages(mapping.start, mapping.end, &mut batch);
+        }
+        // The kernel half belongs to the kernel page table; keep the
+        // table's own drop from freeing it.
+        // Safety: the table is ours and about to go away.
+        let entries = unsafe { root_entries(inner.table.root_paddr()) };
+        for entry in &mut entries[first_kernel_entry()..] {
+            entry.clear();
+        }
+        drop(inner);
+        batch.flush();
+    }
+}
+
+/// Switches the calling processor back to the kernel page table, leaving
+/// whatever address space it was using.
+pub fn activate_kernel() {
+    let root = PAGE_MAPPER.read().root_paddr();
+    // Safety: the kernel page table lives forever.
+    unsafe { arch::set_user_page_table(root, KERNEL_ASID) };
+    asid::switch_to_kernel();
+    switched(arch::cpu_index(), core::ptr::null());
+}
+
+/// Records that processor `cpu` now runs on address space `shared` (null
+/// for the kernel page table), and takes it out of the processor mask of
+/// the address space it left unless its TLB keeps that address space's
+/// entries.
+fn switched(cpu: usize, shared: *const Shared) {
+    let previous = LOADED[cpu].swap(shared.cast_mut(), Ordering::AcqRel);
+    if !previous.is_null() && previous.cast_const() != shared && !asid::enabled() {
+        // Safety: an address space is not dropped while loaded, so the one
+        // we just left is still alive.
+        unsafe { &*previous }
+            .cpus
+            .fetch_and(!CpuMask::single(cpu).bits(), Ordering::AcqRel);
+    }
+}
+
+/// Resolves `fault` at a user address in the address space the calling
+/// processor runs on.
+/// # Errors
+/// when the processor runs on the kernel page table, no mapping covers the
+/// address, the mapping does not allow the access, or there is not enough
+/// memory.
+pub fn handle_fault(fault: &PageFault) -> Result<(), FaultError> {
+    let shared = LOADED[arch::cpu_index()].load(Ordering::Acquire);
+    if shared.is_null() {
+        return Err(FaultError::NoRegion);
+    }
+    // Safety: an address space is not dropped while loaded.
+    let shared = unsafe { &*shared };
+    // The shootdown waits for other processors, which may be spinning on
+    // the lock with interrupts off, so it runs after the lock is dropped.
+    let mut inner = shared.inner.lock();
+    let mut batch = shared.tlb_batch();
+    let result = inner.resolve(shared, fault, &mut batch);
+    drop(inner);
+    batch.flush();
+    result
+}
@@ -1,64 +1,538 @@
-// allocator based on free list
-use free_list::{AllocError, FreeList, PageLayout, PageRange};
-use limine::memmap::{Entry, MEMMAP_USABLE};
+// allocator based on the buddy system
+use crate::memory::buddy::{BuddyAllocator, MAX_ORDER};
+use crate::memory::early;
+use crate::memory::numa::{self, MAX_NODES};
+use crate::memory::page_desc;
+use crate::memory::stats::{self, FramePurpose, MemoryRegion, MemoryStats, RegionStats};
+use crate::memory::zero;
+use crate::memory::{FRAME_ALLOCATOR, PAGE_SIZE};
+use core::fmt;
+use free_list::{PageLayout, PageRange};
+use limine::memmap::{Entry, MEMMAP_BOOTLOADER_RECLAIMABLE, MEMMAP_USABLE};
+
+/// Maximum number of bootloader-reclaimable regions remembered for reclaim.
+const MAX_RECLAIMABLE_REGIONS: usize = 64;
+/// Maximum number of memory map entries remembered for `region_stats`.
+const MAX_MEMMAP_REGIONS: usize = 256;
+/// Share of a zone, as a right shift of its size, that requests for higher
+/// zones falling back into it may not take.
+const LOW_RESERVE_SHIFT: u32 = 4;
+
+/// The frame allocator could not satisfy a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError;
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("out of physical memory")
+    }
+}
+
+/// Physical memory zones, for devices that can only address part of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Below 16 MiB, for legacy ISA DMA.
+    Dma,
+    /// Below 4 GiB, for 32-bit DMA engines.
+    Dma32,
+    /// Everything else.
+    Normal,
+}
+
+impl Zone {
+    pub const ALL: [Self; 3] = [Self::Dma, Self::Dma32, Self::Normal];
+
+    /// Physical address range `start..end` covered by the zone.
+    #[must_use]
+    pub const fn range(self) -> (usize, usize) {
+        match self {
+            Self::Dma => (0, 16 * 1024 * 1024),
+            Self::Dma32 => (16 * 1024 * 1024, 0x1_0000_0000),
+            Self::Normal => (0x1_0000_0000, usize::MAX),
+        }
+    }
+
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Dma => "DMA",
+            Self::Dma32 => "DMA32",
+            Self::Normal => "Normal",
+        }
+    }
+
+    /// Returns the zone that contains the physical address `paddr`.
+    #[must_use]
+    pub const fn of(paddr: usize) -> Self {
+        if paddr < Self::Dma.range().1 {
+            Self::Dma
+        } else if paddr < Self::Dma32.range().1 {
+            Self::Dma32
+        } else {
+            Self::Normal
+        }
+    }
+}
+
+/// Page counts of a single zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZoneUsage {
+    pub zone: Zone,
+    pub total_pages: usize,
+    pub free_pages: usize,
+}
+
+/// Page counts of a single NUMA node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeUsage {
+    pub node: usize,
+    pub total_pages: usize,
+    pub free_pages: usize,
+}
 
 pub struct FrameAllocator {
-    allocator: FreeList<16>,
+    /// One buddy allocator per NUMA node and zone, indexed by node and then
+    /// by `Zone as usize`.
+    pools: [[BuddyAllocator; 3]; MAX_NODES],
     pub hhdm_offset: usize,
+    /// Bootloader-reclaimable ranges, copied out of the memory map because
+    /// the memory map itself lives in one of them.
+    reclaimable: [(usize, usize); MAX_RECLAIMABLE_REGIONS],
+    reclaimable_count: usize,
+    /// Copy of the memory map, for the per-region breakdown.
+    regions: [MemoryRegion; MAX_MEMMAP_REGIONS],
+    region_count: usize,
 }
 
-// Safety: FrameAllocator wraps FreeList<16> and a usize. FreeList contains
-// a SmallVec of PageRange (all Copy types), making it automatically Send+Sync.
-// External synchronization via RwLock prevents concurrent access.
+// Safety: the buddy allocators only refer to physical memory they own,
+// through the HHDM. External synchronization via RwLock prevents concurrent
+// access.
 unsafe impl Send for FrameAllocator {}
 unsafe impl Sync for FrameAllocator {}
 
+/// Returns the page-aligned physical range `start..end` covered by a memory
+/// map entry, shrunk inwards to page boundaries.
+fn entry_range(region: &Entry) -> Option<(usize, usize)> {
+    let start = usize::try_from(region.base).expect("allocator: invalid base in memory region");
+    let length =
+        usize::try_from(region.length).expect("allocator: invalid length in memory region");
+    let end = start
+        .checked_add(length)
+        .expect("allocator: integer overflow in memory region calculation");
+    let start = start.next_multiple_of(PAGE_SIZE);
+    let end = end & !(PAGE_SIZE - 1);
+    (start < end).then_some((start, end))
+}
+
+/// Splits `start..end` at zone boundaries.
+fn zone_pieces(start: usize, end: usize) -> impl Iterator<Item = (Zone, PageRange)> {
+    Zone::ALL.into_iter().filter_map(move |zone| {
+        let (zone_start, zone_end) = zone.range();
+        let range = PageRange::new(start.max(zone_start), end.min(zone_end)).ok()?;
+        Some((zone, range))
+    })
+}
+
+/// Splits `start..end` at node and zone boundaries.
+fn pool_pieces(start: usize, end: usize) -> impl Iterator<Item = (usize, Zone, PageRange)> {
+    numa::node_pieces(start, end).flat_map(|(node, start, end)| {
+        zone_pieces(start, end).map(move |(zone, range)| (node, zone, range))
+    })
+}
+
+/// Like `FrameAllocator::allocate`, but the returned memory is filled with
+/// zeroes, after the frame allocator lock is dropped.
+/// # Errors
+/// when no free block is large enough for the layout.
+pub fn allocate_zeroed(layout: PageLayout, purpose: FramePurpose) -> Result<PageRange, AllocError> {
+    allocate_zeroed_in(layout, Zone::Normal, purpose)
+}
+
+/// Like `FrameAllocator::allocate_in`, but the returned memory is filled
+/// with zeroes, after the frame allocator lock is dropped.
+/// # Errors
+/// when neither the zone nor any zone below it can satisfy the layout.
+pub fn allocate_zeroed_in(
+    layout: PageLayout,
+    zone: Zone,
+    purpose: FramePurpose,
+) -> Result<PageRange, AllocError> {
+    let range = FRAME_ALLOCATOR.write().allocate_in(layout, zone, purpose)?;
+    // Safety: the range was just allocated.
+    unsafe { zero::zero_frames(range.start(), range.len().get()) };
+    Ok(range)
+}
+
+/// Frees `addr`, which must have been allocated for `purpose`, to the frame
+/// allocator. The range is zeroed first, before the lock is taken, if the
+/// scrub-on-free policy is enabled.
+pub fn free_frames(addr: PageRange, purpose: FramePurpose) {
+    if !page_desc::mark_free(addr, purpose) {
+        return;
+    }
+    // Frames coming back from a cache were scrubbed when they entered it.
+    if purpose != FramePurpose::Cache {
+        zero::scrub(addr.start(), addr.len().get());
+    }
+    FRAME_ALLOCATOR.write().release(addr, purpose);
+}
+
+/// Records that `range` was handed out for `purpose`.
+fn account_alloc(range: PageRange, purpose: FramePurpose) -> PageRange {
+    stats::account_alloc(purpose, range.pages().get());
+    page_desc::mark_allocated(range, purpose);
+    range
+}
+
 impl FrameAllocator {
     #[must_use]
-    pub const fn new(hhdm_offset: usize) -> Self {
+    pub fn new(hhdm_offset: usize) -> Self {
         Self {
-            allocator: FreeList::new(),
+            pools: core::array::from_fn(|_| {
+                core::array::from_fn(|_| BuddyAllocator::new(hhdm_offset))
+            }),
             hhdm_offset,
+            reclaimable: [(0, 0); MAX_RECLAIMABLE_REGIONS],
+            reclaimable_count: 0,
+            regions: [MemoryRegion {
+                base: 0,
+                length: 0,
+                type_: 0,
+            }; MAX_MEMMAP_REGIONS],
+            region_count: 0,
         }
     }
 
     /// initialization code for `frame allocator`.
     /// initializes the free memory based on the provided memory information from the boot loader
+    /// and takes over from the early allocator: its free ranges become free memory and what it
+    /// handed out is accounted as allocated. The NUMA topology and the page descriptors must
+    /// be set up by then.
     /// # Panics
-    /// when the free list allocator cant grab the memory
+    /// when the early allocator cannot provide the buddy bitmaps
     pub fn init(&mut self, memmap: &[&Entry]) {
-        memmap
+        let of_type = |type_| {
+            memmap
+                .iter()
+                .filter(move |region| region.type_ == type_)
+                .filter_map(|region| entry_range(region))
+        };
+        let usable = || of_type(MEMMAP_USABLE);
+        // The tracked spans also cover bootloader-reclaimable memory, so
+        // that it can be handed over later by `reclaim_bootloader_memory`.
+        let tracked = || usable().chain(of_type(MEMMAP_BOOTLOADER_RECLAIMABLE));
+
+        // Work out the span of every node's zones and the bitmap each one
+        // needs. Pools with no memory get an empty span.
+        let mut spans = [[(usize::MAX, 0); 3]; MAX_NODES];
+        for (start, end) in tracked() {
+            for (node, zone, range) in pool_pieces(start, end) {
+                let span = &mut spans[node][zone as usize];
+                *span = (span.0.min(range.start()), span.1.max(range.end()));
+            }
+        }
+        let spans = spans.as_flattened_mut();
+        for span in spans.iter_mut().filter(|span| span.0 > span.1) {
+            *span = (0, 0);
+        }
+        let bitmap_size = |(base, end)| BuddyAllocator::bitmap_size(base, end);
+        let bitmaps_size = spans
+            .iter()
+            .map(|&span| bitmap_size(span))
+            .sum::<usize>()
+            .next_multiple_of(PAGE_SIZE);
+        assert!(
+            bitmaps_size > 0,
+            "allocator: no usable memory in the memory map"
+        );
+
+        let layout = PageLayout::from_size_align(bitmaps_size, PAGE_SIZE)
+            .expect("allocator: invalid buddy bitmap layout");
+        let bitmaps = early::alloc_frames(layout, FramePurpose::Other)
+            .expect("allocator: out of early memory for the buddy bitmaps");
+        let mut bitmap = bitmaps.start() + self.hhdm_offset;
+        for (buddy, &(base, end)) in self.pools.as_flattened_mut().iter_mut().zip(&*spans) {
+            // Safety: the bitmap range was allocated from the early
+            // allocator for this purpose and is never freed.
+            unsafe { buddy.init(base, end, bitmap as *mut u64) };
+            bitmap += bitmap_size((base, end));
+        }
+
+        // Take over from the early allocator.
+        let early = early::hand_off();
+        for (start, end) in early.free_ranges() {
+            for (node, zone, range) in pool_pieces(start, end) {
+                self.pools[node][zone as usize].add_memory(range);
+            }
+        }
+        for allocation in early.allocations() {
+            let Ok(range) = PageRange::new(allocation.start, allocation.end) else {
+                continue;
+            };
+            stats::account_alloc(allocation.purpose, range.pages().get());
+            page_desc::mark_allocated(range, allocation.purpose);
+        }
+        drop(early);
+        for range in of_type(MEMMAP_BOOTLOADER_RECLAIMABLE) {
+            if self.reclaimable_count == MAX_RECLAIMABLE_REGIONS {
+                log::warn!("allocator: too many bootloader-reclaimable regions, some are lost");
+                break;
+            }
+            self.reclaimable[self.reclaimable_count] = range;
+            self.reclaimable_count += 1;
+        }
+        for region in memmap.iter().take(MAX_MEMMAP_REGIONS) {
+            self.regions[self.region_count] = MemoryRegion {
+                base: usize::try_from(region.base)
+                    .expect("allocator: invalid base in memory region"),
+                length: usize::try_from(region.length)
+                    .expect("allocator: invalid length in memory region"),
+                type_: region.type_,
+            };
+            self.region_count += 1;
+        }
+        if memmap.len() > MAX_MEMMAP_REGIONS {
+            log::warn!("allocator: memory map too long, region statistics are incomplete");
+        }
+        log::info!(
+            "buddy frame allocator initialized: {} KiB free.",
+            self.free_pages() * PAGE_SIZE / 1024
+        );
+    }
+
+    /// Hands every bootloader-reclaimable region recorded by `init` to the
+    /// allocator and returns the number of bytes recovered. Calling it again
+    /// does nothing.
+    ///
+    /// # Safety
+    /// Nothing may use bootloader-reclaimable memory any more: no Limine
+    /// page tables, stacks or request responses.
+    pub unsafe fn reclaim_bootloader_memory(&mut self) -> usize {
+        let mut reclaimed = 0;
+        for i in 0..self.reclaimable_count {
+            let (start, end) = self.reclaimable[i];
+            for (node, zone, range) in pool_pieces(start, end) {
+                page_desc::mark_managed(range);
+                self.pools[node][zone as usize].add_memory(range);
+                reclaimed += range.len().get();
+            }
+        }
+        self.reclaimable_count = 0;
+        reclaimed
+    }
+
+    /// allocates and returns memory based on the available free memory,
+    /// accounting it to `purpose`
+    /// # Errors
+    /// when no free block is large enough for the layout, we will get an allocation error.
+    pub fn allocate(
+        &mut self,
+        layout: PageLayout,
+        purpose: FramePurpose,
+    ) -> Result<PageRange, AllocError> {
+        self.allocate_in(layout, Zone::Normal, purpose)
+    }
+
+    /// Allocates from `zone`, falling back to the lower zones when it is
+    /// exhausted. A `Normal` request may thus be served from DMA32 and then
+    /// DMA, but a `Dma32` request never gets memory above 4 GiB. A fallback
+    /// leaves a sixteenth of each lower zone to the requests for that zone
+    /// and `allocate_below`.
+    ///
+    /// The node of the calling processor is tried first, then the other
+    /// nodes by increasing distance.
+    /// # Errors
+    /// when neither the zone nor any zone below it can satisfy the layout.
+    pub fn allocate_in(
+        &mut self,
+        layout: PageLayout,
+        zone: Zone,
+        purpose: FramePurpose,
+    ) -> Result<PageRange, AllocError> {
+        let order = numa::fallback_order(numa::current_node());
+        let range = order[..numa::node_count()]
             .iter()
-            .filter(|region| region.type_ == MEMMAP_USABLE)
-            .map(|region| {
-                let start =
-                    usize::try_from(region.base).expect("allocator: invalid base in memory region");
-                let length = usize::try_from(region.length)
-                    .expect("allocator: invalid length in memory region");
-                let end = start
-                    .checked_add(length)
-                    .expect("allocator: integer overflow in memory region calculation");
-                (start..end).try_into()
+            .find_map(|&node| self.allocate_from_node(layout, node, zone, false))
+            .ok_or(AllocError)?;
+        Ok(account_alloc(range, purpose))
+    }
+
+    /// Allocates from `node` only, in any zone, for memory that must be
+    /// local to a given node regardless of which processor asks for it.
+    /// # Errors
+    /// when the node does not exist or has no free block large enough for
+    /// the layout.
+    pub fn allocate_on_node(
+        &mut self,
+        layout: PageLayout,
+        node: usize,
+        purpose: FramePurpose,
+    ) -> Result<PageRange, AllocError> {
+        if node >= numa::node_count() {
+            return Err(AllocError);
+        }
+        let range = self
+            .allocate_from_node(layout, node, Zone::Normal, false)
+            .ok_or(AllocError)?;
+        Ok(account_alloc(range, purpose))
+    }
+
+    /// Allocates from `zone` on `node`, falling back to the lower zones,
+    /// whose reserves only count when not `use_reserve`.
+    fn allocate_from_node(
+        &mut self,
+        layout: PageLayout,
+        node: usize,
+        zone: Zone,
+        use_reserve: bool,
+    ) -> Option<PageRange> {
+        let pages = layout.size() / PAGE_SIZE;
+        self.pools[node][..=zone as usize]
+            .iter_mut()
+            .enumerate()
+            .rev()
+            .filter(|(index, buddy)| {
+                // Lower zones keep their reserve for the devices that need them.
+                use_reserve
+                    || *index == zone as usize
+                    || buddy.free_pages() >= pages + (buddy.total_pages() >> LOW_RESERVE_SHIFT)
             })
-            .filter_map(Result::ok)
-            .for_each(|region: PageRange| {
-                unsafe {
-                    self.allocator
-                        .deallocate(region)
-                        .expect("failed to add the memory region to the allocator.");
-                };
-            });
-        log::info!("freelist memory allocator initialized.");
-    }
-
-    /// allocates and returns memory based on the available free memory
+            .find_map(|(_, buddy)| buddy.allocate(layout).ok())
+    }
+
+    /// Allocates a range that ends at or below the physical address `limit`,
+    /// for devices whose addressing limit does not match a zone boundary.
+    /// On every node, nearest first, zones entirely below the limit are
+    /// tried first, highest first, and the zone the limit falls into last.
     /// # Errors
-    /// when no memory is available on the free list to allocate, we will get an allocation error.
-    pub fn allocate(&mut self, layout: PageLayout) -> Result<PageRange, AllocError> {
-        self.allocator.allocate(layout)
+    /// when no free block below the limit is large enough for the layout.
+    pub fn allocate_below(
+        &mut self,
+        layout: PageLayout,
+        limit: usize,
+        purpose: FramePurpose,
+    ) -> Result<PageRange, AllocError> {
+        let straddling = Zone::ALL
+            .into_iter()
+            .find(|zone| zone.range().1 > limit && zone.range().0 < limit);
+        let below = Zone::ALL
+            .into_iter()
+            .rev()
+            .find(|zone| zone.range().1 <= limit);
+        let order = numa::fallback_order(numa::current_node());
+        let range = order[..numa::node_count()]
+            .iter()
+            .find_map(|&node| {
+                below
+                    .and_then(|zone| self.allocate_from_node(layout, node, zone, true))
+                    .or_else(|| {
+                        let zone = straddling?;
+                        self.pools[node][zone as usize]
+                            .allocate_below(layout, limit)
+                            .ok()
+                    })
+            })
+            .ok_or(AllocError)?;
+        Ok(account_alloc(range, purpose))
+    }
+
+    /// Frees `addr`, which must have been allocated for `purpose`. The range
+    /// is not scrubbed, as that would hold the lock for a memset: use
+    /// `free_frames` unless the frames are known to be clean.
+    pub fn deallocate(&mut self, addr: PageRange, purpose: FramePurpose) {
+        if page_desc::mark_free(addr, purpose) {
+            self.release(addr, purpose);
+        }
     }
 
-    pub fn deallocate(&mut self, addr: PageRange) {
-        unsafe { self.allocator.deallocate(addr).ok() };
+    /// Returns `addr`, already marked free, to the pools.
+    fn release(&mut self, addr: PageRange, purpose: FramePurpose) {
+        for (node, zone, range) in pool_pieces(addr.start(), addr.end()) {
+            self.pools[node][zone as usize].deallocate(range);
+        }
+        stats::account_free(purpose, addr.pages().get());
+    }
+
+    /// Returns the number of pages managed by the allocator.
+    #[must_use]
+    pub fn total_pages(&self) -> usize {
+        self.pools
+            .as_flattened()
+            .iter()
+            .map(BuddyAllocator::total_pages)
+            .sum()
+    }
+
+    /// Returns the number of free pages.
+    #[must_use]
+    pub fn free_pages(&self) -> usize {
+        self.pools
+            .as_flattened()
+            .iter()
+            .map(BuddyAllocator::free_pages)
+            .sum()
+    }
+
+    /// Returns the size in bytes of the largest physically contiguous block
+    /// that a single allocation can currently get.
+    #[must_use]
+    pub fn largest_free_block(&self) -> usize {
+        self.pools
+            .as_flattened()
+            .iter()
+            .filter_map(BuddyAllocator::largest_free_order)
+            .max()
+            .map_or(0, |order| PAGE_SIZE << order.min(MAX_ORDER))
+    }
+
+    /// Returns a snapshot of physical memory usage.
+    #[must_use]
+    pub fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            total_pages: self.total_pages(),
+            free_pages: self.free_pages(),
+            allocated_pages: FramePurpose::ALL.map(stats::allocated_pages),
+            largest_free_block: self.largest_free_block(),
+            zones: Zone::ALL.map(|zone| self.zone_usage(zone)),
+            node_count: numa::node_count(),
+            nodes: core::array::from_fn(|node| self.node_usage(node)),
+        }
+    }
+
+    /// Returns the usage of every memory map region seen at `init`. This
+    /// walks the free lists, so it is slow with fragmented memory.
+    pub fn region_stats(&self) -> impl Iterator<Item = RegionStats> + '_ {
+        self.regions[..self.region_count].iter().map(|&region| {
+            let start = region.base.next_multiple_of(PAGE_SIZE);
+            let end = (region.base + region.length) & !(PAGE_SIZE - 1);
+            let free_pages = pool_pieces(start, end)
+                .map(|(node, zone, range)| {
+                    self.pools[node][zone as usize].free_pages_in(range.start(), range.end())
+                })
+                .sum();
+            RegionStats { region, free_pages }
+        })
+    }
+
+    /// Returns the page counts of `zone`, summed over all nodes.
+    #[must_use]
+    pub fn zone_usage(&self, zone: Zone) -> ZoneUsage {
+        let buddies = || self.pools.iter().map(|pools| &pools[zone as usize]);
+        ZoneUsage {
+            zone,
+            total_pages: buddies().map(BuddyAllocator::total_pages).sum(),
+            free_pages: buddies().map(BuddyAllocator::free_pages).sum(),
+        }
+    }
+
+    /// Returns the page counts of NUMA node `node`, over all zones.
+    #[must_use]
+    pub fn node_usage(&self, node: usize) -> NodeUsage {
+        let pools = &self.pools[node];
+        NodeUsage {
+            node,
+            total_pages: pools.iter().map(BuddyAllocator::total_pages).sum(),
+            free_pages: pools.iter().map(BuddyAllocator::free_pages).sum(),
+        }
     }
 }
@@ -0,0 +1,197 @@
+//! Address space identifiers: PCIDs on x86_64, ASIDs on riscv64 and
+//! aarch64.
+//!
+//! Tagging TLB entries with the address space they belong to lets a
+//! processor switch address spaces without flushing its TLB. An address
+//! space gets an identifier the first time it is activated, from a bitmap
+//! covering as many identifiers as the hardware implements. Identifier 0
+//! belongs to the kernel page table and is never handed out.
+//!
+//! When the bitmap runs out, the generation is bumped and every identifier
+//! is free again, except the ones processors are running on, which keep
+//! theirs. An address space holding an identifier of an older generation
+//! gets a new one when it is next activated, and every processor flushes
+//! all of its tagged entries before its first switch in the new generation,
+//! so no stale translation survives an identifier being reused.
+//!
+//! Without hardware support (x86_64 without PCID and INVPCID, riscv64 harts
+//! that implement no more ASIDs than there can be processors, loongarch64
+//! for now) everything runs on identifier 0 and every switch flushes the
+//! TLB. Architectures only report identifiers when there are more than
+//! `MAX_CPUS + 1`, so a rollover always leaves one free.
+use crate::arch;
+use crate::memory::frame_cache::MAX_CPUS;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+/// An address space identifier.
+pub type Asid = u16;
+
+/// Identifier of the kernel page table.
+pub const KERNEL_ASID: Asid = 0;
+
+/// Largest number of identifiers any architecture implements.
+const MAX_ASIDS: usize = 1 << 16;
+/// A context is `generation << GENERATION_SHIFT | asid`; 0 means none.
+const GENERATION_SHIFT: u32 = 16;
+
+const fn asid_of(context: u64) -> Asid {
+    (context & 0xffff) as Asid
+}
+
+const fn generation_of(context: u64) -> u64 {
+    context >> GENERATION_SHIFT
+}
+
+/// The identifier of an address space and the generation it belongs to.
+#[derive(Debug, Default)]
+pub struct AsidContext(AtomicU64);
+
+impl AsidContext {
+    /// Creates a context with no identifier assigned yet.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    /// Returns the identifier last assigned, if any. It may belong to an
+    /// older generation; TLBs may hold entries tagged with it either way.
+    #[must_use]
+    pub fn asid(&self) -> Option<Asid> {
+        let context = self.0.load(Ordering::Acquire);
+        (context != 0).then_some(asid_of(context))
+    }
+}
+
+struct Allocator {
+    generation: u64,
+    /// Identifiers handed out in the current generation.
+    used: [u64; MAX_ASIDS / 64],
+    /// Where the search for a free identifier resumes.
+    next: usize,
+    /// Context each processor switched to last, or 0 for the kernel page
+    /// table or when it has not switched since the last rollover.
+    active: [u64; MAX_CPUS],
+    /// Context each processor was running on at the last rollover.
+    reserved: [u64; MAX_CPUS],
+    /// Processors that must flush all tagged entries before their next
+    /// switch, as `CpuMask` bits.
+    flush_pending: u64,
+}
+
+static ALLOCATOR: Mutex<Allocator> = Mutex::new(Allocator::new());
+
+/// Returns `true` if the hardware tags TLB entries with identifiers.
+#[must_use]
+pub fn enabled() -> bool {
+    arch::asid_bits() > 0
+}
+
+impl Allocator {
+    const fn new() -> Self {
+        let mut used = [0; MAX_ASIDS / 64];
+        used[0] = 1 << KERNEL_ASID;
+        Self {
+            generation: 1,
+            used,
+            next: 1,
+            active: [0; MAX_CPUS],
+            reserved: [0; MAX_CPUS],
+            flush_pending: 0,
+        }
+    }
+
+    fn is_used(&self, asid: usize) -> bool {
+        self.used[asid / 64] & (1 << (asid % 64)) != 0
+    }
+
+    fn mark_used(&mut self, asid: usize) {
+        self.used[asid / 64] |= 1 << (asid % 64);
+    }
+
+    /// Starts a new generation in which only the identifiers processors
+    /// are running on stay taken.
+    fn rollover(&mut self) {
+        self.generation += 1;
+        self.used = [0; MAX_ASIDS / 64];
+        self.mark_used(usize::from(KERNEL_ASID));
+        for cpu in 0..MAX_CPUS {
+            let mut context = core::mem::take(&mut self.active[cpu]);
+            if context == 0 {
+                // Not switched since the previous rollover: still running on
+                // what it ran on then.
+                context = self.reserved[cpu];
+            }
+            if context != 0 {
+                self.mark_used(usize::from(asid_of(context)));
+            }
+            self.reserved[cpu] = context;
+        }
+        self.next = 1;
+        self.flush_pending = u64::MAX;
+        log::debug!("asid: rolled over to generation {}", self.generation);
+    }
+
+    /// Returns a context of the current generation for an address space
+    /// whose last context was `old`.
+    fn new_context(&mut self, old: u64) -> u64 {
+        let current = self.generation << GENERATION_SHIFT;
+        if old != 0 && self.reserved.contains(&old) {
+            // A processor still runs on it, so it kept its identifier.
+            let context = current | u64::from(asid_of(old));
+            for reserved in &mut self.reserved {
+                if *reserved == old {
+                    *reserved = context;
+                }
+            }
+            return context;
+        }
+        let count = 1 << arch::asid_bits();
+        match (self.next..count).find(|&asid| !self.is_used(asid)) {
+            Some(asid) => {
+                self.mark_used(asid);
+                self.next = asid + 1;
+                current | asid as u64
+            }
+            None => {
+                // Right after a rollover, only the identifiers of running
+                // processors are taken, and there are more than those.
+                assert!(self.next > 1, "asid: no identifier left after a rollover");
+                self.rollover();
+                self.new_context(old)
+            }
+        }
+    }
+}
+
+/// Returns the identifier to load together with the address space of
+/// `context` on this processor, assigning a new one if its identifier
+/// belongs to an older generation. Flushes this processor's tagged entries
+/// first if a rollover asked for it. Returns `KERNEL_ASID` when the
+/// hardware has no identifiers.
+pub fn switch_to(context: &AsidContext) -> Asid {
+    if !enabled() {
+        return KERNEL_ASID;
+    }
+    let cpu = arch::cpu_index();
+    let mut allocator = ALLOCATOR.lock();
+    let mut current = context.0.load(Ordering::Relaxed);
+    if generation_of(current) != allocator.generation {
+        current = allocator.new_context(current);
+        context.0.store(current, Ordering::Release);
+    }
+    allocator.active[cpu] = current;
+    let this = 1 << cpu;
+    if allocator.flush_pending & this != 0 {
+        allocator.flush_pending &= !this;
+        arch::flush_tlb_local(None, None);
+    }
+    asid_of(current)
+}
+
+/// Records that this processor switched back to the kernel page table.
+pub fn switch_to_kernel() {
+    if enabled() {
+        ALLOCATOR.lock().active[arch::cpu_index()] = 0;
+    }
+}
@@ -0,0 +1,303 @@
+//! Binary buddy allocator for physical page frames.
+//!
+//! Free blocks are kept on one intrusive, doubly linked list per order. The
+//! list nodes live inside the free frames themselves and are reached through
+//! the HHDM, so the allocator needs no heap. A bitmap with one bit per frame
+//! marks the first frame of every free block, which lets `free_block` find
+//! and coalesce a free buddy in constant time per order.
+use crate::memory::PAGE_SIZE;
+use crate::memory::allocator::AllocError;
+use free_list::{PageLayout, PageRange};
+
+/// Largest block order handed out: `2^18` pages, i.e. 1 GiB.
+pub const MAX_ORDER: usize = 18;
+
+/// End-of-list marker for the intrusive free lists.
+const NIL: usize = usize::MAX;
+
+/// Header written at the start of every free block.
+#[repr(C)]
+struct FreeBlock {
+    next: usize,
+    prev: usize,
+    order: usize,
+}
+
+pub struct BuddyAllocator {
+    /// Physical address of the first block of each order's free list.
+    heads: [usize; MAX_ORDER + 1],
+    /// One bit per frame in `base..end`, set when that frame starts a free block.
+    bitmap: &'static mut [u64],
+    base: usize,
+    end: usize,
+    hhdm_offset: usize,
+    total_pages: usize,
+    free_pages: usize,
+}
+
+impl BuddyAllocator {
+    #[must_use]
+    pub const fn new(hhdm_offset: usize) -> Self {
+        Self {
+            heads: [NIL; MAX_ORDER + 1],
+            bitmap: &mut [],
+            base: 0,
+            end: 0,
+            hhdm_offset,
+            total_pages: 0,
+            free_pages: 0,
+        }
+    }
+
+    /// Number of bytes of bitmap storage needed to track `base..end`.
+    #[must_use]
+    pub const fn bitmap_size(base: usize, end: usize) -> usize {
+        let frames = (end - base) / PAGE_SIZE;
+        frames.div_ceil(64) * core::mem::size_of::<u64>()
+    }
+
+    /// Sets up the allocator to track the physical span `base..end`, using
+    /// the (HHDM-mapped) memory at `bitmap` as its free-block bitmap. The
+    /// span starts out fully allocated; hand memory over with `add_memory`.
+    ///
+    /// # Safety
+    /// `bitmap` must point to at least `bitmap_size(base, end)` writable
+    /// bytes that are not used for anything else for the life of the kernel.
+    pub unsafe fn init(&mut self, base: usize, end: usize, bitmap: *mut u64) {
+        let words = Self::bitmap_size(base, end) / core::mem::size_of::<u64>();
+        // Safety: guaranteed by the caller.
+        self.bitmap = unsafe { core::slice::from_raw_parts_mut(bitmap, words) };
+        self.bitmap.fill(0);
+        self.base = base;
+        self.end = end;
+    }
+
+    /// Returns the number of pages handed over with `add_memory`.
+    #[must_use]
+    pub const fn total_pages(&self) -> usize {
+        self.total_pages
+    }
+
+    /// Returns the number of free pages.
+    #[must_use]
+    pub const fn free_pages(&self) -> usize {
+        self.free_pages
+    }
+
+    /// Returns the order of the largest free block, if any block is free.
+    #[must_use]
+    pub fn largest_free_order(&self) -> Option<usize> {
+        (0..=MAX_ORDER)
+            .rev()
+            .find(|&order| self.heads[order] != NIL)
+    }
+
+    /// Counts the free pages inside `start..end` by walking every free list.
+    /// This is linear in the number of free blocks, so it is meant for
+    /// introspection rather than hot paths.
+    #[must_use]
+    pub fn free_pages_in(&self, start: usize, end: usize) -> usize {
+        let mut pages = 0;
+        for (order, &head) in self.heads.iter().enumerate() {
+            let mut addr = head;
+            while addr != NIL {
+                let block_end = addr + (PAGE_SIZE << order);
+                let overlap_start = addr.max(start);
+                let overlap_end = block_end.min(end);
+                if overlap_start < overlap_end {
+                    pages += (overlap_end - overlap_start) / PAGE_SIZE;
+                }
+                // Safety: `addr` is on a free list, so its header is valid.
+                addr = unsafe { (*self.header(addr)).next };
+            }
+        }
+        pages
+    }
+
+    /// Returns `true` if `addr..addr + len` lies inside the tracked span.
+    #[must_use]
+    pub const fn contains(&self, addr: usize, len: usize) -> bool {
+        addr >= self.base && addr < self.end && len <= self.end - addr
+    }
+
+    /// Hands `range` to the allocator for the first time, growing the
+    /// amount of memory it manages.
+    pub fn add_memory(&mut self, range: PageRange) {
+        if self.contains(range.start(), range.len().get()) {
+            self.total_pages += range.pages().get();
+        }
+        self.deallocate(range);
+    }
+
+    /// Allocates a physically contiguous range satisfying `layout`.
+    ///
+    /// The request is rounded up to a power-of-two block that also satisfies
+    /// the alignment; the unused tail of that block is returned to the free
+    /// lists straight away, so the caller gets exactly `layout.size()` bytes.
+    /// # Errors
+    /// when no block of the required order is free.
+    pub fn allocate(&mut self, layout: PageLayout) -> Result<PageRange, AllocError> {
+        self.allocate_below(layout, usize::MAX)
+    }
+
+    /// Like `allocate`, but the returned range must end at or below the
+    /// physical address `limit`. Blocks that start too high are skipped, so
+    /// this may have to walk the free lists.
+    /// # Errors
+    /// when no suitable block is free.
+    pub fn allocate_below(
+        &mut self,
+        layout: PageLayout,
+        limit: usize,
+    ) -> Result<PageRange, AllocError> {
+        let pages = layout.size() / PAGE_SIZE;
+        if pages == 0 {
+            return Err(AllocError);
+        }
+        let size_order = pages.next_power_of_two().trailing_zeros() as usize;
+        let align_order = (layout.align() / PAGE_SIZE).trailing_zeros() as usize;
+        let order = size_order.max(align_order);
+        if order > MAX_ORDER {
+            return Err(AllocError);
+        }
+        let addr = self.alloc_block(order, limit).ok_or(AllocError)?;
+        let used_end = addr + layout.size();
+        let block_end = addr + (PAGE_SIZE << order);
+        if used_end < block_end {
+            self.add_range(used_end, block_end);
+        }
+        self.free_pages -= pages;
+        PageRange::new(addr, used_end).map_err(|_| AllocError)
+    }
+
+    /// Returns `range` to the free lists, merging it with free buddies.
+    /// The range does not have to match a previous allocation; it is split
+    /// into naturally aligned blocks first.
+    pub fn deallocate(&mut self, range: PageRange) {
+        let start = range.start();
+        let end = range.end();
+        if !self.contains(start, end - start) {
+            log::warn!("buddy: ignoring free of {range} outside the tracked span");
+            return;
+        }
+        let pages = (end - start) / PAGE_SIZE;
+        self.add_range(start, end);
+        self.free_pages += pages;
+    }
+
+    /// Splits `start..end` into the largest naturally aligned blocks that fit
+    /// and frees each of them. Does not touch `free_pages`.
+    fn add_range(&mut self, mut start: usize, end: usize) {
+        while start < end {
+            let frame = start / PAGE_SIZE;
+            let remaining = (end - start) / PAGE_SIZE;
+            let align_order = if frame == 0 {
+                MAX_ORDER
+            } else {
+                frame.trailing_zeros() as usize
+            };
+            let fit_order = (usize::BITS - 1 - remaining.leading_zeros()) as usize;
+            let order = align_order.min(fit_order).min(MAX_ORDER);
+            self.free_block(start, order);
+            start += PAGE_SIZE << order;
+        }
+    }
+
+    /// Pops a block of exactly `order` that ends at or below `limit`,
+    /// splitting a larger one if needed. Splitting keeps the lower half, so
+    /// a larger block qualifies as long as its first `order` block does.
+    fn alloc_block(&mut self, order: usize, limit: usize) -> Option<usize> {
+        let (addr, mut current) = (order..=MAX_ORDER).find_map(|o| {
+            let mut addr = self.heads[o];
+            while addr != NIL {
+                if addr + (PAGE_SIZE << order) <= limit {
+                    return Some((addr, o));
+                }
+                // Safety: `addr` is on a free list, so its header is valid.
+                addr = unsafe { (*self.header(addr)).next };
+            }
+            None
+        })?;
+        self.remove(addr, current);
+        // Hand the upper halves back until the block has the requested order.
+        while current > order {
+            current -= 1;
+            self.push(addr + (PAGE_SIZE << current), current);
+        }
+        Some(addr)
+    }
+
+    /// Frees a single naturally aligned block, coalescing with its buddy for
+    /// as long as the buddy is free and of the same order.
+    fn free_block(&mut self, mut addr: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let size = PAGE_SIZE << order;
+            let buddy = addr ^ size;
+            if !self.contains(buddy, size) || !self.is_free_head(buddy) {
+                break;
+            }
+            // Safety: the bitmap says a free block starts at `buddy`, so its
+            // header is valid.
+            if unsafe { (*self.header(buddy)).order } != order {
+                break;
+            }
+            self.remove(buddy, order);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(addr, order);
+    }
+
+    fn push(&mut self, addr: usize, order: usize) {
+        let next = self.heads[order];
+        // Safety: `addr` starts a block that is being freed and is owned by
+        // the allocator from now on; `next` is a free block header.
+        unsafe {
+            self.header(addr).write(FreeBlock {
+                next,
+                prev: NIL,
+                order,
+            });
+            if next != NIL {
+                (*self.header(next)).prev = addr;
+            }
+        }
+        self.heads[order] = addr;
+        self.set_free_head(addr, true);
+    }
+
+    fn remove(&mut self, addr: usize, order: usize) {
+        // Safety: callers only pass blocks that are currently on a free list.
+        unsafe {
+            let block = self.header(addr);
+            let (next, prev) = ((*block).next, (*block).prev);
+            if prev == NIL {
+                self.heads[order] = next;
+            } else {
+                (*self.header(prev)).next = next;
+            }
+            if next != NIL {
+                (*self.header(next)).prev = prev;
+            }
+        }
+        self.set_free_head(addr, false);
+    }
+
+    fn header(&self, addr: usize) -> *mut FreeBlock {
+        (addr + self.hhdm_offset) as *mut FreeBlock
+    }
+
+    fn is_free_head(&self, addr: usize) -> bool {
+        let bit = (addr - self.base) / PAGE_SIZE;
+        self.bitmap[bit / 64] & (1 << (bit % 64)) != 0
+    }
+
+    fn set_free_head(&mut self, addr: usize, free: bool) {
+        let bit = (addr - self.base) / PAGE_SIZE;
+        if free {
+            self.bitmap[bit / 64] |= 1 << (bit % 64);
+        } else {
+            self.bitmap[bit / 64] &= !(1 << (bit % 64));
+        }
+    }
+}
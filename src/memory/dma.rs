@@ -0,0 +1,228 @@
+//! Physically contiguous buffers for device DMA.
+//!
+//! A `DmaBuffer` owns a zeroed, physically contiguous range with a known bus
+//! address (there is no IOMMU, so bus and physical addresses are the same)
+//! and a kernel virtual address to reach it through. On x86_64 and
+//! loongarch64 DMA is always cache-coherent and the virtual address is in
+//! the HHDM. On aarch64 and riscv64, buffers for devices that do not snoop
+//! the caches get an uncached alias instead, at `kaslr::Layout::dma_uncached_start`
+//! plus the physical address.
+//!
+//! Such buffers stay mapped cacheable in the HHDM too, which must not be
+//! used to reach them. Speculation may still pull lines in through it, but
+//! never dirty ones, so the range is cleaned and invalidated by virtual
+//! address after zeroing, before the uncached alias is handed out, and
+//! again once the alias is gone, before the frames are freed.
+use crate::memory::allocator::{self, AllocError};
+use crate::memory::stats::FramePurpose;
+use crate::memory::tlb::TlbBatch;
+use crate::memory::{FRAME_ALLOCATOR, PAGE_SIZE, hhdm_offset, zero};
+use free_list::{PageLayout, PageRange};
+use memory_addr::{PhysAddr, VirtAddr};
+
+/// What a device requires of a DMA buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaConstraints {
+    /// Alignment of the bus address, a power of two of at least a page.
+    pub align: usize,
+    /// If set, the buffer must not cross a multiple of this many bytes, a
+    /// power of two. Common for descriptor rings.
+    pub boundary: Option<usize>,
+    /// Highest bus address the device can reach, exclusive.
+    pub limit: usize,
+    /// Whether the device snoops CPU caches. Ignored on architectures where
+    /// DMA is always coherent.
+    pub coherent: bool,
+}
+
+impl DmaConstraints {
+    /// Page-aligned, anywhere in memory, coherent.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            align: PAGE_SIZE,
+            boundary: None,
+            limit: usize::MAX,
+            coherent: true,
+        }
+    }
+
+    /// Like `new`, but below 4 GiB for devices with 32-bit DMA.
+    #[must_use]
+    pub const fn dma32() -> Self {
+        Self {
+            limit: 0x1_0000_0000,
+            ..Self::new()
+        }
+    }
+}
+
+impl Default for DmaConstraints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct DmaBuffer {
+    range: PageRange,
+    vaddr: usize,
+    /// Whether `vaddr` is an uncached alias that must be unmapped on drop.
+    uncached: bool,
+}
+
+// Safety: the buffer exclusively owns its frames and alias mapping.
+unsafe impl Send for DmaBuffer {}
+unsafe impl Sync for DmaBuffer {}
+
+impl DmaBuffer {
+    /// Allocates a zeroed buffer of at least `size` bytes with the default
+    /// constraints.
+    /// # Errors
+    /// when no suitable physical memory is free.
+    pub fn new(size: usize) -> Result<Self, AllocError> {
+        Self::with_constraints(size, DmaConstraints::new())
+    }
+
+    /// Allocates a zeroed buffer of at least `size` bytes satisfying
+    /// `constraints`. The size is rounded up to whole pages.
+    /// # Errors
+    /// when no suitable physical memory is free, or the uncached alias
+    /// cannot be mapped.
+    /// # Panics
+    /// when the constraints are malformed or cannot be met by any buffer of
+    /// that size, e.g. a buffer larger than its boundary.
+    pub fn with_constraints(size: usize, constraints: DmaConstraints) -> Result<Self, AllocError> {
+        let size = size.max(1).next_multiple_of(PAGE_SIZE);
+        assert!(
+            constraints.align.is_power_of_two(),
+            "dma: alignment must be a power of two"
+        );
+        let mut align = constraints.align.max(PAGE_SIZE);
+        if let Some(boundary) = constraints.boundary {
+            assert!(
+                boundary.is_power_of_two() && size <= boundary,
+                "dma: buffer of {size} bytes cannot respect a {boundary} byte boundary"
+            );
+            // A block aligned to its own power-of-two size never crosses a
+            // larger power-of-two boundary.
+            align = align.max(size.next_power_of_two());
+        }
+        let layout = PageLayout::from_size_align(size, align).map_err(|_| AllocError)?;
+        let range = FRAME_ALLOCATOR.write().allocate_below(
+            layout,
+            constraints.limit,
+            FramePurpose::Driver,
+        )?;
+        // Safety: the range was just allocated.
+        unsafe { zero::zero_frames(range.start(), size) };
+
+        let buffer = Self {
+            range,
+            vaddr: range.start() + hhdm_offset(),
+            uncached: false,
+        };
+        #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+        let buffer = {
+            let mut buffer = buffer;
+            if !constraints.coherent {
+                buffer.map_uncached()?;
+            }
+            buffer
+        };
+        Ok(buffer)
+    }
+
+    /// Maps the buffer uncached at its alias address.
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    fn map_uncached(&mut self) -> Result<(), AllocError> {
+        use crate::memory::kaslr;
+        use crate::memory::vmm::dma_uncached_size;
+        use page_table_multiarch::{MappingFlags, PageSize};
+
+        let start = self.range.start();
+        // Buffers must lie below the size of the window to get an alias.
+        if self.range.end() > dma_uncached_size() {
+            log::warn!("dma: {} is beyond the uncached alias window", self.range);
+            return Err(AllocError);
+        }
+        // The zeroes must reach memory, and no dirty line may be written
+        // back over what the device stores later.
+        if !crate::arch::clean_invalidate_dcache(start + hhdm_offset(), self.len()) {
+            log::warn!("dma: no cache maintenance, non-coherent DMA buffer may see stale lines");
+        }
+        #[cfg(target_arch = "riscv64")]
+        if !crate::arch::has_svpbmt() {
+            log::warn!("dma: no Svpbmt, non-coherent DMA buffer stays cacheable");
+        }
+        // The alias is tracked before mapping so a partial failure is undone
+        // by `Drop`.
+        self.vaddr = kaslr::layout().dma_uncached_start + start;
+        self.uncached = true;
+        let flags = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::UNCACHED;
+        for offset in (0..self.len()).step_by(PAGE_SIZE) {
+            let vaddr = self.vaddr + offset;
+            crate::memory::PAGE_MAPPER
+                .write()
+                .cursor()
+                .map(
+                    VirtAddr::from(vaddr),
+                    PhysAddr::from(start + offset),
+                    PageSize::Size4K,
+                    flags,
+                )
+                .map_err(|_| AllocError)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the address the device must be programmed with.
+    #[must_use]
+    pub fn bus_addr(&self) -> PhysAddr {
+        PhysAddr::from(self.range.start())
+    }
+
+    /// Returns the kernel virtual address of the buffer.
+    #[must_use]
+    pub fn vaddr(&self) -> VirtAddr {
+        VirtAddr::from(self.vaddr)
+    }
+
+    /// Returns a pointer to the start of the buffer. The device may write
+    /// to the buffer at any time, so access goes through raw pointers.
+    #[must_use]
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.vaddr as *mut u8
+    }
+
+    /// Returns the size of the buffer in bytes.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.range.len().get()
+    }
+
+    /// Buffers are never empty; provided for symmetry with `len`.
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        if self.uncached {
+            let mut mapper = crate::memory::PAGE_MAPPER.write();
+            for offset in (0..self.len()).step_by(PAGE_SIZE) {
+                let _ = mapper.cursor().unmap(VirtAddr::from(self.vaddr + offset));
+            }
+            drop(mapper);
+            // No processor may reach the frames through the alias once they
+            // are freed.
+            TlbBatch::kernel().invalidate(self.vaddr, self.len());
+            // Drop lines speculatively loaded through the HHDM while the
+            // device owned the buffer, before the frames are reused.
+            #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+            crate::arch::clean_invalidate_dcache(self.range.start() + hhdm_offset(), self.len());
+        }
+        allocator::free_frames(self.range, FramePurpose::Driver);
+    }
+}
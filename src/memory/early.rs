@@ -0,0 +1,213 @@
+//! Early boot allocator, usable before `FRAME_ALLOCATOR` and the heap.
+//!
+//! `early::init` copies the usable ranges of the Limine memory map and then
+//! hands out frames by bumping down from the top of the highest range that
+//! fits, which keeps low memory free for devices. Every allocation is
+//! recorded with its `FramePurpose`. When the frame allocator starts it
+//! calls `hand_off`, takes over the ranges that are still free and accounts
+//! the recorded allocations as its own; nothing the early allocator handed
+//! out is ever freed by it.
+//!
+//! The global allocator also falls back to `alloc` until the heap is
+//! initialized, so `Box` works from the first line of `main`. Such early
+//! heap allocations are never freed.
+use crate::memory::allocator::AllocError;
+use crate::memory::stats::FramePurpose;
+use crate::memory::{FRAME_ALLOCATOR, PAGE_SIZE, hhdm_offset};
+use core::alloc::Layout;
+use free_list::{PageLayout, PageRange};
+use limine::memmap::{Entry, MEMMAP_USABLE};
+use spin::{Mutex, MutexGuard, Spin};
+
+/// Maximum number of free ranges tracked, including the gaps left by
+/// alignment.
+const MAX_FREE_RANGES: usize = 256;
+/// Maximum number of allocation records; adjacent allocations for the same
+/// purpose share a record.
+const MAX_ALLOCATIONS: usize = 64;
+
+/// A range handed out by the early allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EarlyAllocation {
+    pub start: usize,
+    pub end: usize,
+    pub purpose: FramePurpose,
+}
+
+pub struct EarlyAllocator {
+    /// Free physical ranges `(start, end)`, page-aligned.
+    free: [(usize, usize); MAX_FREE_RANGES],
+    free_count: usize,
+    allocations: [EarlyAllocation; MAX_ALLOCATIONS],
+    allocation_count: usize,
+    /// Set once the frame allocator has taken over.
+    handed_off: bool,
+    /// Sub-page bump pointer for early heap allocations, as an HHDM address,
+    /// and the end of its page.
+    heap_next: usize,
+    heap_end: usize,
+}
+
+static EARLY: Mutex<EarlyAllocator> = Mutex::new(EarlyAllocator {
+    free: [(0, 0); MAX_FREE_RANGES],
+    free_count: 0,
+    allocations: [EarlyAllocation {
+        start: 0,
+        end: 0,
+        purpose: FramePurpose::Other,
+    }; MAX_ALLOCATIONS],
+    allocation_count: 0,
+    handed_off: false,
+    heap_next: 0,
+    heap_end: 0,
+});
+
+/// Takes the usable ranges of `memmap`. Must run before anything allocates,
+/// once the HHDM offset is known.
+pub fn init(memmap: &[&Entry]) {
+    let mut early = EARLY.lock();
+    for entry in memmap.iter().filter(|entry| entry.type_ == MEMMAP_USABLE) {
+        let base = usize::try_from(entry.base).expect("early: invalid base in memory region");
+        let length = usize::try_from(entry.length).expect("early: invalid length in memory region");
+        let start = base.next_multiple_of(PAGE_SIZE);
+        let end = (base + length) & !(PAGE_SIZE - 1);
+        if start < end {
+            early.add_free(start, end);
+        }
+    }
+}
+
+impl EarlyAllocator {
+    fn add_free(&mut self, start: usize, end: usize) {
+        if self.free_count == MAX_FREE_RANGES {
+            log::warn!("early: too many free ranges, {start:#x}-{end:#x} is lost");
+            return;
+        }
+        self.free[self.free_count] = (start, end);
+        self.free_count += 1;
+    }
+
+    fn record(&mut self, start: usize, end: usize, purpose: FramePurpose) {
+        // Bumping down makes consecutive allocations adjacent.
+        if let Some(last) = self.allocations[..self.allocation_count].last_mut()
+            && last.start == end
+            && last.purpose == purpose
+        {
+            last.start = start;
+            return;
+        }
+        assert!(
+            self.allocation_count < MAX_ALLOCATIONS,
+            "early: too many allocations to hand off"
+        );
+        self.allocations[self.allocation_count] = EarlyAllocation {
+            start,
+            end,
+            purpose,
+        };
+        self.allocation_count += 1;
+    }
+
+    fn allocate(
+        &mut self,
+        layout: PageLayout,
+        purpose: FramePurpose,
+    ) -> Result<PageRange, AllocError> {
+        let size = layout.size();
+        let align = layout.align();
+        // Highest range first.
+        let (index, start) = self.free[..self.free_count]
+            .iter()
+            .enumerate()
+            .filter_map(|(index, &(start, end))| {
+                let alloc_start = end.checked_sub(size)? & !(align - 1);
+                (alloc_start >= start).then_some((index, alloc_start))
+            })
+            .max_by_key(|&(_, alloc_start)| alloc_start)
+            .ok_or(AllocError)?;
+        let (range_start, range_end) = self.free[index];
+        let end = start + size;
+        self.free[index] = (range_start, start);
+        if end < range_end {
+            // Left over by alignment.
+            self.add_free(end, range_end);
+        }
+        self.record(start, end, purpose);
+        PageRange::new(start, end).map_err(|_| AllocError)
+    }
+
+    /// Returns the ranges that are still free.
+    pub fn free_ranges(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.free[..self.free_count]
+            .iter()
+            .copied()
+            .filter(|(start, end)| start < end)
+    }
+
+    /// Returns every range handed out so far.
+    #[must_use]
+    pub fn allocations(&self) -> &[EarlyAllocation] {
+        &self.allocations[..self.allocation_count]
+    }
+}
+
+/// Allocates physically contiguous frames for `purpose`, before the frame
+/// allocator exists.
+/// # Errors
+/// when no free range is large enough, or the frame allocator has already
+/// taken over.
+pub fn alloc_frames(layout: PageLayout, purpose: FramePurpose) -> Result<PageRange, AllocError> {
+    let mut early = EARLY.lock();
+    if early.handed_off {
+        log::warn!("early: allocation after hand-off, use FRAME_ALLOCATOR");
+        return Err(AllocError);
+    }
+    early.allocate(layout, purpose)
+}
+
+/// Marks the early allocator as retired and returns it, so the frame
+/// allocator can take over its free ranges and allocation records.
+/// # Panics
+/// when called twice.
+pub(crate) fn hand_off() -> MutexGuard<'static, EarlyAllocator, Spin> {
+    let mut early = EARLY.lock();
+    assert!(!early.handed_off, "early: handed off twice");
+    early.handed_off = true;
+    early
+}
+
+/// Allocates memory for the global allocator before the heap is
+/// initialized. The memory is never freed. After hand-off, pages come from
+/// `FRAME_ALLOCATOR` instead.
+pub fn alloc(layout: Layout) -> *mut u8 {
+    let mut early = EARLY.lock();
+    let next = early.heap_next.next_multiple_of(layout.align());
+    if early.heap_next != 0 && next + layout.size() <= early.heap_end {
+        early.heap_next = next + layout.size();
+        return next as *mut u8;
+    }
+    let Ok(page_layout) = PageLayout::from_size_align(
+        layout.size().next_multiple_of(PAGE_SIZE),
+        layout.align().max(PAGE_SIZE),
+    ) else {
+        return core::ptr::null_mut();
+    };
+    let range = if early.handed_off {
+        drop(early);
+        let range = FRAME_ALLOCATOR
+            .write()
+            .allocate(page_layout, FramePurpose::Heap);
+        early = EARLY.lock();
+        range
+    } else {
+        early.allocate(page_layout, FramePurpose::Heap)
+    };
+    let Ok(range) = range else {
+        return core::ptr::null_mut();
+    };
+    let start = range.start() + hhdm_offset();
+    // Keep bumping in whatever is left of the new pages.
+    early.heap_next = start + layout.size();
+    early.heap_end = start + range.len().get();
+    start as *mut u8
+}
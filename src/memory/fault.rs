@@ -0,0 +1,248 @@
+//! Architecture-independent page fault resolution.
+//!
+//! Trap code decodes a fault into a `PageFault` and calls `handle`, which
+//! finds the region the address falls in and resolves the fault there:
+//! - the kernel heap is demand-zero. The fault may have interrupted the
+//!   holder of the frame allocator or page table lock, so only try-locks
+//!   are used, with a bounded number of retries. When the allocators are
+//!   locked, the data frame and any page-table pages come from a
+//!   per-processor reserve that `refill_reserve` tops up outside the fault
+//!   path;
+//! - `vmm` ranges backed on demand are demand-zero;
+//! - user addresses go to the address space the processor runs on, which
+//!   resolves demand-zero, copy-on-write and stack growth faults.
+//!
+//! Anything else is a bug (or, once there is user mode, a bad access) and
+//! comes back as a `FaultError`.
+use crate::allocator::{heap_end, heap_start};
+use crate::arch;
+use crate::memory::address_space::{self, USER_START, user_end};
+use crate::memory::frame_cache::{self, MAX_CPUS};
+use crate::memory::stats::{self, FramePurpose};
+use crate::memory::{PAGE_MAPPER, PAGE_SIZE, hhdm_offset, mode, page_desc, vmm, zero};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use memory_addr::{PhysAddr, VirtAddr};
+use page_table_multiarch::{MappingFlags, PageSize, PagingError};
+
+/// The kind of access that faulted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// A page fault, as decoded by the trap code.
+#[derive(Debug, Clone, Copy)]
+pub struct PageFault {
+    /// The faulting virtual address.
+    pub addr: usize,
+    pub access: Access,
+    /// Whether the page was mapped, i.e. the access broke its permissions.
+    pub present: bool,
+    /// Whether the access came from user mode.
+    pub user: bool,
+}
+
+/// Why a page fault could not be resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    /// No region covers the address, or it is a guard page.
+    NoRegion,
+    /// The region does not allow the access.
+    Protection,
+    /// No frame was available to back the page.
+    OutOfMemory,
+    /// The page table stayed locked, possibly by the code the fault
+    /// interrupted.
+    Busy,
+}
+
+/// Attempts at a try-lock before a heap fault gives up on it.
+const LOCK_RETRIES: usize = 1 << 16;
+/// Frames a heap fault may need: the data frame and a page-table page for
+/// every level below the root, of which there are at most four.
+const RESERVE_SIZE: usize = 4;
+
+/// Each processor's reserve of zeroed frames for heap faults that find the
+/// allocators locked; 0 marks an empty slot. Reserved frames are accounted
+/// to `FramePurpose::Cache`.
+static RESERVES: [[AtomicUsize; RESERVE_SIZE]; MAX_CPUS] =
+    [const { [const { AtomicUsize::new(0) }; RESERVE_SIZE] }; MAX_CPUS];
+/// Set while the processor maps a page for a heap fault, so that the
+/// page-table pages it needs come from `table_frame`.
+static MAPPING: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+/// Frames heap faults allocated but could not use, linked through their
+/// first word, or 0. Freeing them may need the locks the fault found
+/// taken, so `refill_reserve` does it.
+static DEFERRED: AtomicUsize = AtomicUsize::new(0);
+
+/// Fills the boot processor's reserve.
+pub fn init() {
+    refill_reserve();
+}
+
+/// Frees the frames heap faults could not use and tops the current
+/// processor's reserve up to one frame per paging level. Runs outside the
+/// fault path, from `zero::refill`.
+pub fn refill_reserve() {
+    let mut paddr = DEFERRED.swap(0, Ordering::Acquire);
+    while paddr != 0 {
+        // Safety: listed frames belong to the list and hold the link.
+        let next = unsafe { ((paddr + hhdm_offset()) as *const usize).read() };
+        frame_cache::free_frame(PhysAddr::from(paddr), FramePurpose::Heap);
+        paddr = next;
+    }
+    let Some(reserve) = RESERVES.get(arch::cpu_index()) else {
+        return;
+    };
+    for slot in reserve.iter().take(mode::levels()) {
+        if slot.load(Ordering::Relaxed) != 0 {
+            continue;
+        }
+        let Ok(paddr) = zero::alloc_zeroed_frame(FramePurpose::Cache) else {
+            return;
+        };
+        slot.store(paddr.as_usize(), Ordering::Release);
+    }
+}
+
+/// Takes a frame for `purpose` from the current processor's reserve.
+fn take_reserved(purpose: FramePurpose) -> Option<PhysAddr> {
+    let paddr = RESERVES
+        .get(arch::cpu_index())?
+        .iter()
+        .map(|slot| slot.swap(0, Ordering::Acquire))
+        .find(|&paddr| paddr != 0)?;
+    let paddr = PhysAddr::from(paddr);
+    stats::account_free(FramePurpose::Cache, 1);
+    stats::account_alloc(purpose, 1);
+    page_desc::transfer(paddr, FramePurpose::Cache, purpose);
+    Some(paddr)
+}
+
+/// Hands a heap frame a fault could not use to `refill_reserve` to free.
+fn defer_free(paddr: PhysAddr) {
+    let link = (paddr.as_usize() + hhdm_offset()) as *mut usize;
+    let mut head = DEFERRED.load(Ordering::Relaxed);
+    loop {
+        // Safety: the frame is ours until it is on the list.
+        unsafe { link.write(head) };
+        match DEFERRED.compare_exchange_weak(
+            head,
+            paddr.as_usize(),
+            Ordering::Release,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => return,
+            Err(current) => head = current,
+        }
+    }
+}
+
+/// Returns `Some` with a frame for a page-table page, or `None` if out of
+/// frames, when the current processor maps a page for a heap fault: from
+/// its frame cache if the allocators are free, else from its reserve.
+/// Returns `None` outside heap faults, when page tables allocate as usual.
+pub(crate) fn table_frame() -> Option<Option<PhysAddr>> {
+    if !MAPPING
+        .get(arch::cpu_index())
+        .is_some_and(|mapping| mapping.load(Ordering::Relaxed))
+    {
+        return None;
+    }
+    Some(
+        match frame_cache::try_alloc_frame(FramePurpose::PageTable) {
+            Some(Ok(paddr)) => Some(paddr),
+            _ => take_reserved(FramePurpose::PageTable),
+        },
+    )
+}
+
+/// Calls `attempt` until it returns `Some`, at most `LOCK_RETRIES` times.
+fn with_retries<T>(mut attempt: impl FnMut() -> Option<T>) -> Result<T, FaultError> {
+    for _ in 0..LOCK_RETRIES {
+        if let Some(value) = attempt() {
+            return Ok(value);
+        }
+        core::hint::spin_loop();
+    }
+    Err(FaultError::Busy)
+}
+
+/// Resolves `fault`.
+/// # Errors
+/// when no region covers the address, the region does not allow the
+/// access, or there is not enough memory.
+pub fn handle(fault: &PageFault) -> Result<(), FaultError> {
+    if (USER_START..user_end()).contains(&fault.addr) {
+        return address_space::handle_fault(fault);
+    }
+    if fault.user {
+        return Err(FaultError::Protection);
+    }
+    if fault.present {
+        // Kernel mappings never change their permissions on faults.
+        return Err(FaultError::Protection);
+    }
+    if (heap_start()..=heap_end()).contains(&fault.addr) {
+        return heap_fault(fault.addr);
+    }
+    if vmm::handle_fault(fault.addr) {
+        return Ok(());
+    }
+    Err(FaultError::NoRegion)
+}
+
+/// Maps a zeroed frame at the heap page holding `addr`, so that the heap
+/// is only backed where it is used.
+fn heap_fault(addr: usize) -> Result<(), FaultError> {
+    let vaddr = VirtAddr::from(addr & !(PAGE_SIZE - 1));
+
+    // Another processor may have mapped the page since, or have been
+    // splitting the huge page holding it.
+    let mapped = with_retries(|| PAGE_MAPPER.try_read().map(|mapper| mapper.query(vaddr)))?;
+    if mapped.is_ok() {
+        return Ok(());
+    }
+
+    // The pre-zeroed pool, this processor's frame cache or the frame
+    // allocator, unless they are locked, maybe by the code the fault
+    // interrupted; the reserve then.
+    let paddr = match zero::try_alloc_zeroed_frame(FramePurpose::Heap) {
+        Some(Ok(paddr)) => paddr,
+        _ => take_reserved(FramePurpose::Heap).ok_or(FaultError::OutOfMemory)?,
+    };
+
+    // The lock is taken after the frame, and the page-table pages `map`
+    // needs come from `table_frame`, which does not block either.
+    let mapping = MAPPING.get(arch::cpu_index());
+    let mapped = with_retries(|| {
+        let mut mapper = PAGE_MAPPER.try_write()?;
+        mapping.inspect(|mapping| mapping.store(true, Ordering::Relaxed));
+        let mapped = mapper.cursor().map(
+            vaddr,
+            paddr,
+            PageSize::Size4K,
+            MappingFlags::READ | MappingFlags::WRITE,
+        );
+        mapping.inspect(|mapping| mapping.store(false, Ordering::Relaxed));
+        Some(mapped)
+    });
+    match mapped {
+        Ok(Ok(())) => Ok(()),
+        // Someone mapped the page in the meantime.
+        Ok(Err(PagingError::AlreadyMapped | PagingError::MappedToHugePage)) => {
+            defer_free(paddr);
+            Ok(())
+        }
+        Ok(Err(_)) => {
+            defer_free(paddr);
+            Err(FaultError::OutOfMemory)
+        }
+        Err(error) => {
+            defer_free(paddr);
+            Err(error)
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Per-CPU caches of single frames in front of `FRAME_ALLOCATOR`.
+//!
+//! Almost every frame the kernel allocates is a single page: page-table
+//! pages, heap pages and demand-paged faults. Each processor keeps a small
+//! stack of free frames that it refills from, and drains back to, the global
+//! allocator in batches, so the global lock is only taken once per `BATCH`
+//! frames instead of once per frame.
+//!
+//! Frames sitting in a cache are accounted to `FramePurpose::Cache`.
+use crate::arch;
+use crate::memory::allocator::{self, AllocError, FrameAllocator};
+use crate::memory::page_desc::{self, PageDescriptor};
+use crate::memory::stats::{self, FramePurpose};
+use crate::memory::zero;
+use crate::memory::{FRAME_ALLOCATOR, PAGE_SIZE};
+use free_list::{PageLayout, PageRange};
+use memory_addr::PhysAddr;
+use spin::Mutex;
+
+/// Maximum number of processors with a frame cache. Processors with a
+/// higher index go straight to the global allocator.
+pub const MAX_CPUS: usize = 64;
+/// Frames a cache can hold.
+const CACHE_SIZE: usize = 64;
+/// Frames moved between a cache and the global allocator at once.
+const BATCH: usize = CACHE_SIZE / 2;
+
+struct FrameCache {
+    frames: [usize; CACHE_SIZE],
+    count: usize,
+}
+
+impl FrameCache {
+    const fn new() -> Self {
+        Self {
+            frames: [0; CACHE_SIZE],
+            count: 0,
+        }
+    }
+
+    /// Moves up to `BATCH` frames from the global allocator into the cache.
+    fn refill(&mut self, frame_alloc: &mut FrameAllocator) {
+        let layout = single_frame();
+        while self.count < BATCH {
+            let Ok(range) = frame_alloc.allocate(layout, FramePurpose::Cache) else {
+                break;
+            };
+            self.frames[self.count] = range.start();
+            self.count += 1;
+        }
+    }
+
+    /// Moves `BATCH` frames from the cache back to the global allocator.
+    fn drain(&mut self, frame_alloc: &mut FrameAllocator) {
+        let keep = self.count.saturating_sub(BATCH);
+        for &paddr in &self.frames[keep..self.count] {
+            frame_alloc.deallocate(frame_range(paddr), FramePurpose::Cache);
+        }
+        self.count = keep;
+    }
+}
+
+// The lock is never contended by another processor; it only guards against
+// an interrupt handler on the same processor re-entering the cache, in which
+// case the handler falls back to the global allocator.
+static CACHES: [Mutex<FrameCache>; MAX_CPUS] = [const { Mutex::new(FrameCache::new()) }; MAX_CPUS];
+
+fn single_frame() -> PageLayout {
+    PageLayout::from_size_align(PAGE_SIZE, PAGE_SIZE).expect("frame cache: invalid frame layout")
+}
+
+fn frame_range(paddr: usize) -> PageRange {
+    PageRange::new(paddr, paddr + PAGE_SIZE).expect("frame cache: invalid frame address")
+}
+
+/// Allocates one frame for `purpose`, preferring the current processor's
+/// cache.
+/// # Errors
+/// when the cache is empty and the global allocator is out of memory.
+pub fn alloc_frame(purpose: FramePurpose) -> Result<PhysAddr, AllocError> {
+    alloc(purpose, true).expect("frame cache: blocking allocation did not block")
+}
+
+/// Like `alloc_frame`, but returns `None` instead of spinning when the
+/// global allocator is locked. Meant for fault handlers that may have
+/// interrupted the lock holder.
+pub fn try_alloc_frame(purpose: FramePurpose) -> Option<Result<PhysAddr, AllocError>> {
+    alloc(purpose, false)
+}
+
+fn alloc(purpose: FramePurpose, block: bool) -> Option<Result<PhysAddr, AllocError>> {
+    let global = || {
+        if block {
+            Some(FRAME_ALLOCATOR.write())
+        } else {
+            FRAME_ALLOCATOR.try_write()
+        }
+    };
+    let Some(mut cache) = CACHES.get(arch::cpu_index()).and_then(Mutex::try_lock) else {
+        let range = global()?.allocate(single_frame(), purpose);
+        return Some(range.map(|range| PhysAddr::from(range.start())));
+    };
+    if cache.count == 0 {
+        let mut frame_alloc = global()?;
+        cache.refill(&mut frame_alloc);
+        if cache.count == 0 {
+            return Some(Err(AllocError));
+        }
+    }
+    cache.count -= 1;
+    let paddr = cache.frames[cache.count];
+    stats::account_free(FramePurpose::Cache, 1);
+    stats::account_alloc(purpose, 1);
+    let paddr = PhysAddr::from(paddr);
+    page_desc::transfer(paddr, FramePurpose::Cache, purpose);
+    Some(Ok(paddr))
+}
+
+/// Frees one frame allocated for `purpose` into the current processor's
+/// cache.
+pub fn free_frame(paddr: PhysAddr, purpose: FramePurpose) {
+    if let Some(desc) = page_desc::lookup(paddr) {
+        if !desc.has_flags(PageDescriptor::ALLOCATED) {
+            log::warn!("frame cache: ignoring free of {paddr:?}, which is not allocated");
+            return;
+        }
+        // A frame sitting in a cache stays allocated, to the cache.
+        if desc.owner() != Some(purpose) {
+            log::warn!(
+                "frame cache: ignoring free of {paddr:?} as {}, which it is not owned by",
+                purpose.name()
+            );
+            return;
+        }
+    }
+    let Some(mut cache) = CACHES.get(arch::cpu_index()).and_then(Mutex::try_lock) else {
+        allocator::free_frames(frame_range(paddr.as_usize()), purpose);
+        return;
+    };
+    if cache.count == CACHE_SIZE {
+        cache.drain(&mut FRAME_ALLOCATOR.write());
+    }
+    if purpose != FramePurpose::Cache {
+        zero::scrub(paddr.as_usize(), PAGE_SIZE);
+    }
+    let count = cache.count;
+    cache.frames[count] = paddr.as_usize();
+    cache.count += 1;
+    stats::account_free(purpose, 1);
+    stats::account_alloc(FramePurpose::Cache, 1);
+    page_desc::transfer(paddr, purpose, FramePurpose::Cache);
+}
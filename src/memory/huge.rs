@@ -0,0 +1,276 @@
+//! Huge pages for kernel mappings.
+//!
+//! Large kernel allocations are mapped with 2 MiB pages, and 1 GiB pages
+//! where the range is large enough, whenever the virtual address is aligned
+//! to the page size and a naturally aligned block of frames is free. One
+//! huge page takes a single TLB entry and needs no last-level page tables.
+//!
+//! Freeing part of a huge page splits it into a table of smaller pages with
+//! the same frames and flags first, so the rest stays mapped. A table of
+//! 4 KiB pages that ended up mapping a contiguous, aligned 2 MiB block with
+//! the same flags can be merged back into one 2 MiB page.
+//!
+//! On aarch64, an entry that changes between a block and a table must be
+//! cleared and flushed from every TLB before the new one is written
+//! (break-before-make). Accesses in between fault and wait for the page
+//! table lock, which is held throughout.
+use crate::memory::allocator::{self, AllocError};
+use crate::memory::page_desc::{self, PageDescriptor};
+use crate::memory::paging::AmirOSPagingHandler;
+use crate::memory::stats::FramePurpose;
+use crate::memory::tlb::TlbBatch;
+use crate::memory::walk::{entry, leaf, level_size, table};
+use crate::memory::{
+    FRAME_ALLOCATOR, PAGE_MAPPER, PAGE_SIZE, PAGE_SIZE_1G, PAGE_SIZE_2M, PageTableEntry,
+    frame_cache, zero,
+};
+use free_list::PageLayout;
+use memory_addr::{PhysAddr, VirtAddr};
+use page_table_multiarch::{GenericPTE, MappingFlags, PageSize, PagingError, PagingHandler};
+
+/// Clears `entry` and flushes `vaddr`, or everything if `None`, from every
+/// TLB, before the entry is rewritten as a table or block of another size.
+#[cfg(target_arch = "aarch64")]
+fn break_entry(entry: &mut PageTableEntry, vaddr: Option<usize>) {
+    entry.clear();
+    crate::arch::flush_tlb_broadcast(None, vaddr);
+}
+
+/// Other architectures allow an entry to change size in place; the stale
+/// translation maps the same frames and is flushed afterwards.
+#[cfg(not(target_arch = "aarch64"))]
+const fn break_entry(_entry: &mut PageTableEntry, _vaddr: Option<usize>) {}
+
+/// Returns the largest page size that `vaddr` is aligned to and that fits
+/// in `vaddr..end`.
+const fn largest_fit(vaddr: usize, end: usize) -> PageSize {
+    let remaining = end - vaddr;
+    if vaddr.is_multiple_of(PAGE_SIZE_1G) && remaining >= PAGE_SIZE_1G {
+        PageSize::Size1G
+    } else if vaddr.is_multiple_of(PAGE_SIZE_2M) && remaining >= PAGE_SIZE_2M {
+        PageSize::Size2M
+    } else {
+        PageSize::Size4K
+    }
+}
+
+/// Returns the next smaller page size.
+const fn smaller(size: PageSize) -> PageSize {
+    match size {
+        PageSize::Size1G => PageSize::Size2M,
+        _ => PageSize::Size4K,
+    }
+}
+
+/// Returns the alignment that lets a range of `size` bytes start with the
+/// largest huge page it can hold, for placing it in the address space.
+#[must_use]
+pub const fn alignment_for(size: usize) -> usize {
+    if size >= PAGE_SIZE_1G {
+        PAGE_SIZE_1G
+    } else if size >= PAGE_SIZE_2M {
+        PAGE_SIZE_2M
+    } else {
+        PAGE_SIZE
+    }
+}
+
+/// Allocates a zeroed, naturally aligned block of frames for a page of
+/// `size`.
+fn alloc_block(size: PageSize, purpose: FramePurpose) -> Result<PhysAddr, AllocError> {
+    if size == PageSize::Size4K {
+        return zero::alloc_zeroed_frame(purpose);
+    }
+    let layout = PageLayout::from_size_align(size as usize, size as usize)
+        .expect("huge: invalid block layout");
+    let range = FRAME_ALLOCATOR.write().allocate(layout, purpose)?;
+    // Zeroed after the lock is dropped, as a 1 GiB memset takes a while.
+    // Safety: the block was just allocated.
+    unsafe { zero::zero_frames(range.start(), range.len().get()) };
+    Ok(PhysAddr::from(range.start()))
+}
+
+fn free_block(paddr: PhysAddr, size: PageSize, purpose: FramePurpose) {
+    if size == PageSize::Size4K {
+        frame_cache::free_frame(paddr, purpose);
+    } else if let Ok(range) = (paddr.as_usize()..paddr.as_usize() + size as usize).try_into() {
+        allocator::free_frames(range, purpose);
+    }
+}
+
+/// Maps zeroed frames over the unmapped pages of the page-aligned range
+/// `start..end` with `flags`, using the largest pages that alignment and
+/// size allow, and accounts them to `purpose`. Pages that are mapped
+/// already are left alone. Returns whether anything was mapped.
+/// # Errors
+/// when out of physical memory.
+pub fn map_zeroed(
+    start: usize,
+    end: usize,
+    flags: MappingFlags,
+    purpose: FramePurpose,
+) -> Result<bool, AllocError> {
+    let mut vaddr = start;
+    let mut mapped_any = false;
+    while vaddr < end {
+        if let Ok((_, _, mapped)) = PAGE_MAPPER.read().query(VirtAddr::from(vaddr)) {
+            vaddr = (vaddr & !(mapped as usize - 1)) + mapped as usize;
+            continue;
+        }
+        vaddr += map_page(vaddr, end, flags, purpose)?;
+        mapped_any = true;
+    }
+    Ok(mapped_any)
+}
+
+/// Maps a zeroed page at the unmapped address `vaddr`, as large as fits in
+/// `vaddr..end`, and returns its size. Falls back to smaller pages when no
+/// block of frames is free or part of a huge page's range is mapped.
+fn map_page(
+    vaddr: usize,
+    end: usize,
+    flags: MappingFlags,
+    purpose: FramePurpose,
+) -> Result<usize, AllocError> {
+    let mut size = largest_fit(vaddr, end);
+    loop {
+        let paddr = match alloc_block(size, purpose) {
+            Ok(paddr) => paddr,
+            Err(error) if size == PageSize::Size4K => return Err(error),
+            Err(_) => {
+                size = smaller(size);
+                continue;
+            }
+        };
+        // The block is allocated first: mapping it may need page-table
+        // pages, which `PAGE_MAPPER` takes from the frame allocator.
+        let mapped = PAGE_MAPPER
+            .write()
+            .cursor()
+            .map(VirtAddr::from(vaddr), paddr, size, flags);
+        match mapped {
+            Ok(()) => return Ok(size as usize),
+            Err(PagingError::AlreadyMapped) if size != PageSize::Size4K => {
+                free_block(paddr, size, purpose);
+                size = smaller(size);
+            }
+            // Someone else mapped it in the meantime.
+            Err(PagingError::AlreadyMapped | PagingError::MappedToHugePage) => {
+                free_block(paddr, size, purpose);
+                return Ok(PAGE_SIZE);
+            }
+            Err(_) => {
+                free_block(paddr, size, purpose);
+                return Err(AllocError);
+            }
+        }
+    }
+}
+
+/// Unmaps every page in the page-aligned range `start..end`, splitting huge
+/// pages that stick out of it. The unmapped ranges are recorded in `batch`,
+/// and so are their frames, to be freed as `purpose`, unless `purpose` is
+/// `None` because the caller owns them. A huge page that cannot be split for
+/// lack of memory stays mapped whole.
+pub fn unmap(start: usize, end: usize, batch: &mut TlbBatch, purpose: Option<FramePurpose>) {
+    let mut vaddr = start;
+    while vaddr < end {
+        let mut mapper = PAGE_MAPPER.write();
+        let Ok((_, _, size)) = mapper.query(VirtAddr::from(vaddr)) else {
+            vaddr += PAGE_SIZE;
+            continue;
+        };
+        let size = size as usize;
+        let base = vaddr & !(size - 1);
+        if base < start || base + size > end {
+            drop(mapper);
+            if split(base).is_err() {
+                log::warn!(
+                    "huge: out of memory splitting the page at {base:#x}, leaving it mapped"
+                );
+                vaddr = base + size;
+            }
+            continue;
+        }
+        let unmapped = mapper.cursor().unmap(VirtAddr::from(base));
+        drop(mapper);
+        if let Ok((paddr, _, _)) = unmapped {
+            // Invalidating any address in a huge page drops the whole
+            // translation, on every architecture.
+            batch.invalidate(base, PAGE_SIZE);
+            if let Some(purpose) = purpose {
+                batch.free_range_after(paddr, size, purpose);
+            }
+        }
+        vaddr = base + size;
+    }
+}
+
+/// Splits the huge page mapping `vaddr` into a table of pages one level
+/// smaller, with the same frames and flags. Does nothing if `vaddr` is
+/// unmapped or mapped by a 4 KiB page.
+/// # Errors
+/// when out of memory for the new table.
+pub fn split(vaddr: usize) -> Result<(), AllocError> {
+    let mut batch = TlbBatch::kernel();
+    let mapper = PAGE_MAPPER.write();
+    // Safety: the kernel page table is locked.
+    let Some((entry, level)) = (unsafe { leaf(mapper.root_paddr(), vaddr) }) else {
+        return Ok(());
+    };
+    if level == 0 {
+        return Ok(());
+    }
+    let table_paddr = AmirOSPagingHandler::alloc_frame().ok_or(AllocError)?;
+    let (paddr, flags) = (entry.paddr(), entry.flags());
+    let child_size = level_size(level - 1);
+    // Safety: the frame was just allocated as a page-table page.
+    let children = unsafe { table(table_paddr) };
+    for (i, child) in children.iter_mut().enumerate() {
+        *child = PageTableEntry::new_page(paddr + i * child_size, flags, level > 1);
+    }
+    let base = vaddr & !(level_size(level) - 1);
+    break_entry(entry, Some(base));
+    *entry = PageTableEntry::new_table(table_paddr);
+    drop(mapper);
+    batch.invalidate(base, PAGE_SIZE);
+    Ok(())
+}
+
+/// Merges the 4 KiB pages of the 2 MiB range holding `vaddr` into a single
+/// 2 MiB page, if all of them are mapped with the same flags to a
+/// contiguous, 2 MiB aligned block of frames. Frees their page-table page.
+/// Returns whether the range was merged.
+pub fn merge(vaddr: usize) -> bool {
+    let base = vaddr & !(PAGE_SIZE_2M - 1);
+    let mut batch = TlbBatch::kernel();
+    let mapper = PAGE_MAPPER.write();
+    // Safety: the kernel page table is locked.
+    let Some(entry) = (unsafe { entry(mapper.root_paddr(), base, 1) }) else {
+        return false;
+    };
+    if !entry.is_present() || entry.is_huge() {
+        return false;
+    }
+    let table_paddr = entry.paddr();
+    // Safety: the entry points to a last-level table.
+    let pages = unsafe { table(table_paddr) };
+    let (paddr, flags) = (pages[0].paddr(), pages[0].flags());
+    let mergeable = paddr.as_usize().is_multiple_of(PAGE_SIZE_2M)
+        && pages.iter().enumerate().all(|(i, page)| {
+            page.is_present() && page.paddr() == paddr + i * PAGE_SIZE && page.flags() == flags
+        });
+    if !mergeable {
+        return false;
+    }
+    break_entry(entry, None);
+    *entry = PageTableEntry::new_page(paddr, flags, true);
+    drop(mapper);
+    // Any of the 4 KiB translations may be cached, and so may the table.
+    batch.invalidate(base, PAGE_SIZE_2M);
+    if let Some(desc) = page_desc::lookup(table_paddr) {
+        desc.clear_flags(PageDescriptor::PAGE_TABLE);
+    }
+    batch.free_after(table_paddr, FramePurpose::PageTable);
+    true
+}
@@ -0,0 +1,315 @@
+//! Kernel address space layout randomization.
+//!
+//! The heap and the dynamic regions of the kernel address space (vmalloc,
+//! kernel stacks, MMIO and the uncached DMA window) are placed at random
+//! offsets at boot, so that a kernel bug cannot be aimed at a known
+//! address. The heap slides within the top of the lower half, above user
+//! space; each dynamic region slides within its own slot in the top half
+//! of the higher half, so they never overlap. Both areas are sized from
+//! the paging mode. Offsets are multiples of 2 MiB, which keeps
+//! huge pages usable. The kernel image itself is placed by Limine.
+//!
+//! Entropy is mixed from every source at hand:
+//! - the CPU's random number generator (RDRAND, RNDR), where there is one;
+//! - the `rng-seed` property firmware may put in the device tree;
+//! - jitter in the timer when timing bursts of memory accesses;
+//! - what Limine varies from boot to boot: the boot time, the kernel's
+//!   load addresses and the HHDM offset.
+//!
+//! The kernel command line has two switches: `nokaslr` keeps the fixed
+//! layout, and `kaslr.debug` logs the layout chosen.
+use crate::allocator::{heap_area_end, heap_area_start, heap_size};
+use crate::arch;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+use crate::memory::vmm::dma_uncached_size;
+use crate::memory::vmm::{mmio_size, stacks_size, vmalloc_size};
+use crate::memory::{PAGE_SIZE_2M, mode};
+use spin::Once;
+
+/// Start of the part of the higher half the dynamic regions are placed in,
+/// halfway up it: 0xffff_c000_0000_0000 with 4-level paging.
+fn dynamic_area_start() -> usize {
+    mode::higher_half_start() + mode::half_size() / 2
+}
+
+/// Returns the size of the slot each dynamic region slides within: 8 TiB
+/// with 4-level paging, 16 GiB with Sv39.
+#[must_use]
+pub fn slot_size() -> usize {
+    mode::half_size() / 16
+}
+/// Granularity of every offset.
+const GRANULE: usize = PAGE_SIZE_2M;
+/// Number of timed bursts of memory accesses mixed in.
+const JITTER_ROUNDS: usize = 256;
+
+/// Where the heap and the dynamic regions start.
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    pub heap_start: usize,
+    pub vmalloc_start: usize,
+    pub stacks_start: usize,
+    pub mmio_start: usize,
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    pub dma_uncached_start: usize,
+    /// Whether the layout was randomized, or `nokaslr` kept the fixed one.
+    pub randomized: bool,
+    /// The entropy sources that contributed, as `Source` bits.
+    sources: u8,
+}
+
+/// An entropy source, as a bit in `Layout::sources`.
+#[derive(Debug, Clone, Copy)]
+enum Source {
+    Cpu = 1 << 0,
+    DeviceTree = 1 << 1,
+    TimerJitter = 1 << 2,
+    BootLoader = 1 << 3,
+}
+
+impl Source {
+    const ALL: [Self; 4] = [
+        Self::Cpu,
+        Self::DeviceTree,
+        Self::TimerJitter,
+        Self::BootLoader,
+    ];
+
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Cpu => "cpu rng",
+            Self::DeviceTree => "dtb rng-seed",
+            Self::TimerJitter => "timer jitter",
+            Self::BootLoader => "boot loader",
+        }
+    }
+}
+
+static LAYOUT: Once<Layout> = Once::new();
+
+/// Returns the layout chosen by `init`.
+/// # Panics
+/// if `init` has not run yet.
+#[must_use]
+pub fn layout() -> &'static Layout {
+    LAYOUT.get().expect("kaslr: layout used before kaslr::init")
+}
+
+/// A pool of entropy that the random offsets are drawn from.
+struct Entropy {
+    state: u64,
+}
+
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// The splitmix64 finalizer: every input bit affects every output bit.
+const fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+impl Entropy {
+    const fn new() -> Self {
+        Self { state: 0 }
+    }
+
+    fn add(&mut self, value: u64) {
+        self.state = mix(self.state.wrapping_add(GOLDEN_GAMMA) ^ value);
+    }
+
+    fn add_bytes(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.add(u64::from_le_bytes(word));
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(GOLDEN_GAMMA);
+        mix(self.state)
+    }
+
+    /// Returns a random multiple of `GRANULE` no larger than `room`.
+    fn offset(&mut self, room: usize) -> usize {
+        let slots = room / GRANULE + 1;
+        (self.next() as usize % slots) * GRANULE
+    }
+}
+
+/// Returns `true` if `option` is a word of the kernel command line.
+pub(crate) fn has_option(option: &str) -> bool {
+    crate::EXECUTABLE_CMDLINE_REQUEST
+        .response()
+        .is_some_and(|response| {
+            response
+                .cmdline()
+                .split_whitespace()
+                .any(|word| word == option)
+        })
+}
+
+/// Reads a big-endian 32-bit word of a flattened device tree.
+fn be32(blob: &[u8], offset: usize) -> Option<usize> {
+    let bytes = blob.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?) as usize)
+}
+
+const FDT_MAGIC: usize = 0xd00d_feed;
+const FDT_BEGIN_NODE: usize = 1;
+const FDT_END_NODE: usize = 2;
+const FDT_PROP: usize = 3;
+const FDT_NOP: usize = 4;
+
+/// Returns the value of the first property named `property`, NUL
+/// included, in the device tree Limine passed, if there is one.
+pub(crate) fn dtb_property(property: &[u8]) -> Option<&'static [u8]> {
+    let dtb = crate::DEVICE_TREE_BLOB_REQUEST.response()?;
+    let base = dtb.dtb_ptr.cast::<u8>();
+    if base.is_null() {
+        return None;
+    }
+    // Safety: Limine hands us a valid, mapped flattened device tree, whose
+    // header starts with the magic and the big-endian total size.
+    let header = unsafe { core::slice::from_raw_parts(base, 8) };
+    if be32(header, 0)? != FDT_MAGIC {
+        return None;
+    }
+    // Safety: as above, the blob is that many bytes long.
+    let blob = unsafe { core::slice::from_raw_parts(base, be32(header, 4)?) };
+    let strings = be32(blob, 12)?;
+    let mut offset = be32(blob, 8)?;
+    loop {
+        let token = be32(blob, offset)?;
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = blob.get(offset..)?.iter().position(|&byte| byte == 0)?;
+                offset += (name + 1).next_multiple_of(4);
+            }
+            FDT_PROP => {
+                let len = be32(blob, offset)?;
+                let name = strings + be32(blob, offset + 4)?;
+                offset += 8;
+                if blob.get(name..)?.starts_with(property) {
+                    return blob.get(offset..offset + len);
+                }
+                offset += len.next_multiple_of(4);
+            }
+            FDT_END_NODE | FDT_NOP => {}
+            // FDT_END, or a malformed tree.
+            _ => return None,
+        }
+    }
+}
+
+/// Times short bursts of memory accesses and mixes in every duration:
+/// caches, TLBs, interrupts and, under emulation, the host make them vary.
+fn timer_jitter(entropy: &mut Entropy) {
+    let mut buffer = [0u64; 64];
+    for round in 0..JITTER_ROUNDS {
+        let start = arch::timestamp();
+        for i in 0..buffer.len() {
+            let j = (i * 7 + round) % buffer.len();
+            buffer[j] = buffer[j].rotate_left(5) ^ start ^ i as u64;
+        }
+        core::hint::black_box(&mut buffer);
+        let end = arch::timestamp();
+        entropy.add(end.wrapping_sub(start) ^ end.rotate_left(32));
+    }
+}
+
+/// Gathers entropy and returns it with the sources that contributed.
+fn gather() -> (Entropy, u8) {
+    let mut entropy = Entropy::new();
+    let mut sources = 0;
+    if let Some(value) = arch::random_u64() {
+        entropy.add(value);
+        sources |= Source::Cpu as u8;
+    }
+    if let Some(seed) = dtb_property(b"rng-seed\0") {
+        entropy.add_bytes(seed);
+        sources |= Source::DeviceTree as u8;
+    }
+    timer_jitter(&mut entropy);
+    sources |= Source::TimerJitter as u8;
+    if let Some(date) = crate::DATE_AT_BOOT_REQUEST.response() {
+        entropy.add(date.timestamp as u64);
+        sources |= Source::BootLoader as u8;
+    }
+    if let Some(address) = crate::EXECUTABLE_ADDRESS_REQUEST.response() {
+        entropy.add(address.physical_base);
+        entropy.add(address.virtual_base);
+    }
+    if let Some(hhdm) = crate::HHDM_REQUEST.response() {
+        entropy.add(hhdm.offset);
+    }
+    (entropy, sources)
+}
+
+/// Chooses the layout. Must run before anything is placed by it, i.e.
+/// before `vmm::init` and the heap.
+pub fn init() {
+    LAYOUT.call_once(|| {
+        let randomized = !has_option("nokaslr");
+        let (mut entropy, sources) = if randomized {
+            gather()
+        } else {
+            (Entropy::new(), 0)
+        };
+        let mut place = |start: usize, room: usize| {
+            if randomized {
+                start + entropy.offset(room)
+            } else {
+                start
+            }
+        };
+        let slot_size = slot_size();
+        let slot = |index: usize| dynamic_area_start() + index * slot_size;
+        Layout {
+            heap_start: place(
+                heap_area_start(),
+                heap_area_end() + 1 - heap_area_start() - heap_size(),
+            ),
+            vmalloc_start: place(slot(0), slot_size - vmalloc_size()),
+            stacks_start: place(slot(1), slot_size - stacks_size()),
+            mmio_start: place(slot(2), slot_size - mmio_size()),
+            #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+            dma_uncached_start: place(slot(3), slot_size - dma_uncached_size()),
+            randomized,
+            sources,
+        }
+    });
+}
+
+/// Warns if the layout was randomized from weak entropy only, and logs it
+/// if `kaslr.debug` is on the command line. Runs once the logger is up.
+pub fn report() {
+    let layout = layout();
+    let strong = Source::Cpu as u8 | Source::DeviceTree as u8;
+    if layout.randomized && layout.sources & strong == 0 {
+        log::warn!(
+            "kaslr: no hardware or firmware entropy, the layout is only as random as boot timing"
+        );
+    }
+    if !has_option("kaslr.debug") {
+        return;
+    }
+    if layout.randomized {
+        log::info!("kaslr: layout randomized, entropy from:");
+        for source in Source::ALL {
+            if layout.sources & source as u8 != 0 {
+                log::info!("  {}", source.name());
+            }
+        }
+    } else {
+        log::info!("kaslr: disabled by nokaslr, fixed layout");
+    }
+    log::info!("  heap         {:#018x}", layout.heap_start);
+    log::info!("  vmalloc      {:#018x}", layout.vmalloc_start);
+    log::info!("  stacks       {:#018x}", layout.stacks_start);
+    log::info!("  mmio         {:#018x}", layout.mmio_start);
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    log::info!("  dma-uncached {:#018x}", layout.dma_uncached_start);
+}
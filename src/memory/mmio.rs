@@ -0,0 +1,169 @@
+//! Mapping device registers into the kernel address space.
+//!
+//! `ioremap` maps a physical range into the `mmio` region of the kernel
+//! address space with device attributes (strongly ordered, uncached) and
+//! returns an `MmioMapping` that unmaps it again on drop.
+//! `ioremap_uncached` maps it as normal uncached memory instead, for memory
+//! that sits behind a device but may be accessed like RAM, e.g. a frame
+//! buffer.
+//!
+//! Device memory is never reached through the HHDM: the HHDM only covers
+//! the memory map and uses cacheable attributes.
+use crate::memory::allocator::AllocError;
+use crate::memory::stats::FramePurpose;
+use crate::memory::vmm::{self, Backing, RegionId};
+use crate::memory::{PAGE_MAPPER, PAGE_SIZE};
+use memory_addr::{PhysAddr, VirtAddr};
+use page_table_multiarch::{MappingFlags, PageSize};
+
+/// A physical range mapped into the `mmio` region. Unmapped on drop.
+pub struct MmioMapping {
+    /// Virtual address of the first mapped page.
+    base: usize,
+    /// Offset of the requested physical address into the first page.
+    offset: usize,
+    paddr: usize,
+    size: usize,
+}
+
+// Safety: the mapping is only reached through volatile accesses; callers
+// synchronize access to the device themselves.
+unsafe impl Send for MmioMapping {}
+unsafe impl Sync for MmioMapping {}
+
+/// Maps the device registers at `paddr..paddr + size` with device
+/// attributes.
+/// # Errors
+/// when the `mmio` region is full or the pages cannot be mapped.
+pub fn ioremap(paddr: PhysAddr, size: usize) -> Result<MmioMapping, AllocError> {
+    map(
+        paddr,
+        size,
+        MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+    )
+}
+
+/// Maps the memory at `paddr..paddr + size` as normal uncached memory.
+/// # Errors
+/// when the `mmio` region is full or the pages cannot be mapped.
+pub fn ioremap_uncached(paddr: PhysAddr, size: usize) -> Result<MmioMapping, AllocError> {
+    map(
+        paddr,
+        size,
+        MappingFlags::READ | MappingFlags::WRITE | MappingFlags::UNCACHED,
+    )
+}
+
+fn map(paddr: PhysAddr, size: usize, flags: MappingFlags) -> Result<MmioMapping, AllocError> {
+    let paddr = paddr.as_usize();
+    let offset = paddr & (PAGE_SIZE - 1);
+    let first_page = paddr - offset;
+    let len = (offset + size.max(1)).next_multiple_of(PAGE_SIZE);
+    let base = vmm::allocate(
+        RegionId::MMIO,
+        "mmio",
+        len,
+        Backing::None,
+        FramePurpose::Driver,
+    )?
+    .as_usize();
+    // From here on, dropping the mapping undoes whatever was mapped.
+    let mapping = MmioMapping {
+        base,
+        offset,
+        paddr,
+        size,
+    };
+    let mut mapper = PAGE_MAPPER.write();
+    let mut cursor = mapper.cursor();
+    for page in (0..len).step_by(PAGE_SIZE) {
+        cursor
+            .map(
+                VirtAddr::from(base + page),
+                PhysAddr::from(first_page + page),
+                PageSize::Size4K,
+                flags,
+            )
+            .map_err(|_| AllocError)?;
+    }
+    Ok(mapping)
+}
+
+impl MmioMapping {
+    /// Returns the virtual address of the requested physical address.
+    #[must_use]
+    pub fn vaddr(&self) -> VirtAddr {
+        VirtAddr::from(self.base + self.offset)
+    }
+
+    /// Returns the physical address the mapping was requested for.
+    #[must_use]
+    pub fn paddr(&self) -> PhysAddr {
+        PhysAddr::from(self.paddr)
+    }
+
+    /// Returns a pointer to the start of the mapped registers.
+    #[must_use]
+    pub fn as_ptr(&self) -> *mut u8 {
+        (self.base + self.offset) as *mut u8
+    }
+
+    /// Returns the size of the mapping in bytes, as requested.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    /// Returns `true` if the mapping was requested with a size of 0.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Reads the register at byte offset `offset`.
+    /// # Panics
+    /// when the register does not lie inside the mapping or is misaligned.
+    #[must_use]
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        let ptr = self.register::<T>(offset);
+        // Safety: `register` checked that the register is mapped and
+        // aligned.
+        unsafe { ptr.read_volatile() }
+    }
+
+    /// Writes `value` to the register at byte offset `offset`.
+    /// # Panics
+    /// when the register does not lie inside the mapping or is misaligned.
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        let ptr = self.register::<T>(offset);
+        // Safety: as in `read`.
+        unsafe { ptr.write_volatile(value) }
+    }
+
+    fn register<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset + size_of::<T>() <= self.size,
+            "mmio: register at {offset:#x} is outside a {:#x} byte mapping",
+            self.size
+        );
+        let ptr = self.as_ptr().wrapping_add(offset).cast::<T>();
+        assert!(ptr.is_aligned(), "mmio: misaligned register at {offset:#x}");
+        ptr
+    }
+
+    /// Keeps the registers mapped for the rest of the kernel's life and
+    /// returns a pointer to them.
+    #[must_use]
+    pub fn leak(self) -> *mut u8 {
+        let ptr = self.as_ptr();
+        core::mem::forget(self);
+        ptr
+    }
+}
+
+impl Drop for MmioMapping {
+    fn drop(&mut self) {
+        // Unmaps the pages; unbacked ranges own no frames.
+        vmm::free(VirtAddr::from(self.base));
+    }
+}
@@ -1,12 +1,34 @@
 // memory management
 use crate::arch;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use limine::memmap::{Entry, MEMMAP_BAD_MEMORY};
 use memory_addr::{PhysAddr, VirtAddr};
-use page_table_multiarch::{MappingFlags, PageSize};
-use spin::RwLock;
+use object::Endianness;
+use object::elf::{FileHeader64, PF_R, PF_W, PF_X, PT_LOAD};
+use object::read::elf::{FileHeader, ProgramHeader};
+use page_table_multiarch::{MappingFlags, PageSize, PagingMetaData};
+use spin::{Mutex, RwLock};
+pub mod address_space;
 pub mod allocator;
+pub mod asid;
+pub mod buddy;
+pub mod dma;
+pub mod early;
+pub mod fault;
+pub mod frame_cache;
+pub mod huge;
+pub mod kaslr;
+pub mod mmio;
+pub mod mode;
+pub mod numa;
+pub mod page_desc;
 pub mod paging;
+pub mod stats;
+pub mod tlb;
+pub mod vmm;
+pub mod walk;
+pub mod zero;
 
 pub type PageTable = crate::arch::PageTable;
 pub type PageTableEntry = arch::PageTableEntry;
@@ -31,12 +53,76 @@ lazy_static! {
 pub const PAGE_SIZE_1G: usize = 1024 * 1024 * 1024;
 pub const PAGE_SIZE_2M: usize = 2 * 1024 * 1024;
 pub const PAGE_SIZE: usize = 4096;
+/// Size of the kernel stacks handed out by `alloc_kernel_stack`, not
+/// counting the guard page below each.
+pub const KERNEL_STACK_SIZE: usize = 128 * 1024;
+/// Maximum number of `PT_LOAD` segments in the kernel image.
+const MAX_KERNEL_SEGMENTS: usize = 16;
+/// End of the physical memory identity-mapped until `remove_identity_map`.
+const IDENTITY_MAP_END: usize = 0x1_0000_0000;
+/// Maximum number of low mappings kept by `request_low_mapping`.
+const MAX_LOW_MAPPINGS: usize = 8;
+
+/// Identity mappings that survive `remove_identity_map`.
+struct LowMappings {
+    /// `(start, end, flags)` of every requested range, page-aligned.
+    ranges: [(usize, usize, MappingFlags); MAX_LOW_MAPPINGS],
+    count: usize,
+}
+
+static LOW_MAPPINGS: Mutex<LowMappings> = Mutex::new(LowMappings {
+    ranges: [(0, 0, MappingFlags::empty()); MAX_LOW_MAPPINGS],
+    count: 0,
+});
+static IDENTITY_MAP_REMOVED: AtomicBool = AtomicBool::new(false);
+
+/// Copy of `FrameAllocator::hhdm_offset`, set by `early_init`, for code that
+/// runs before the frame allocator exists or must not take its lock, such
+/// as fault handlers.
+static HHDM_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the offset of the higher-half direct map.
+#[must_use]
+pub fn hhdm_offset() -> usize {
+    HHDM_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Sets up the early boot allocator, so that frames and `Box` can be used
+/// before `init`. Must be the first thing the kernel does.
+/// # Panics
+/// if the bootloader did not provide the HHDM offset.
+pub fn early_init(memmap: &[&Entry]) {
+    let hhdm_offset = usize::try_from(
+        crate::HHDM_REQUEST
+            .response()
+            .expect("memory: failed to get HHDM response")
+            .offset,
+    )
+    .expect("memory: invalid HHDM offset");
+    HHDM_OFFSET.store(hhdm_offset, Ordering::Relaxed);
+    mode::init();
+    early::init(memmap);
+}
 
 /// initialization code for the memory manager and page mapping.
 /// # Panics
 /// if initialization fails or we cant map the kernel.
 pub fn init(memmap: &[&Entry]) {
-    // initialize our frame allocator.
+    log::info!(
+        "paging mode {}, {}-bit virtual addresses.",
+        mode::name(),
+        mode::va_bits()
+    );
+    // the heap and the kernel address space regions are placed by the
+    // randomized layout, so choose it before anything is placed.
+    kaslr::init();
+    // the frame allocator splits memory by NUMA node, so read the topology
+    // first.
+    numa::init();
+    // the page descriptors come next, so that the frame allocator can mark
+    // what the early allocator handed out.
+    page_desc::init(memmap);
+    // initialize our frame allocator, which takes over from the early one.
     FRAME_ALLOCATOR.write().init(memmap);
     // Get the necessary information from the bootloader.
     let hhdm_offset = FRAME_ALLOCATOR.read().hhdm_offset;
@@ -53,7 +139,10 @@ pub fn init(memmap: &[&Entry]) {
     // First, map all physical memory to the higher-half direct map (HHDM) region.
     // We also identity-map the first 4GiB. This is a robust technique to ensure
     // that the CPU can continue execution seamlessly after the CR3 switch, as it
-    // makes physical addresses temporarily valid as virtual addresses.
+    // makes physical addresses temporarily valid as virtual addresses. The
+    // identity map is removed again by `remove_identity_map` once every
+    // processor has switched.
+    let mut hhdm_end = hhdm_offset;
     for entry in memmap {
         // We map all memory types except for bad memory. This includes the kernel,
         // modules, and bootloader-reclaimable memory.
@@ -65,6 +154,7 @@ pub fn init(memmap: &[&Entry]) {
         let end_pa = start_pa
             .checked_add(length)
             .expect("memory: integer overflow in memmap range calculation");
+        hhdm_end = hhdm_end.max(end_pa + hhdm_offset);
         let mut pa = start_pa;
 
         while pa < end_pa {
@@ -81,7 +171,7 @@ pub fn init(memmap: &[&Entry]) {
                     .cursor()
                     .map(vaddr, paddr, PageSize::Size1G, flags)
                     .expect("Failed to map 1G HHDM page");
-                if pa < 0x1_0000_0000 {
+                if pa < IDENTITY_MAP_END {
                     let identity_vaddr = VirtAddr::from(pa);
                     mapper
                         .cursor()
@@ -98,7 +188,7 @@ pub fn init(memmap: &[&Entry]) {
                     .cursor()
                     .map(vaddr, paddr, PageSize::Size2M, flags)
                     .expect("Failed to map 2M HHDM page");
-                if pa < 0x1_0000_0000 {
+                if pa < IDENTITY_MAP_END {
                     let identity_vaddr = VirtAddr::from(pa);
                     mapper
                         .cursor()
@@ -112,7 +202,7 @@ pub fn init(memmap: &[&Entry]) {
                     .cursor()
                     .map(vaddr, paddr, PageSize::Size4K, flags)
                     .expect("Failed to map 4K HHDM page");
-                if pa < 0x1_0000_0000 {
+                if pa < IDENTITY_MAP_END {
                     let identity_vaddr = VirtAddr::from(pa);
                     mapper
                         .cursor()
@@ -126,24 +216,261 @@ pub fn init(memmap: &[&Entry]) {
     log::info!("HHDM and low-memory identity mapping complete.");
 
     // Second, map the kernel itself at its higher-half virtual address.
-    let kernel_physical_address = PhysAddr::from(
-        usize::try_from(kernel_address.physical_base)
-            .expect("memory: invalid kernel physical base address"),
+    let kernel_physical_address = usize::try_from(kernel_address.physical_base)
+        .expect("memory: invalid kernel physical base address");
+    let kernel_virtual_address = usize::try_from(kernel_address.virtual_base)
+        .expect("memory: invalid kernel virtual base address");
+    let kernel_image = map_kernel(
+        &mut mapper,
+        kernel_file.data(),
+        kernel_physical_address,
+        kernel_virtual_address,
+    );
+    log::info!("Kernel sections mapped.");
+    drop(mapper);
+
+    // Finally, lay out the rest of the kernel address space around them.
+    vmm::init((hhdm_offset, hhdm_end), kernel_image);
+    fault::init();
+}
+
+/// Maps every `PT_LOAD` segment of the kernel ELF image `elf` with its own
+/// permissions, sized by its size in memory so that `.bss` is covered. A
+/// page shared by two segments gets the permissions of both. Returns the
+/// `start..end` range spanned by the image.
+/// # Panics
+/// if the image is not a valid ELF file, a page cannot be mapped, or any
+/// kernel page ends up both writable and executable.
+fn map_kernel(
+    mapper: &mut PageTable,
+    elf: &[u8],
+    physical_base: usize,
+    virtual_base: usize,
+) -> (usize, usize) {
+    let header =
+        FileHeader64::<Endianness>::parse(elf).expect("memory: kernel is not a valid ELF64 file");
+    let endian = header
+        .endian()
+        .expect("memory: invalid byte order in kernel ELF");
+    let phdrs = header
+        .program_headers(endian, elf)
+        .expect("memory: invalid program headers in kernel ELF");
+
+    // Page-aligned `(start, end, flags)` of every loadable segment.
+    let mut segments = [(0, 0, MappingFlags::empty()); MAX_KERNEL_SEGMENTS];
+    let mut count = 0;
+    for phdr in phdrs
+        .iter()
+        .filter(|phdr| phdr.p_type(endian) == PT_LOAD && phdr.p_memsz(endian) > 0)
+    {
+        assert!(
+            count < MAX_KERNEL_SEGMENTS,
+            "memory: too many loadable segments in kernel ELF"
+        );
+        let vaddr =
+            usize::try_from(phdr.p_vaddr(endian)).expect("memory: invalid kernel segment address");
+        let memsz =
+            usize::try_from(phdr.p_memsz(endian)).expect("memory: invalid kernel segment size");
+        let p_flags = phdr.p_flags(endian);
+        let mut flags = MappingFlags::empty();
+        for (bit, flag) in [
+            (PF_R, MappingFlags::READ),
+            (PF_W, MappingFlags::WRITE),
+            (PF_X, MappingFlags::EXECUTE),
+        ] {
+            if p_flags & bit != 0 {
+                flags |= flag;
+            }
+        }
+        segments[count] = (
+            vaddr & !(PAGE_SIZE - 1),
+            (vaddr + memsz).next_multiple_of(PAGE_SIZE),
+            flags,
+        );
+        count += 1;
+    }
+    let segments = &segments[..count];
+
+    for (i, &(start, end, _)) in segments.iter().enumerate() {
+        for vaddr in (start..end).step_by(PAGE_SIZE) {
+            let covers =
+                |&(start, end, _): &(usize, usize, MappingFlags)| (start..end).contains(&vaddr);
+            // Pages shared with an earlier segment are already mapped.
+            if segments[..i].iter().any(covers) {
+                continue;
+            }
+            let flags = segments
+                .iter()
+                .filter(|segment| covers(segment))
+                .fold(MappingFlags::empty(), |acc, &(_, _, flags)| acc | flags);
+            let paddr = physical_base + (vaddr - virtual_base);
+            mapper
+                .cursor()
+                .map(
+                    VirtAddr::from(vaddr),
+                    PhysAddr::from(paddr),
+                    PageSize::Size4K,
+                    flags,
+                )
+                .expect("Failed to map kernel page");
+        }
+        log::info!(
+            "kernel segment {:#x}-{:#x} mapped {:?}.",
+            start,
+            end,
+            segments[i].2
+        );
+    }
+
+    // W^X: nothing in the kernel image may be both writable and executable.
+    for &(start, end, _) in segments {
+        for vaddr in (start..end).step_by(PAGE_SIZE) {
+            let (_, flags, _) = mapper
+                .query(VirtAddr::from(vaddr))
+                .expect("memory: kernel page missing after mapping");
+            assert!(
+                !flags.contains(MappingFlags::WRITE | MappingFlags::EXECUTE),
+                "memory: kernel page {vaddr:#x} is mapped writable and executable"
+            );
+        }
+    }
+    segments
+        .iter()
+        .fold((usize::MAX, 0), |(lo, hi), &(start, end, _)| {
+            (lo.min(start), hi.max(end))
+        })
+}
+
+/// Flushes the TLB of the calling processor.
+pub fn flush_tlb() {
+    arch::PagingMetaData::flush_tlb(None);
+}
+
+/// Keeps physical memory `paddr..paddr + size` identity-mapped with `flags`
+/// after `remove_identity_map`, e.g. for an AP trampoline. If the identity
+/// map is already gone, the range is mapped right away.
+/// # Panics
+/// when the range is not below 4 GiB, too many ranges are requested, or the
+/// range cannot be mapped.
+pub fn request_low_mapping(paddr: usize, size: usize, flags: MappingFlags) {
+    let start = paddr & !(PAGE_SIZE - 1);
+    let end = (paddr + size).next_multiple_of(PAGE_SIZE);
+    assert!(
+        end <= IDENTITY_MAP_END,
+        "memory: low mapping {start:#x}-{end:#x} is above 4 GiB"
     );
-    let kernel_virtual_address = VirtAddr::from(
-        usize::try_from(kernel_address.virtual_base)
-            .expect("memory: invalid kernel virtual base address"),
+    let mut low = LOW_MAPPINGS.lock();
+    assert!(
+        low.count < MAX_LOW_MAPPINGS,
+        "memory: too many low mappings requested"
     );
-    let kernel_size =
-        (kernel_file.data().len() + crate::memory::PAGE_SIZE - 1) & !(crate::memory::PAGE_SIZE - 1);
-    let kflags = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE;
-    for offset in (0..kernel_size).step_by(crate::memory::PAGE_SIZE) {
-        let paddr = kernel_physical_address + offset;
-        let vaddr = kernel_virtual_address + offset;
-        mapper
-            .cursor()
-            .map(vaddr, paddr, PageSize::Size4K, kflags)
-            .expect("Failed to map kernel page");
+    let count = low.count;
+    low.ranges[count] = (start, end, flags);
+    low.count += 1;
+    if IDENTITY_MAP_REMOVED.load(Ordering::Acquire) {
+        map_identity(start, end, flags);
     }
-    log::info!("Kernel sections mapped.");
+}
+
+/// Identity-maps `start..end` with 4 KiB pages of `flags`. Whatever maps
+/// the range now is unmapped first, splitting huge pages that stick out of
+/// it so the rest of them stays mapped.
+fn map_identity(start: usize, end: usize, flags: MappingFlags) {
+    let mut batch = tlb::TlbBatch::kernel();
+    // The frames are not the page table's to free.
+    huge::unmap(start, end, &mut batch, None);
+    batch.flush();
+    let mut mapper = PAGE_MAPPER.write();
+    let mut cursor = mapper.cursor();
+    for pa in (start..end).step_by(PAGE_SIZE) {
+        cursor
+            .map(
+                VirtAddr::from(pa),
+                PhysAddr::from(pa),
+                PageSize::Size4K,
+                flags,
+            )
+            .expect("memory: failed to map low page");
+    }
+}
+
+/// Removes the identity map of the low 4 GiB set up by `init`, except for
+/// the ranges passed to `request_low_mapping`, and flushes the TLB. Other
+/// processors must call `flush_tlb` once `identity_map_removed` returns
+/// `true`.
+///
+/// # Safety
+/// Every processor must run on the kernel page table and a kernel stack,
+/// and nothing may use an identity-mapped address any more.
+pub unsafe fn remove_identity_map(memmap: &[&Entry]) {
+    let mut mapper = PAGE_MAPPER.write();
+    let mut cursor = mapper.cursor();
+    let mut removed = 0;
+    // Walk the same ranges `init` mapped, a page of whatever size `init`
+    // chose at a time.
+    for entry in memmap
+        .iter()
+        .filter(|entry| entry.type_ != MEMMAP_BAD_MEMORY)
+    {
+        let start = usize::try_from(entry.base).expect("memory: invalid base in memmap entry");
+        let length = usize::try_from(entry.length).expect("memory: invalid length in memmap entry");
+        let end = (start + length).min(IDENTITY_MAP_END);
+        let mut pa = start;
+        while pa < end {
+            match cursor.unmap(VirtAddr::from(pa)) {
+                Ok((_, _, size)) => {
+                    let size: usize = size.into();
+                    removed += size;
+                    pa = (pa & !(size - 1)) + size;
+                }
+                Err(_) => pa = (pa & !(PAGE_SIZE - 1)) + PAGE_SIZE,
+            }
+        }
+    }
+    drop(cursor);
+    drop(mapper);
+    let low = LOW_MAPPINGS.lock();
+    for &(start, end, flags) in &low.ranges[..low.count] {
+        map_identity(start, end, flags);
+    }
+    flush_tlb();
+    IDENTITY_MAP_REMOVED.store(true, Ordering::Release);
+    log::info!(
+        "removed {} MiB of low identity mappings, kept {} requested ranges.",
+        removed / (1024 * 1024),
+        low.count
+    );
+}
+
+/// Returns `true` once `remove_identity_map` has run.
+#[must_use]
+pub fn identity_map_removed() -> bool {
+    IDENTITY_MAP_REMOVED.load(Ordering::Acquire)
+}
+
+/// Allocates a kernel stack in the stack region, with an unmapped guard
+/// page below it to catch overflows, and returns its top.
+/// # Panics
+/// when there is not enough memory left for the stack.
+#[must_use]
+pub fn alloc_kernel_stack() -> usize {
+    vmm::alloc_stack(KERNEL_STACK_SIZE).expect("memory: out of memory for a kernel stack")
+}
+
+/// Returns all bootloader-reclaimable memory to the frame allocator.
+///
+/// # Safety
+/// Must only be called once every processor runs on the kernel page table
+/// and a kernel stack, and no Limine request response is used any more.
+pub unsafe fn reclaim_bootloader_memory() {
+    let reclaimed = unsafe { FRAME_ALLOCATOR.write().reclaim_bootloader_memory() };
+    log::info!("reclaimed {} KiB of bootloader memory.", reclaimed / 1024);
+}
+
+/// Logs a summary of physical memory usage: totals, allocations by
+/// purpose, per-zone usage and the free memory left in every memory map
+/// region.
+pub fn log_summary() {
+    let frame_alloc = FRAME_ALLOCATOR.read();
+    stats::log_summary(&frame_alloc.stats(), frame_alloc.region_stats());
 }
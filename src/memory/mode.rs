@@ -0,0 +1,99 @@
+//! The paging mode, and the shape of the address space it gives.
+//!
+//! The kernel asks Limine for its preferred paging mode, accepting anything
+//! from the architecture's smallest mode up to four levels: Sv48, else
+//! Sv39, on riscv64 and 4-level paging elsewhere. `init` reads back the
+//! mode Limine enabled.
+//!
+//! 5-level paging and Sv57 are not supported yet: `page_table_multiarch`
+//! only has tables of three and four levels, so the most the kernel
+//! accepts stops at four. Everything below is written in terms of
+//! `levels()`, so lifting the cap only needs the deeper tables.
+//!
+//! The number of levels fixes how many virtual address bits are
+//! significant, and so the two canonical halves. Everything else is cut
+//! from them in the same proportions whatever the mode:
+//! - the lower half holds user space in its bottom half and the heap area
+//!   in its top half;
+//! - the higher half holds the HHDM in its bottom half and the dynamic
+//!   regions, then the kernel image, in its top half.
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of translation levels of the active paging mode. Limine's
+/// default mode, used when it does not answer the request, has four.
+static LEVELS: AtomicUsize = AtomicUsize::new(4);
+
+/// Returns the number of translation levels of the mode Limine chose.
+fn chosen_levels() -> usize {
+    match crate::PAGING_MODE_REQUEST
+        .response()
+        .map(|response| response.mode)
+    {
+        #[cfg(target_arch = "riscv64")]
+        Some(limine::paging::PagingMode::RISCV_SV39) => 3,
+        // Every other mode the kernel accepts has four levels.
+        _ => 4,
+    }
+}
+
+/// Reads back the paging mode Limine enabled. Must run before anything
+/// walks or creates a page table.
+pub fn init() {
+    LEVELS.store(chosen_levels(), Ordering::Relaxed);
+}
+
+/// Returns the number of translation levels.
+#[must_use]
+pub fn levels() -> usize {
+    LEVELS.load(Ordering::Relaxed)
+}
+
+/// Returns the number of significant virtual address bits; the ones above
+/// are copies of the top one.
+#[must_use]
+pub fn va_bits() -> u32 {
+    12 + 9 * levels() as u32
+}
+
+/// Returns the size of each canonical half of the address space.
+#[must_use]
+pub fn half_size() -> usize {
+    1 << (va_bits() - 1)
+}
+
+/// Returns the end of the lower half. Addresses from here up to
+/// `higher_half_start` are non-canonical and raise a general protection
+/// fault rather than a page fault on x86_64.
+#[must_use]
+pub fn lower_half_end() -> usize {
+    half_size()
+}
+
+/// Returns the start of the higher half.
+#[must_use]
+pub fn higher_half_start() -> usize {
+    half_size().wrapping_neg()
+}
+
+/// Returns the number of bytes one root table entry translates.
+#[must_use]
+pub fn root_entry_span() -> usize {
+    1 << (va_bits() - 9)
+}
+
+/// Sign-extends `vaddr` from its top significant bit.
+#[must_use]
+pub fn canonical(vaddr: usize) -> usize {
+    let shift = usize::BITS - va_bits();
+    (((vaddr << shift) as isize) >> shift) as usize
+}
+
+/// Returns a short name for the active mode.
+#[must_use]
+pub fn name() -> &'static str {
+    match (cfg!(target_arch = "riscv64"), levels()) {
+        (true, 3) => "Sv39",
+        (true, _) => "Sv48",
+        _ => "4-level",
+    }
+}
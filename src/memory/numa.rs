@@ -0,0 +1,295 @@
+//! NUMA topology from the ACPI SRAT and SLIT.
+//!
+//! Proximity domains are renumbered into dense node ids `0..node_count()`.
+//! Memory that no SRAT entry covers, and every processor the SRAT does not
+//! mention, belongs to node 0; without an SRAT the whole machine is one
+//! node. The frame allocator keeps one set of zone pools per node and
+//! serves each processor from its own node first, falling back to the
+//! other nodes in order of SLIT distance.
+//!
+//! Processors are matched by local APIC id on x86_64 and by MPIDR on
+//! aarch64 (through the MADT). Other architectures have no SRAT processor
+//! entries the `acpi` crate understands, so their processors use node 0.
+//!
+//! Under QEMU, e.g. `-smp 4 -m 2G -object memory-backend-ram,id=m0,size=1G
+//! -object memory-backend-ram,id=m1,size=1G -numa node,nodeid=0,cpus=0-1,memdev=m0
+//! -numa node,nodeid=1,cpus=2-3,memdev=m1 -numa dist,src=0,dst=1,val=20`.
+use crate::arch;
+use crate::memory::frame_cache::MAX_CPUS;
+use acpi::sdt::slit::Slit;
+use acpi::sdt::srat::{LocalApicAffinityFlags, MemoryAffinityFlags, Srat, SratEntry};
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Once;
+
+/// Maximum number of nodes; further proximity domains are folded into
+/// node 0.
+pub const MAX_NODES: usize = 8;
+/// Maximum number of SRAT memory ranges remembered.
+const MAX_MEMORY_RANGES: usize = 64;
+/// Maximum number of SRAT processor entries remembered.
+const MAX_PROCESSORS: usize = 256;
+/// SLIT distance of a node to itself, and the default to other nodes.
+const LOCAL_DISTANCE: u8 = 10;
+const REMOTE_DISTANCE: u8 = 20;
+
+pub struct NumaTopology {
+    node_count: usize,
+    /// Proximity domain of each node.
+    domains: [u32; MAX_NODES],
+    /// `(start, end, node)` for every enabled SRAT memory range.
+    memory: [(usize, usize, u8); MAX_MEMORY_RANGES],
+    memory_count: usize,
+    /// `(hardware id, node)` for every enabled SRAT processor.
+    processors: [(u64, u8); MAX_PROCESSORS],
+    processor_count: usize,
+    distance: [[u8; MAX_NODES]; MAX_NODES],
+}
+
+static TOPOLOGY: Once<NumaTopology> = Once::new();
+
+/// Node of every processor, indexed by `arch::cpu_index()`.
+static CPU_NODE: [AtomicU8; MAX_CPUS] = [const { AtomicU8::new(0) }; MAX_CPUS];
+
+impl NumaTopology {
+    const fn single_node() -> Self {
+        let mut distance = [[REMOTE_DISTANCE; MAX_NODES]; MAX_NODES];
+        let mut node = 0;
+        while node < MAX_NODES {
+            distance[node][node] = LOCAL_DISTANCE;
+            node += 1;
+        }
+        Self {
+            node_count: 1,
+            domains: [0; MAX_NODES],
+            memory: [(0, 0, 0); MAX_MEMORY_RANGES],
+            memory_count: 0,
+            processors: [(0, 0); MAX_PROCESSORS],
+            processor_count: 0,
+            distance,
+        }
+    }
+
+    /// Returns the node of proximity domain `domain`, creating it if there
+    /// is room.
+    fn node_of_domain(&mut self, domain: u32) -> u8 {
+        if let Some(node) = self.domains[..self.node_count]
+            .iter()
+            .position(|&known| known == domain)
+        {
+            return node as u8;
+        }
+        if self.memory_count == 0 && self.processor_count == 0 {
+            // The first domain seen becomes node 0.
+            self.domains[0] = domain;
+            return 0;
+        }
+        if self.node_count == MAX_NODES {
+            log::warn!("numa: too many proximity domains, folding domain {domain} into node 0");
+            return 0;
+        }
+        self.domains[self.node_count] = domain;
+        self.node_count += 1;
+        (self.node_count - 1) as u8
+    }
+
+    fn add_memory(&mut self, start: usize, end: usize, domain: u32) {
+        let node = self.node_of_domain(domain);
+        if self.memory_count == MAX_MEMORY_RANGES {
+            log::warn!("numa: too many SRAT memory ranges, some are ignored");
+            return;
+        }
+        self.memory[self.memory_count] = (start, end, node);
+        self.memory_count += 1;
+    }
+
+    fn add_processor(&mut self, hw_id: u64, domain: u32) {
+        let node = self.node_of_domain(domain);
+        if self.processor_count == MAX_PROCESSORS {
+            log::warn!("numa: too many SRAT processor entries, some are ignored");
+            return;
+        }
+        self.processors[self.processor_count] = (hw_id, node);
+        self.processor_count += 1;
+    }
+
+    fn parse(&mut self) {
+        let Some(tables) = crate::acpi_handler::tables() else {
+            return;
+        };
+        let Some(srat) = tables.find_table::<Srat>() else {
+            return;
+        };
+        #[cfg(target_arch = "aarch64")]
+        let madt = tables.find_table::<acpi::sdt::madt::Madt>();
+        for entry in srat.get().entries() {
+            match entry {
+                SratEntry::MemoryAffinity(entry) => {
+                    let flags = entry.flags;
+                    if flags.contains(MemoryAffinityFlags::ENABLED) {
+                        let start = entry.base_address() as usize;
+                        let end = start + entry.length() as usize;
+                        self.add_memory(start, end, entry.proximity_domain);
+                    }
+                }
+                SratEntry::LocalApicAffinity(entry) => {
+                    let flags = entry.flags;
+                    if flags.contains(LocalApicAffinityFlags::ENABLED) {
+                        self.add_processor(u64::from(entry.apic_id), entry.proximity_domain());
+                    }
+                }
+                SratEntry::LocalApicX2Affinity(entry) => {
+                    let flags = entry.flags;
+                    if flags.contains(LocalApicAffinityFlags::ENABLED) {
+                        self.add_processor(u64::from(entry.x2apic_id), entry.proximity_domain);
+                    }
+                }
+                #[cfg(target_arch = "aarch64")]
+                SratEntry::GiccAffinity(entry) => {
+                    // The SRAT names the processor by ACPI uid; the MADT
+                    // maps that to the MPIDR we can compare against.
+                    let uid = entry.acpi_processor_uid;
+                    let mpidr = madt.as_ref().and_then(|madt| {
+                        madt.get()
+                            .entries()
+                            .find_map(|madt_entry| match madt_entry {
+                                acpi::sdt::madt::MadtEntry::Gicc(gicc)
+                                    if { gicc.processor_uid } == uid =>
+                                {
+                                    Some(gicc.mpidr)
+                                }
+                                _ => None,
+                            })
+                    });
+                    if let Some(mpidr) = mpidr {
+                        self.add_processor(mpidr, entry.proximity_domain);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(slit) = tables.find_table::<Slit>() {
+            let domains = usize::try_from(slit.get().num_proximity_domains).unwrap_or(0);
+            let matrix = slit.get().matrix_raw();
+            for from in 0..self.node_count {
+                for to in 0..self.node_count {
+                    let (i, j) = (self.domains[from] as usize, self.domains[to] as usize);
+                    if let Some(&distance) = matrix.get(i * domains + j)
+                        && i < domains
+                        && j < domains
+                    {
+                        self.distance[from][to] = distance;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the node holding the physical address `paddr`, and the end
+    /// of the stretch of memory from `paddr` on that belongs to the same
+    /// node.
+    fn node_span(&self, paddr: usize) -> (usize, usize) {
+        let ranges = &self.memory[..self.memory_count];
+        if let Some(&(_, end, node)) = ranges
+            .iter()
+            .find(|&&(start, end, _)| (start..end).contains(&paddr))
+        {
+            return (usize::from(node), end);
+        }
+        // Not covered: node 0, up to the next covered range.
+        let end = ranges
+            .iter()
+            .map(|&(start, _, _)| start)
+            .filter(|&start| start > paddr)
+            .min()
+            .unwrap_or(usize::MAX);
+        (0, end)
+    }
+}
+
+/// Reads the SRAT and SLIT. Must run before the frame allocator is
+/// initialized and before bootloader memory is reclaimed.
+pub fn init() {
+    let topology = TOPOLOGY.call_once(|| {
+        let mut topology = NumaTopology::single_node();
+        topology.parse();
+        topology
+    });
+    if topology.node_count > 1 {
+        for node in 0..topology.node_count {
+            log::info!(
+                "numa: node {} (proximity domain {}), distances {:?}.",
+                node,
+                topology.domains[node],
+                &topology.distance[node][..topology.node_count]
+            );
+        }
+    }
+}
+
+fn topology() -> &'static NumaTopology {
+    static SINGLE_NODE: NumaTopology = NumaTopology::single_node();
+    TOPOLOGY.get().unwrap_or(&SINGLE_NODE)
+}
+
+/// Returns the number of nodes.
+#[must_use]
+pub fn node_count() -> usize {
+    topology().node_count
+}
+
+/// Returns the SLIT distance between two nodes.
+#[must_use]
+pub fn distance(from: usize, to: usize) -> u8 {
+    topology().distance[from][to]
+}
+
+/// Splits `start..end` where the owning node changes, yielding
+/// `(node, start, end)` pieces.
+pub fn node_pieces(start: usize, end: usize) -> impl Iterator<Item = (usize, usize, usize)> {
+    let mut piece_start = start;
+    core::iter::from_fn(move || {
+        if piece_start >= end {
+            return None;
+        }
+        let (node, node_end) = topology().node_span(piece_start);
+        let piece = (node, piece_start, node_end.min(end));
+        piece_start = piece.2;
+        Some(piece)
+    })
+}
+
+/// Returns all nodes ordered by distance from `node`, nearest (`node`
+/// itself) first. Only the first `node_count()` entries are meaningful.
+#[must_use]
+pub fn fallback_order(node: usize) -> [usize; MAX_NODES] {
+    let count = node_count();
+    let mut order = [0; MAX_NODES];
+    for (slot, other) in order.iter_mut().zip(0..count) {
+        *slot = other;
+    }
+    order[..count].sort_unstable_by_key(|&other| (other != node, distance(node, other), other));
+    order
+}
+
+/// Records the hardware id (local APIC id on x86_64, MPIDR on aarch64,
+/// hart id on riscv64) of the processor with index `cpu_index`, so that its
+/// allocations come from its own node.
+pub fn register_cpu(cpu_index: usize, hw_id: u64) {
+    let topology = topology();
+    let node = topology.processors[..topology.processor_count]
+        .iter()
+        .find(|&&(id, _)| id == hw_id)
+        .map_or(0, |&(_, node)| node);
+    if let Some(slot) = CPU_NODE.get(cpu_index) {
+        slot.store(node, Ordering::Relaxed);
+    }
+}
+
+/// Returns the node of the processor we are running on.
+#[must_use]
+pub fn current_node() -> usize {
+    CPU_NODE
+        .get(arch::cpu_index())
+        .map_or(0, |node| usize::from(node.load(Ordering::Relaxed)))
+}
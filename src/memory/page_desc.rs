@@ -0,0 +1,272 @@
+//! Per-frame metadata ("page descriptors"), indexed by physical frame
+//! number.
+//!
+//! One `PageDescriptor` exists for every frame between the lowest and the
+//! highest RAM address in the memory map. The array is allocated from the
+//! early allocator before the frame allocator starts and lives in the HHDM
+//! for the life of the kernel. All fields are atomics, so descriptors can be
+//! read and updated without holding `FRAME_ALLOCATOR`.
+use crate::memory::stats::FramePurpose;
+use crate::memory::{PAGE_SIZE, early, hhdm_offset};
+use core::sync::atomic::{AtomicU8, AtomicU16, AtomicU32, Ordering};
+use free_list::{PageLayout, PageRange};
+use limine::memmap::{
+    Entry, MEMMAP_ACPI_NVS, MEMMAP_ACPI_RECLAIMABLE, MEMMAP_BOOTLOADER_RECLAIMABLE,
+    MEMMAP_EXECUTABLE_AND_MODULES, MEMMAP_USABLE,
+};
+use memory_addr::PhysAddr;
+use spin::Once;
+
+/// Metadata of a single physical frame.
+#[repr(C)]
+pub struct PageDescriptor {
+    refcount: AtomicU32,
+    flags: AtomicU16,
+    /// `FramePurpose as u8` of the current owner; meaningful only while the
+    /// frame is `ALLOCATED`.
+    owner: AtomicU8,
+}
+
+impl PageDescriptor {
+    /// The frame is handed out by the frame allocator.
+    pub const ALLOCATED: u16 = 1 << 0;
+    /// The frame is not managed by the frame allocator (kernel image, ACPI
+    /// tables, bootloader memory not reclaimed yet).
+    pub const RESERVED: u16 = 1 << 1;
+    /// The frame holds a page table.
+    pub const PAGE_TABLE: u16 = 1 << 2;
+
+    const fn new() -> Self {
+        Self {
+            refcount: AtomicU32::new(0),
+            flags: AtomicU16::new(0),
+            owner: AtomicU8::new(0),
+        }
+    }
+
+    /// Returns the number of references to the frame.
+    #[must_use]
+    pub fn refcount(&self) -> u32 {
+        self.refcount.load(Ordering::Acquire)
+    }
+
+    /// Takes an additional reference to the frame, e.g. for a shared
+    /// mapping, and returns the new count.
+    pub fn get(&self) -> u32 {
+        self.refcount.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    /// Drops a reference to the frame and returns the remaining count. The
+    /// caller that sees zero is responsible for freeing the frame.
+    /// # Panics
+    /// when the frame has no references.
+    pub fn put(&self) -> u32 {
+        let previous = self.refcount.fetch_sub(1, Ordering::AcqRel);
+        assert!(previous > 0, "page_desc: reference count underflow");
+        previous - 1
+    }
+
+    /// Drops a shared reference to the frame unless it is the last one.
+    /// Returns `true` if it was the last: the caller owns the frame alone
+    /// and is responsible for freeing it.
+    pub fn release(&self) -> bool {
+        let mut count = self.refcount.load(Ordering::Acquire);
+        loop {
+            if count <= 1 {
+                return true;
+            }
+            match self.refcount.compare_exchange_weak(
+                count,
+                count - 1,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return false,
+                Err(current) => count = current,
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn flags(&self) -> u16 {
+        self.flags.load(Ordering::Acquire)
+    }
+
+    #[must_use]
+    pub fn has_flags(&self, flags: u16) -> bool {
+        self.flags() & flags == flags
+    }
+
+    pub fn set_flags(&self, flags: u16) {
+        self.flags.fetch_or(flags, Ordering::AcqRel);
+    }
+
+    pub fn clear_flags(&self, flags: u16) {
+        self.flags.fetch_and(!flags, Ordering::AcqRel);
+    }
+
+    /// Returns what the frame is allocated for, or `None` if it is free.
+    #[must_use]
+    pub fn owner(&self) -> Option<FramePurpose> {
+        if !self.has_flags(Self::ALLOCATED) {
+            return None;
+        }
+        FramePurpose::ALL
+            .get(usize::from(self.owner.load(Ordering::Acquire)))
+            .copied()
+    }
+
+    pub fn set_owner(&self, purpose: FramePurpose) {
+        self.owner.store(purpose as u8, Ordering::Release);
+    }
+}
+
+struct Descriptors {
+    first_pfn: usize,
+    table: &'static [PageDescriptor],
+}
+
+static DESCRIPTORS: Once<Descriptors> = Once::new();
+
+/// Returns `true` for memory map entries that describe RAM.
+const fn is_ram(type_: u64) -> bool {
+    matches!(
+        type_,
+        MEMMAP_USABLE
+            | MEMMAP_BOOTLOADER_RECLAIMABLE
+            | MEMMAP_EXECUTABLE_AND_MODULES
+            | MEMMAP_ACPI_RECLAIMABLE
+            | MEMMAP_ACPI_NVS
+    )
+}
+
+/// Allocates the descriptor array for every RAM frame in `memmap` and marks
+/// the frames the frame allocator does not manage as `RESERVED`. Must run
+/// right before `FrameAllocator::init`, which marks everything the early
+/// allocator handed out, this array included, as allocated.
+/// # Panics
+/// when the memory map has no RAM or the array cannot be allocated.
+pub fn init(memmap: &[&Entry]) {
+    let ram = || {
+        memmap
+            .iter()
+            .filter(|entry| is_ram(entry.type_))
+            .map(|entry| {
+                let base =
+                    usize::try_from(entry.base).expect("page_desc: invalid base in memory region");
+                let length = usize::try_from(entry.length)
+                    .expect("page_desc: invalid length in memory region");
+                (
+                    base / PAGE_SIZE,
+                    (base + length).div_ceil(PAGE_SIZE),
+                    entry.type_,
+                )
+            })
+    };
+    let first_pfn = ram()
+        .map(|(start, _, _)| start)
+        .min()
+        .expect("page_desc: no RAM in the memory map");
+    let end_pfn = ram().map(|(_, end, _)| end).max().unwrap_or(first_pfn);
+    let count = end_pfn - first_pfn;
+
+    let size = (count * size_of::<PageDescriptor>()).next_multiple_of(PAGE_SIZE);
+    let layout = PageLayout::from_size_align(size, PAGE_SIZE)
+        .expect("page_desc: invalid descriptor array layout");
+    let range = early::alloc_frames(layout, FramePurpose::Other)
+        .expect("page_desc: out of physical memory for the descriptor array");
+    let array = (range.start() + hhdm_offset()) as *mut PageDescriptor;
+    // Safety: the range was just allocated for this array and is reachable
+    // through the HHDM; it is never freed.
+    let table = unsafe {
+        for i in 0..count {
+            array.add(i).write(PageDescriptor::new());
+        }
+        core::slice::from_raw_parts(array, count)
+    };
+    DESCRIPTORS.call_once(|| Descriptors { first_pfn, table });
+
+    for (start, end, type_) in ram() {
+        if type_ != MEMMAP_USABLE {
+            for pfn in start..end {
+                table[pfn - first_pfn].set_flags(PageDescriptor::RESERVED);
+            }
+        }
+    }
+    log::info!(
+        "page descriptors initialized: {} frames, {} KiB.",
+        count,
+        size / 1024
+    );
+}
+
+/// Returns the descriptor of the frame containing `paddr`, or `None` if the
+/// address is not RAM or the array is not set up yet.
+#[must_use]
+pub fn lookup(paddr: PhysAddr) -> Option<&'static PageDescriptor> {
+    let descriptors = DESCRIPTORS.get()?;
+    let pfn = paddr.as_usize() / PAGE_SIZE;
+    descriptors
+        .table
+        .get(pfn.checked_sub(descriptors.first_pfn)?)
+}
+
+/// Returns the descriptors of every frame in `range` that has one.
+fn descriptors_of(range: PageRange) -> impl Iterator<Item = &'static PageDescriptor> {
+    (range.start()..range.end())
+        .step_by(PAGE_SIZE)
+        .filter_map(|paddr| lookup(PhysAddr::from(paddr)))
+}
+
+/// Records that the frame allocator handed out `range` for `purpose`.
+pub(crate) fn mark_allocated(range: PageRange, purpose: FramePurpose) {
+    for desc in descriptors_of(range) {
+        desc.refcount.store(1, Ordering::Release);
+        desc.set_owner(purpose);
+        desc.set_flags(PageDescriptor::ALLOCATED);
+    }
+}
+
+/// Records that `range`, allocated for `purpose`, is being freed. Returns
+/// `false`, leaving the descriptors alone, if any frame in the range is not
+/// allocated, which means a double free.
+pub(crate) fn mark_free(range: PageRange, purpose: FramePurpose) -> bool {
+    if descriptors_of(range).any(|desc| !desc.has_flags(PageDescriptor::ALLOCATED)) {
+        log::warn!("page_desc: ignoring free of {range}, which is not allocated");
+        return false;
+    }
+    if descriptors_of(range).any(|desc| desc.owner() != Some(purpose)) {
+        log::warn!(
+            "page_desc: {range} freed as {} but owned by someone else",
+            purpose.name()
+        );
+    }
+    for desc in descriptors_of(range) {
+        if desc.refcount.swap(0, Ordering::AcqRel) > 1 {
+            log::warn!("page_desc: freeing a frame in {range} that is still shared");
+        }
+        desc.clear_flags(PageDescriptor::ALLOCATED | PageDescriptor::PAGE_TABLE);
+    }
+    true
+}
+
+/// Hands an allocated frame from one owner to another.
+pub(crate) fn transfer(paddr: PhysAddr, from: FramePurpose, to: FramePurpose) {
+    if let Some(desc) = lookup(paddr) {
+        if desc.owner() != Some(from) {
+            log::warn!(
+                "page_desc: frame {:#x} expected to be owned by {}",
+                paddr.as_usize(),
+                from.name()
+            );
+        }
+        desc.set_owner(to);
+    }
+}
+
+/// Clears `RESERVED` on `range` once the frame allocator takes it over.
+pub(crate) fn mark_managed(range: PageRange) {
+    for desc in descriptors_of(range) {
+        desc.clear_flags(PageDescriptor::RESERVED);
+    }
+}
@@ -1,5 +1,8 @@
 //! Unified, multi-architecture paging using a single handler.
-use crate::memory::FRAME_ALLOCATOR;
+use crate::memory::page_desc::{self, PageDescriptor};
+use crate::memory::stats::FramePurpose;
+use crate::memory::{FRAME_ALLOCATOR, hhdm_offset};
+use crate::memory::{allocator, fault, frame_cache};
 use core::alloc::Layout;
 use free_list::PageLayout;
 use memory_addr::{PhysAddr, VirtAddr};
@@ -8,41 +11,68 @@ use page_table_multiarch::PagingHandler;
 #[derive(Clone)]
 pub struct AmirOSPagingHandler;
 
+/// Calls `f` with the descriptor of every frame in the `num_pages` frames
+/// starting at `paddr`.
+fn for_each_descriptor(paddr: PhysAddr, num_pages: usize, f: impl Fn(&PageDescriptor)) {
+    for page in 0..num_pages {
+        if let Some(desc) = page_desc::lookup(paddr + page * 0x1000) {
+            f(desc);
+        }
+    }
+}
+
 impl PagingHandler for AmirOSPagingHandler {
     fn alloc_frames(num_pages: usize, align: usize) -> Option<PhysAddr> {
-        let size = num_pages
-            .checked_mul(0x1000)
-            .expect("paging: integer overflow in alloc_frames size");
-        let layout: PageLayout = PageLayout::from_size_align(size, align)
-            .expect("paging: invalid page layout for alloc_frames");
-        let mut allocator = FRAME_ALLOCATOR.write();
-        if let Ok(page_range) = allocator.allocate(layout) {
-            let paddr = page_range.start();
-            Some(PhysAddr::from(paddr))
+        let paddr = if num_pages == 1 && align <= 0x1000 {
+            // Heap faults must not wait for the frame allocator.
+            match fault::table_frame() {
+                Some(frame) => frame?,
+                None => frame_cache::alloc_frame(FramePurpose::PageTable).ok()?,
+            }
         } else {
-            None
-        }
+            let size = num_pages
+                .checked_mul(0x1000)
+                .expect("paging: integer overflow in alloc_frames size");
+            let layout: PageLayout = PageLayout::from_size_align(size, align)
+                .expect("paging: invalid page layout for alloc_frames");
+            let mut allocator = FRAME_ALLOCATOR.write();
+            let page_range = allocator.allocate(layout, FramePurpose::PageTable).ok()?;
+            PhysAddr::from(page_range.start())
+        };
+        for_each_descriptor(paddr, num_pages, |desc| {
+            desc.set_flags(PageDescriptor::PAGE_TABLE);
+        });
+        Some(paddr)
     }
 
     fn dealloc_frames(paddr: PhysAddr, num_pages: usize) {
+        for_each_descriptor(paddr, num_pages, |desc| {
+            if !desc.has_flags(PageDescriptor::PAGE_TABLE) {
+                log::warn!("paging: freeing {paddr:?} as a page table, but it is not one");
+            }
+            desc.clear_flags(PageDescriptor::PAGE_TABLE);
+        });
+        if num_pages == 1 {
+            frame_cache::free_frame(paddr, FramePurpose::PageTable);
+            return;
+        }
         let size = num_pages
             .checked_mul(0x1000)
             .expect("paging: integer overflow in dealloc_frames size");
         let layout = Layout::from_size_align(size, 0x1000)
             .expect("paging: invalid layout for dealloc_frames");
-        let mut allocator = FRAME_ALLOCATOR.write();
         let paddr_start = paddr.as_usize();
         let paddr_end = paddr_start
             .checked_add(layout.size())
             .expect("paging: integer overflow in dealloc_frames range");
         if let Ok(page_range) = (paddr_start..paddr_end).try_into() {
-            allocator.deallocate(page_range);
+            allocator::free_frames(page_range, FramePurpose::PageTable);
         }
     }
 
     fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
         let pa = paddr.as_usize();
-        pa.checked_add(FRAME_ALLOCATOR.read().hhdm_offset)
+        pa.checked_add(hhdm_offset())
             .map(VirtAddr::from_usize)
             .expect("failed to allocate address")
     }
@@ -0,0 +1,196 @@
+//! Physical memory accounting.
+//!
+//! The buddy allocators know how many pages are free, but not what the
+//! allocated ones are used for. Every allocation therefore names a
+//! `FramePurpose`, and the per-purpose page counts are kept here as atomics
+//! so they can be read without taking the `FRAME_ALLOCATOR` lock.
+use crate::memory::PAGE_SIZE;
+use crate::memory::allocator::{NodeUsage, Zone, ZoneUsage};
+use crate::memory::numa::MAX_NODES;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use limine::memmap::{
+    MEMMAP_ACPI_NVS, MEMMAP_ACPI_RECLAIMABLE, MEMMAP_BAD_MEMORY, MEMMAP_BOOTLOADER_RECLAIMABLE,
+    MEMMAP_EXECUTABLE_AND_MODULES, MEMMAP_FRAMEBUFFER, MEMMAP_MAPPED_RESERVED, MEMMAP_RESERVED,
+    MEMMAP_USABLE,
+};
+
+/// What an allocated frame is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramePurpose {
+    /// Page-table pages, allocated through `AmirOSPagingHandler`.
+    PageTable,
+    /// Pages backing the kernel heap.
+    Heap,
+    /// Kernel stacks.
+    Stack,
+    /// Device drivers, e.g. DMA buffers.
+    Driver,
+    /// Pages mapped into user address spaces.
+    User,
+    /// Free frames held in the per-CPU frame caches and the pre-zeroed pool.
+    Cache,
+    /// Anything else.
+    Other,
+}
+
+impl FramePurpose {
+    pub const ALL: [Self; 7] = [
+        Self::PageTable,
+        Self::Heap,
+        Self::Stack,
+        Self::Driver,
+        Self::User,
+        Self::Cache,
+        Self::Other,
+    ];
+
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::PageTable => "page tables",
+            Self::Heap => "heap",
+            Self::Stack => "stacks",
+            Self::Driver => "drivers",
+            Self::User => "user",
+            Self::Cache => "caches",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// Allocated page counts, indexed by `FramePurpose as usize`.
+static ALLOCATED_PAGES: [AtomicUsize; FramePurpose::ALL.len()] =
+    [const { AtomicUsize::new(0) }; FramePurpose::ALL.len()];
+
+/// Records that `pages` pages were allocated for `purpose`.
+pub fn account_alloc(purpose: FramePurpose, pages: usize) {
+    ALLOCATED_PAGES[purpose as usize].fetch_add(pages, Ordering::Relaxed);
+}
+
+/// Records that `pages` pages allocated for `purpose` were freed.
+pub fn account_free(purpose: FramePurpose, pages: usize) {
+    let counter = &ALLOCATED_PAGES[purpose as usize];
+    if counter.fetch_sub(pages, Ordering::Relaxed) < pages {
+        log::warn!("memory: more {} pages freed than allocated", purpose.name());
+        counter.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Returns the number of pages currently allocated for `purpose`.
+#[must_use]
+pub fn allocated_pages(purpose: FramePurpose) -> usize {
+    ALLOCATED_PAGES[purpose as usize].load(Ordering::Relaxed)
+}
+
+/// A snapshot of physical memory usage. All counts are in pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Pages managed by the frame allocator.
+    pub total_pages: usize,
+    pub free_pages: usize,
+    /// Allocated pages, indexed by `FramePurpose as usize`.
+    pub allocated_pages: [usize; FramePurpose::ALL.len()],
+    /// Size in bytes of the largest physically contiguous free block.
+    pub largest_free_block: usize,
+    pub zones: [ZoneUsage; Zone::ALL.len()],
+    /// Number of NUMA nodes; only the first `node_count` entries of `nodes`
+    /// are meaningful.
+    pub node_count: usize,
+    pub nodes: [NodeUsage; MAX_NODES],
+}
+
+impl MemoryStats {
+    /// Returns the number of allocated pages tagged with `purpose`.
+    #[must_use]
+    pub const fn allocated(&self, purpose: FramePurpose) -> usize {
+        self.allocated_pages[purpose as usize]
+    }
+
+    /// Returns the number of pages in use, whatever they are used for.
+    #[must_use]
+    pub const fn used_pages(&self) -> usize {
+        self.total_pages - self.free_pages
+    }
+}
+
+/// One entry of the bootloader memory map, as recorded by the frame
+/// allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub base: usize,
+    pub length: usize,
+    /// One of the `limine::memmap::MEMMAP_*` types.
+    pub type_: u64,
+}
+
+/// Usage of a single memory map region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionStats {
+    pub region: MemoryRegion,
+    /// Free pages in the region; always zero for regions the frame
+    /// allocator does not manage.
+    pub free_pages: usize,
+}
+
+/// Returns a human-readable name for a memory map entry type.
+#[must_use]
+pub const fn region_type_name(type_: u64) -> &'static str {
+    match type_ {
+        MEMMAP_USABLE => "usable",
+        MEMMAP_RESERVED => "reserved",
+        MEMMAP_ACPI_RECLAIMABLE => "ACPI reclaimable",
+        MEMMAP_ACPI_NVS => "ACPI NVS",
+        MEMMAP_BAD_MEMORY => "bad memory",
+        MEMMAP_BOOTLOADER_RECLAIMABLE => "bootloader reclaimable",
+        MEMMAP_EXECUTABLE_AND_MODULES => "kernel and modules",
+        MEMMAP_FRAMEBUFFER => "framebuffer",
+        MEMMAP_MAPPED_RESERVED => "mapped reserved",
+        _ => "unknown",
+    }
+}
+
+/// Logs `stats` and the per-region breakdown in `regions`.
+pub fn log_summary(stats: &MemoryStats, regions: impl Iterator<Item = RegionStats>) {
+    let kib = |pages: usize| pages * PAGE_SIZE / 1024;
+    log::info!(
+        "physical memory: {} KiB total, {} KiB free, {} KiB used, largest free block {} KiB.",
+        kib(stats.total_pages),
+        kib(stats.free_pages),
+        kib(stats.used_pages()),
+        stats.largest_free_block / 1024
+    );
+    for purpose in FramePurpose::ALL {
+        log::info!(
+            "  {:<11}: {} KiB",
+            purpose.name(),
+            kib(stats.allocated(purpose))
+        );
+    }
+    for usage in &stats.zones {
+        log::info!(
+            "  zone {:<6}: {} KiB free of {} KiB",
+            usage.zone.name(),
+            kib(usage.free_pages),
+            kib(usage.total_pages)
+        );
+    }
+    if stats.node_count > 1 {
+        for usage in &stats.nodes[..stats.node_count] {
+            log::info!(
+                "  node {:<6}: {} KiB free of {} KiB",
+                usage.node,
+                kib(usage.free_pages),
+                kib(usage.total_pages)
+            );
+        }
+    }
+    for region in regions {
+        log::info!(
+            "  [{:#014x}-{:#014x}] {:<22} {} KiB free",
+            region.region.base,
+            region.region.base + region.region.length,
+            region_type_name(region.region.type_),
+            kib(region.free_pages)
+        );
+    }
+}
@@ -0,0 +1,329 @@
+//! Cross-CPU TLB shootdown.
+//!
+//! Unmapping a page only flushes the TLB of the processor doing it; other
+//! processors may keep the old translation and reach the frame after it has
+//! been freed. A `TlbBatch` collects the ranges being unmapped and the
+//! frames they pointed to. When it is flushed, it invalidates the ranges on
+//! every processor that may cache them, waits until all of them are done
+//! and only then frees the frames.
+//!
+//! How other processors are reached depends on the architecture:
+//! - x86_64: an IPI. The request is published in a mailbox and every
+//!   target acknowledges it once it has flushed.
+//! - riscv64: the SBI RFENCE extension, which returns once the remote hart
+//!   has flushed.
+//! - aarch64: broadcast `tlbi ...is` instructions, complete after
+//!   `dsb ish`.
+//! - loongarch64: application processors are not started yet, so there is
+//!   nobody to tell.
+use crate::arch;
+use crate::memory::asid::Asid;
+use crate::memory::frame_cache::{self, MAX_CPUS};
+use crate::memory::stats::FramePurpose;
+use crate::memory::{PAGE_SIZE, allocator};
+use core::sync::atomic::{AtomicU64, Ordering};
+use memory_addr::PhysAddr;
+
+/// Maximum number of ranges a request carries before it becomes a full
+/// flush.
+const MAX_RANGES: usize = 16;
+/// Maximum number of frame ranges a batch holds before it flushes early.
+const MAX_FRAMES: usize = 32;
+/// Ranges of more pages than this are invalidated by flushing everything.
+const FULL_FLUSH_PAGES: usize = 64;
+
+const _: () = assert!(MAX_CPUS <= 64, "tlb: CpuMask holds 64 processors");
+
+/// A set of processors, by index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CpuMask(u64);
+
+impl CpuMask {
+    pub const EMPTY: Self = Self(0);
+    pub const ALL: Self = Self(u64::MAX);
+
+    #[must_use]
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    #[must_use]
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Returns the set holding only processor `cpu`.
+    #[must_use]
+    pub const fn single(cpu: usize) -> Self {
+        Self(1 << cpu)
+    }
+
+    #[must_use]
+    pub const fn contains(self, cpu: usize) -> bool {
+        self.0 & (1 << cpu) != 0
+    }
+
+    #[must_use]
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns the indices of the processors in the set.
+    pub fn iter(self) -> impl Iterator<Item = usize> {
+        (0..MAX_CPUS).filter(move |&cpu| self.contains(cpu))
+    }
+}
+
+/// Processors that run on the kernel page table and can take shootdowns.
+static ONLINE: AtomicU64 = AtomicU64::new(0);
+/// Hardware id of every online processor, by index.
+static HW_IDS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Marks processor `index`, with hardware id `hw_id` (local APIC id, hart
+/// id or MPIDR), as a shootdown target. Must be called once the processor
+/// runs on the kernel page table and can take shootdown IPIs.
+pub fn cpu_online(index: usize, hw_id: u64) {
+    HW_IDS[index].store(hw_id, Ordering::Relaxed);
+    ONLINE.fetch_or(CpuMask::single(index).bits(), Ordering::Release);
+}
+
+/// Returns the processors that take shootdowns.
+#[must_use]
+pub fn online_cpus() -> CpuMask {
+    CpuMask(ONLINE.load(Ordering::Acquire))
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
+fn hw_id(cpu: usize) -> u64 {
+    HW_IDS[cpu].load(Ordering::Relaxed)
+}
+
+/// Virtual ranges `(start, end)` to invalidate.
+#[derive(Debug, Clone, Copy)]
+struct FlushRequest {
+    /// Address space the ranges belong to, or `None` for mappings every
+    /// address space shares.
+    asid: Option<Asid>,
+    ranges: [(usize, usize); MAX_RANGES],
+    count: usize,
+    /// Set when the ranges did not fit; everything is flushed instead.
+    full: bool,
+}
+
+impl FlushRequest {
+    const fn new(asid: Option<Asid>) -> Self {
+        Self {
+            asid,
+            ranges: [(0, 0); MAX_RANGES],
+            count: 0,
+            full: false,
+        }
+    }
+
+    fn add(&mut self, start: usize, end: usize) {
+        if self.full {
+            return;
+        }
+        if (end - start) / PAGE_SIZE > FULL_FLUSH_PAGES {
+            self.full = true;
+        } else if let Some(last) = self.ranges[..self.count].last_mut()
+            && last.1 == start
+        {
+            last.1 = end;
+        } else if self.count == MAX_RANGES {
+            self.full = true;
+        } else {
+            self.ranges[self.count] = (start, end);
+            self.count += 1;
+        }
+    }
+
+    const fn is_empty(&self) -> bool {
+        !self.full && self.count == 0
+    }
+
+    fn pages(&self) -> impl Iterator<Item = usize> + '_ {
+        self.ranges[..self.count]
+            .iter()
+            .flat_map(|&(start, end)| (start..end).step_by(PAGE_SIZE))
+    }
+
+    fn flush_local(&self) {
+        if self.full {
+            arch::flush_tlb_local(self.asid, None);
+        } else {
+            for page in self.pages() {
+                if arch::flush_tlb_local(self.asid, Some(page)) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Ranges unmapped on behalf of a set of processors, and the frames to free
+/// once none of them can reach them any more. Flushed on drop.
+pub struct TlbBatch {
+    targets: CpuMask,
+    request: FlushRequest,
+    /// `(start, size, purpose)` of every frame range to free.
+    frames: [(PhysAddr, usize, FramePurpose); MAX_FRAMES],
+    frame_count: usize,
+}
+
+impl TlbBatch {
+    /// Creates a batch for mappings of address space `asid` (`None` for
+    /// mappings shared by every address space) that the processors in
+    /// `targets` may cache.
+    #[must_use]
+    pub const fn new(targets: CpuMask, asid: Option<Asid>) -> Self {
+        Self {
+            targets,
+            request: FlushRequest::new(asid),
+            frames: [(PhysAddr::from_usize(0), 0, FramePurpose::Other); MAX_FRAMES],
+            frame_count: 0,
+        }
+    }
+
+    /// Creates a batch for kernel mappings, which every processor may
+    /// cache.
+    #[must_use]
+    pub const fn kernel() -> Self {
+        Self::new(CpuMask::ALL, None)
+    }
+
+    /// Records that `vaddr..vaddr + size` was unmapped or had its
+    /// permissions reduced.
+    pub fn invalidate(&mut self, vaddr: usize, size: usize) {
+        let start = vaddr & !(PAGE_SIZE - 1);
+        let end = (vaddr + size).next_multiple_of(PAGE_SIZE);
+        self.request.add(start, end);
+    }
+
+    /// Frees the frame at `paddr`, allocated for `purpose`, once the
+    /// ranges recorded so far have been invalidated everywhere.
+    pub fn free_after(&mut self, paddr: PhysAddr, purpose: FramePurpose) {
+        self.free_range_after(paddr, PAGE_SIZE, purpose);
+    }
+
+    /// Like `free_after`, for the `size` bytes of contiguous frames starting
+    /// at `paddr` that backed a huge page.
+    pub fn free_range_after(&mut self, paddr: PhysAddr, size: usize, purpose: FramePurpose) {
+        if self.frame_count == MAX_FRAMES {
+            self.flush();
+        }
+        self.frames[self.frame_count] = (paddr, size, purpose);
+        self.frame_count += 1;
+    }
+
+    /// Invalidates the recorded ranges on this processor and on every
+    /// online target, waits for the targets to finish and frees the
+    /// recorded frames. Must not be called while holding a lock that a
+    /// target may be spinning on with interrupts disabled.
+    pub fn flush(&mut self) {
+        if !self.request.is_empty() {
+            self.request.flush_local();
+            let this = CpuMask::single(arch::cpu_index());
+            let remote = CpuMask(self.targets.0 & online_cpus().0 & !this.0);
+            if !remote.is_empty() {
+                shootdown(remote, &self.request);
+            }
+            self.request = FlushRequest::new(self.request.asid);
+        }
+        for &(paddr, size, purpose) in &self.frames[..self.frame_count] {
+            if size == PAGE_SIZE {
+                frame_cache::free_frame(paddr, purpose);
+            } else if let Ok(range) = (paddr.as_usize()..paddr.as_usize() + size).try_into() {
+                allocator::free_frames(range, purpose);
+            }
+        }
+        self.frame_count = 0;
+    }
+}
+
+impl Drop for TlbBatch {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod mailbox {
+    use super::FlushRequest;
+    use core::sync::atomic::AtomicU64;
+    use spin::{Mutex, RwLock};
+
+    /// Held by the processor running a shootdown.
+    pub static SHOOTDOWN: Mutex<()> = Mutex::new(());
+    /// What the current shootdown invalidates.
+    pub static REQUEST: RwLock<FlushRequest> = RwLock::new(FlushRequest::new(None));
+    /// Targets that have not acknowledged the current shootdown yet.
+    pub static PENDING: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Invalidates `request` on the processors in `remote` and returns once
+/// they are done.
+fn shootdown(remote: CpuMask, request: &FlushRequest) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        use mailbox::{PENDING, REQUEST, SHOOTDOWN};
+        // Whoever holds the mailbox may be waiting for us, so keep
+        // answering while waiting for it.
+        let _guard = loop {
+            if let Some(guard) = SHOOTDOWN.try_lock() {
+                break guard;
+            }
+            handle_shootdown();
+            core::hint::spin_loop();
+        };
+        *REQUEST.write() = *request;
+        PENDING.store(remote.bits(), Ordering::Release);
+        for cpu in remote.iter() {
+            arch::apic::send_ipi(hw_id(cpu), arch::apic::TLB_SHOOTDOWN_VECTOR);
+        }
+        while PENDING.load(Ordering::Acquire) != 0 {
+            core::hint::spin_loop();
+        }
+    }
+    #[cfg(target_arch = "riscv64")]
+    for cpu in remote.iter() {
+        let hart = hw_id(cpu);
+        let done = if request.full {
+            arch::remote_sfence_vma(hart, request.asid, 0, usize::MAX)
+        } else {
+            request.ranges[..request.count].iter().all(|&(start, end)| {
+                arch::remote_sfence_vma(hart, request.asid, start, end - start)
+            })
+        };
+        if !done {
+            log::warn!("tlb: SBI refused a remote fence on hart {hart}");
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        let _ = remote;
+        if request.full {
+            arch::flush_tlb_broadcast(request.asid, None);
+        } else {
+            for page in request.pages() {
+                arch::flush_tlb_broadcast(request.asid, Some(page));
+            }
+        }
+    }
+    #[cfg(target_arch = "loongarch64")]
+    let _ = (remote, request);
+}
+
+/// Services a pending shootdown aimed at this processor. Called from the
+/// shootdown IPI handler, and while waiting to start a shootdown.
+#[cfg(target_arch = "x86_64")]
+pub fn handle_shootdown() {
+    use mailbox::{PENDING, REQUEST};
+    let this = CpuMask::single(arch::cpu_index()).bits();
+    if PENDING.load(Ordering::Acquire) & this == 0 {
+        return;
+    }
+    let request = *REQUEST.read();
+    request.flush_local();
+    PENDING.fetch_and(!this, Ordering::Release);
+}
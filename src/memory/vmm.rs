@@ -0,0 +1,446 @@
+//! Kernel virtual address space manager.
+//!
+//! The kernel's virtual address space is split into named regions. Fixed
+//! regions (the HHDM, the kernel image, the heap) are only reserved so that
+//! nothing else is placed on top of them; dynamic regions (vmalloc, kernel
+//! stacks, MMIO) hand out page-aligned ranges. Every range handed out is
+//! preceded by an unmapped guard page, so stacks overflowing downwards and
+//! buffers overrunning upwards fault instead of corrupting a neighbour.
+//!
+//! A range is backed eagerly (frames mapped before `allocate` returns), on
+//! demand (the page fault handler maps a zeroed frame on first access), or
+//! not at all (the caller maps it, e.g. with device memory). Ranges of 2 MiB
+//! or more start on a huge page boundary, so eager backing can use huge
+//! pages.
+//!
+//! Ranges are tracked in a `meminterval` tree, whose nodes live on the
+//! heap; before the heap is initialized they come from the early
+//! allocator.
+use crate::memory::allocator::AllocError;
+use crate::memory::stats::FramePurpose;
+use crate::memory::tlb::TlbBatch;
+use crate::memory::{PAGE_MAPPER, PAGE_SIZE, address_space, frame_cache, huge, kaslr, zero};
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use meminterval::IntervalTree;
+use memory_addr::VirtAddr;
+use page_table_multiarch::{MappingFlags, PageSize, PagingError};
+use spin::Mutex;
+
+// Sizes of the dynamic regions. `kaslr` chooses where they start.
+/// Caps the size of a dynamic region at half of its `kaslr` slot, leaving
+/// the other half to slide it in. Only Sv39 has slots small enough for
+/// the cap to matter.
+fn region_size(size: usize) -> usize {
+    size.min(kaslr::slot_size() / 2)
+}
+
+/// Size of the vmalloc region: 1 TiB.
+#[must_use]
+pub fn vmalloc_size() -> usize {
+    region_size(0x100_0000_0000)
+}
+
+/// Size of the kernel stack region: 64 GiB.
+#[must_use]
+pub fn stacks_size() -> usize {
+    region_size(0x10_0000_0000)
+}
+
+/// Size of the region device memory is mapped into: 1 TiB.
+#[must_use]
+pub fn mmio_size() -> usize {
+    region_size(0x100_0000_0000)
+}
+
+/// Size of the window holding uncached aliases of DMA buffers: 1 TiB.
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+#[must_use]
+pub fn dma_uncached_size() -> usize {
+    region_size(0x100_0000_0000)
+}
+
+/// Maximum number of regions.
+const MAX_REGIONS: usize = 16;
+/// Size of the unmapped gap below every range handed out.
+const GUARD_SIZE: usize = PAGE_SIZE;
+
+/// Identifies a region reserved with `reserve_region`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionId(usize);
+
+impl RegionId {
+    pub const VMALLOC: Self = Self(0);
+    pub const STACKS: Self = Self(1);
+    pub const MMIO: Self = Self(2);
+}
+
+/// A named part of the kernel address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: usize,
+    pub end: usize,
+    /// Whether `allocate` hands out ranges from the region. Fixed regions
+    /// are managed by someone else and only reserved here.
+    pub dynamic: bool,
+}
+
+/// How the pages of a range get physical memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Zeroed frames are mapped before `allocate` returns.
+    Eager,
+    /// Zeroed frames are mapped by the page fault handler on first access.
+    OnDemand,
+    /// Nothing is mapped; the caller maps the range itself.
+    None,
+}
+
+impl Backing {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Eager => "eager",
+            Self::OnDemand => "on demand",
+            Self::None => "unbacked",
+        }
+    }
+}
+
+/// A range handed out by `allocate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allocation {
+    pub name: &'static str,
+    pub region: RegionId,
+    /// Usable range `start..end`; the guard page lies just below `start`.
+    pub start: usize,
+    pub end: usize,
+    pub backing: Backing,
+    /// What the backing frames are accounted to.
+    pub purpose: FramePurpose,
+}
+
+pub struct KernelVmm {
+    regions: [Option<Region>; MAX_REGIONS],
+    region_count: usize,
+    /// Every allocation, keyed by its range including the guard page.
+    allocations: IntervalTree<usize, Allocation>,
+}
+
+lazy_static! {
+    pub static ref KERNEL_VMM: Mutex<KernelVmm> = Mutex::new(KernelVmm {
+        regions: [None; MAX_REGIONS],
+        region_count: 0,
+        allocations: IntervalTree::new(),
+    });
+}
+
+impl KernelVmm {
+    /// Reserves `start..start + size` under `name`.
+    /// # Panics
+    /// when the range overlaps a region reserved before, there are too many
+    /// regions, or there is no memory left for the page tables that share
+    /// the region with every address space.
+    pub fn reserve_region(
+        &mut self,
+        name: &'static str,
+        start: usize,
+        size: usize,
+        dynamic: bool,
+    ) -> RegionId {
+        let end = start
+            .checked_add(size)
+            .expect("vmm: region wraps around the address space");
+        if let Some(other) = self
+            .regions()
+            .find(|other| other.start < end && start < other.end)
+        {
+            panic!(
+                "vmm: region {name} ({start:#x}-{end:#x}) overlaps {} ({:#x}-{:#x})",
+                other.name, other.start, other.end
+            );
+        }
+        assert!(
+            self.region_count < MAX_REGIONS,
+            "vmm: too many regions reserved"
+        );
+        self.regions[self.region_count] = Some(Region {
+            name,
+            start,
+            end,
+            dynamic,
+        });
+        self.region_count += 1;
+        // Address spaces created from now on share the region's mappings.
+        address_space::populate_kernel_entries(start, end);
+        RegionId(self.region_count - 1)
+    }
+
+    /// Returns every reserved region.
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions[..self.region_count].iter().flatten()
+    }
+
+    /// Returns the region `id`.
+    #[must_use]
+    pub fn region(&self, id: RegionId) -> Option<&Region> {
+        self.regions.get(id.0)?.as_ref()
+    }
+
+    /// Returns the allocation containing `vaddr`, if any. Guard pages are
+    /// not part of any allocation.
+    #[must_use]
+    pub fn find(&self, vaddr: usize) -> Option<Allocation> {
+        self.allocations
+            .query(vaddr..vaddr + 1)
+            .map(|entry| *entry.value)
+            .find(|allocation| (allocation.start..allocation.end).contains(&vaddr))
+    }
+
+    /// Reserves `size` bytes, rounded up to pages, in the dynamic region
+    /// `region`, without mapping anything. The range is aligned for the
+    /// largest huge page it can hold.
+    fn reserve(
+        &mut self,
+        region: RegionId,
+        name: &'static str,
+        size: usize,
+        backing: Backing,
+        purpose: FramePurpose,
+    ) -> Result<Allocation, AllocError> {
+        let region_bounds = *self.region(region).ok_or(AllocError)?;
+        if !region_bounds.dynamic || size == 0 {
+            return Err(AllocError);
+        }
+        let size = size.next_multiple_of(PAGE_SIZE);
+        let len = size.checked_add(GUARD_SIZE).ok_or(AllocError)?;
+        let align = huge::alignment_for(size);
+        // First fit: skip past whatever overlaps the candidate range.
+        let mut base = region_bounds.start;
+        loop {
+            base = (base + GUARD_SIZE).next_multiple_of(align) - GUARD_SIZE;
+            let end = base.checked_add(len).ok_or(AllocError)?;
+            if end > region_bounds.end {
+                return Err(AllocError);
+            }
+            match self
+                .allocations
+                .query(base..end)
+                .map(|entry| entry.interval.end)
+                .max()
+            {
+                Some(next) => base = next,
+                None => break,
+            }
+        }
+        let allocation = Allocation {
+            name,
+            region,
+            start: base + GUARD_SIZE,
+            end: base + len,
+            backing,
+            purpose,
+        };
+        self.allocations.insert(base..allocation.end, allocation);
+        Ok(allocation)
+    }
+
+    /// Forgets the allocation starting at `start` and returns it.
+    fn release(&mut self, start: usize) -> Option<Allocation> {
+        let allocation = self.find(start).filter(|found| found.start == start)?;
+        self.allocations
+            .delete(allocation.start - GUARD_SIZE..allocation.end);
+        Some(allocation)
+    }
+}
+
+/// Reserves the standard regions. `hhdm` and `kernel` are the `start..end`
+/// ranges of the direct map and of the kernel image.
+/// # Panics
+/// when the regions overlap.
+pub fn init(hhdm: (usize, usize), kernel: (usize, usize)) {
+    let layout = kaslr::layout();
+    let mut vmm = KERNEL_VMM.lock();
+    // The order matches the `RegionId` constants.
+    vmm.reserve_region("vmalloc", layout.vmalloc_start, vmalloc_size(), true);
+    vmm.reserve_region("stacks", layout.stacks_start, stacks_size(), true);
+    vmm.reserve_region("mmio", layout.mmio_start, mmio_size(), true);
+    vmm.reserve_region("hhdm", hhdm.0, hhdm.1 - hhdm.0, false);
+    vmm.reserve_region("kernel", kernel.0, kernel.1 - kernel.0, false);
+    vmm.reserve_region(
+        "heap",
+        layout.heap_start,
+        crate::allocator::heap_size(),
+        false,
+    );
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    vmm.reserve_region(
+        "dma-uncached",
+        layout.dma_uncached_start,
+        dma_uncached_size(),
+        false,
+    );
+}
+
+/// Allocates a guarded range of at least `size` bytes in the dynamic region
+/// `region`, backed as `backing` says and mapped read-write. Backing frames
+/// are accounted to `purpose`.
+/// # Errors
+/// when the region is full or not dynamic, or there is not enough physical
+/// memory to back an eager range.
+pub fn allocate(
+    region: RegionId,
+    name: &'static str,
+    size: usize,
+    backing: Backing,
+    purpose: FramePurpose,
+) -> Result<VirtAddr, AllocError> {
+    let allocation = KERNEL_VMM
+        .lock()
+        .reserve(region, name, size, backing, purpose)?;
+    if backing == Backing::Eager
+        && huge::map_zeroed(
+            allocation.start,
+            allocation.end,
+            MappingFlags::READ | MappingFlags::WRITE,
+            purpose,
+        )
+        .is_err()
+    {
+        free(VirtAddr::from(allocation.start));
+        return Err(AllocError);
+    }
+    Ok(VirtAddr::from(allocation.start))
+}
+
+/// Frees the range starting at `vaddr`. Backing frames are unmapped and
+/// freed; unbacked ranges are only unmapped.
+pub fn free(vaddr: VirtAddr) {
+    let found = KERNEL_VMM
+        .lock()
+        .find(vaddr.as_usize())
+        .filter(|allocation| allocation.start == vaddr.as_usize());
+    let Some(allocation) = found else {
+        log::warn!(
+            "vmm: ignoring free of {:#x}, which is not allocated",
+            vaddr.as_usize()
+        );
+        return;
+    };
+    // Frames are only freed once no processor can reach them any more.
+    let mut batch = TlbBatch::kernel();
+    let purpose = (allocation.backing != Backing::None).then_some(allocation.purpose);
+    huge::unmap(allocation.start, allocation.end, &mut batch, purpose);
+    batch.flush();
+    // The range is only handed out again once nothing maps it, or a new
+    // allocation could have its mappings torn down by the unmap above.
+    KERNEL_VMM.lock().release(allocation.start);
+}
+
+fn map_zeroed(page: usize, purpose: FramePurpose) -> Result<(), AllocError> {
+    let paddr = zero::alloc_zeroed_frame(purpose)?;
+    let mapped = PAGE_MAPPER.write().cursor().map(
+        VirtAddr::from(page),
+        paddr,
+        PageSize::Size4K,
+        MappingFlags::READ | MappingFlags::WRITE,
+    );
+    match mapped {
+        Ok(()) => Ok(()),
+        // Another processor resolved the same fault first.
+        Err(PagingError::AlreadyMapped | PagingError::MappedToHugePage) => {
+            frame_cache::free_frame(paddr, purpose);
+            Ok(())
+        }
+        Err(_) => {
+            frame_cache::free_frame(paddr, purpose);
+            Err(AllocError)
+        }
+    }
+}
+
+/// Resolves a page fault at `vaddr` by mapping a zeroed frame if the
+/// address lies in an on-demand range. Returns `false` for any other
+/// address, guard pages included, and when out of memory.
+pub fn handle_fault(vaddr: usize) -> bool {
+    let Some(allocation) = KERNEL_VMM.lock().find(vaddr) else {
+        return false;
+    };
+    allocation.backing == Backing::OnDemand
+        && map_zeroed(vaddr & !(PAGE_SIZE - 1), allocation.purpose).is_ok()
+}
+
+/// Allocates a kernel stack of `size` bytes with a guard page below it and
+/// returns its top.
+/// # Errors
+/// when out of stack address space or physical memory.
+pub fn alloc_stack(size: usize) -> Result<usize, AllocError> {
+    let bottom = allocate(
+        RegionId::STACKS,
+        "kernel stack",
+        size,
+        Backing::Eager,
+        FramePurpose::Stack,
+    )?;
+    Ok(bottom.as_usize() + size.next_multiple_of(PAGE_SIZE))
+}
+
+/// Allocates `size` bytes of virtually contiguous kernel memory, mapped
+/// right away.
+/// # Errors
+/// when out of vmalloc address space or physical memory.
+pub fn vmalloc(size: usize) -> Result<VirtAddr, AllocError> {
+    allocate(
+        RegionId::VMALLOC,
+        "vmalloc",
+        size,
+        Backing::Eager,
+        FramePurpose::Other,
+    )
+}
+
+/// Like `vmalloc`, but pages are only backed when first touched.
+/// # Errors
+/// when out of vmalloc address space.
+pub fn vmalloc_on_demand(size: usize) -> Result<VirtAddr, AllocError> {
+    allocate(
+        RegionId::VMALLOC,
+        "vmalloc",
+        size,
+        Backing::OnDemand,
+        FramePurpose::Other,
+    )
+}
+
+/// Logs every region and the ranges allocated in it.
+pub fn dump() {
+    let vmm = KERNEL_VMM.lock();
+    let mut regions: Vec<&Region> = vmm.regions().collect();
+    regions.sort_unstable_by_key(|region| region.start);
+    log::info!("kernel address space:");
+    for region in regions {
+        log::info!(
+            "  [{:#018x}-{:#018x}] {:<12} {} KiB",
+            region.start,
+            region.end,
+            region.name,
+            (region.end - region.start) / 1024
+        );
+        let mut allocations: Vec<Allocation> = vmm
+            .allocations
+            .query(region.start..region.end)
+            .map(|entry| *entry.value)
+            .collect();
+        allocations.sort_unstable_by_key(|allocation| allocation.start);
+        for allocation in allocations {
+            log::info!(
+                "    [{:#018x}-{:#018x}] {:<12} {} KiB, {}",
+                allocation.start,
+                allocation.end,
+                allocation.name,
+                (allocation.end - allocation.start) / 1024,
+                allocation.backing.name()
+            );
+        }
+    }
+}
@@ -0,0 +1,312 @@
+//! Architecture-independent page table walks.
+//!
+//! Every supported architecture uses tables of 512 eight-byte entries, as
+//! many levels deep as the paging mode has, and `page_table_multiarch`
+//! gives their entries a common `GenericPTE` interface, so one walker
+//! serves all of them:
+//! - `translate` looks an address up in the kernel page table;
+//! - `translate_active` looks it up in the table the processor has loaded
+//!   for it, without taking any lock: the bootloader's before the kernel
+//!   table is loaded, or a user address space's;
+//! - `dump` and `dump_active` log the mappings of a range, coalescing runs
+//!   of pages that map contiguous physical memory with the same page size
+//!   and flags into one line.
+use crate::arch;
+use crate::memory::{PAGE_MAPPER, PAGE_SIZE, PageTableEntry, hhdm_offset, mode};
+use core::fmt;
+use memory_addr::PhysAddr;
+use page_table_multiarch::{GenericPTE, MappingFlags, PageSize};
+
+/// Number of entries in a page-table page.
+pub const ENTRIES: usize = 512;
+/// Number of levels pages are mapped at: 0 (4 KiB), 1 (2 MiB) and 2 (1 GiB).
+const PAGE_LEVELS: usize = 3;
+
+/// Level of the root table.
+pub(crate) fn root_level() -> usize {
+    mode::levels() - 1
+}
+
+/// Size of the pages mapped by entries at `level`.
+pub(crate) const fn level_size(level: usize) -> usize {
+    PAGE_SIZE << (9 * level)
+}
+
+/// Index of the entry translating `vaddr` in a table at `level`.
+pub(crate) const fn index(vaddr: usize, level: usize) -> usize {
+    (vaddr >> (12 + 9 * level)) % ENTRIES
+}
+
+const fn page_size(level: usize) -> PageSize {
+    match level {
+        0 => PageSize::Size4K,
+        1 => PageSize::Size2M,
+        _ => PageSize::Size1G,
+    }
+}
+
+/// Returns the entries of the page-table page at `paddr`.
+///
+/// # Safety
+/// `paddr` must be a page-table page of a live page table, and the caller
+/// must hold the lock protecting it if it writes to the entries.
+pub(crate) unsafe fn table<'a>(paddr: PhysAddr) -> &'a mut [PageTableEntry] {
+    let ptr = (paddr.as_usize() + hhdm_offset()) as *mut PageTableEntry;
+    // Safety: page tables are reachable through the HHDM and a table is a
+    // whole page of entries.
+    unsafe { core::slice::from_raw_parts_mut(ptr, ENTRIES) }
+}
+
+/// Returns the entry at `level` on the way to `vaddr`, or `None` if a
+/// level above it is unmapped or maps a huge page.
+///
+/// # Safety
+/// as for `table`, with `root` the root of the page table.
+pub(crate) unsafe fn entry<'a>(
+    root: PhysAddr,
+    vaddr: usize,
+    level: usize,
+) -> Option<&'a mut PageTableEntry> {
+    let mut paddr = root;
+    for current in (level + 1..=root_level()).rev() {
+        // Safety: `paddr` is the root or was read from a table entry.
+        let entry = unsafe { &table(paddr)[index(vaddr, current)] };
+        if !entry.is_present() || entry.is_huge() {
+            return None;
+        }
+        paddr = entry.paddr();
+    }
+    // Safety: as above.
+    Some(unsafe { &mut table(paddr)[index(vaddr, level)] })
+}
+
+/// Returns the entry mapping `vaddr` and its level, or `None` if `vaddr`
+/// is not mapped.
+///
+/// # Safety
+/// as for `entry`.
+pub(crate) unsafe fn leaf<'a>(
+    root: PhysAddr,
+    vaddr: usize,
+) -> Option<(&'a mut PageTableEntry, usize)> {
+    let mut paddr = root;
+    for level in (0..=root_level()).rev() {
+        // Safety: `paddr` is the root or was read from a table entry.
+        let entry = unsafe { &mut table(paddr)[index(vaddr, level)] };
+        if !entry.is_present() {
+            return None;
+        }
+        if level == 0 || entry.is_huge() {
+            return Some((entry, level));
+        }
+        paddr = entry.paddr();
+    }
+    None
+}
+
+/// Where an address is mapped to, and how.
+#[derive(Debug, Clone, Copy)]
+pub struct Translation {
+    /// The physical address, offset into the page included.
+    pub paddr: PhysAddr,
+    pub flags: MappingFlags,
+    /// Size of the page holding the address.
+    pub size: PageSize,
+}
+
+/// Translates `vaddr` through the page table rooted at `root`.
+///
+/// # Safety
+/// `root` must be the root of a live page table.
+#[must_use]
+pub unsafe fn translate_in(root: PhysAddr, vaddr: usize) -> Option<Translation> {
+    // Safety: forwarded to the caller; the entry is only read.
+    let (entry, level) = unsafe { leaf(root, vaddr) }?;
+    Some(Translation {
+        paddr: entry.paddr() + (vaddr & (level_size(level) - 1)),
+        flags: entry.flags(),
+        size: page_size(level),
+    })
+}
+
+/// Translates `vaddr` through the kernel page table.
+#[must_use]
+pub fn translate(vaddr: usize) -> Option<Translation> {
+    let mapper = PAGE_MAPPER.read();
+    // Safety: the kernel page table is live and locked.
+    unsafe { translate_in(mapper.root_paddr(), vaddr) }
+}
+
+/// Translates `vaddr` through the page table this processor has loaded
+/// for it. Takes no lock, so it works before the kernel page table is
+/// loaded and in fault handlers, but may see a mapping that is being
+/// changed.
+#[must_use]
+pub fn translate_active(vaddr: usize) -> Option<Translation> {
+    // Safety: the loaded page table is live.
+    unsafe { translate_in(arch::active_root(vaddr), vaddr) }
+}
+
+/// Mapping flags in `rwxu` form, followed by the memory type if it is not
+/// normal cached memory.
+struct Flags(MappingFlags);
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (flag, name) in [
+            (MappingFlags::READ, 'r'),
+            (MappingFlags::WRITE, 'w'),
+            (MappingFlags::EXECUTE, 'x'),
+            (MappingFlags::USER, 'u'),
+        ] {
+            write!(f, "{}", if self.0.contains(flag) { name } else { '-' })?;
+        }
+        if self.0.contains(MappingFlags::DEVICE) {
+            write!(f, " device")?;
+        } else if self.0.contains(MappingFlags::UNCACHED) {
+            write!(f, " uncached")?;
+        }
+        Ok(())
+    }
+}
+
+/// Consecutive pages of one size mapping contiguous physical memory with
+/// the same flags.
+#[derive(Clone, Copy)]
+struct Run {
+    start: usize,
+    end: usize,
+    paddr: usize,
+    level: usize,
+    flags: MappingFlags,
+}
+
+impl Run {
+    fn continued_by(&self, vaddr: usize, paddr: usize, level: usize, flags: MappingFlags) -> bool {
+        vaddr == self.end
+            && paddr == self.paddr + (self.end - self.start)
+            && level == self.level
+            && flags == self.flags
+    }
+
+    fn log(&self) {
+        let size = level_size(self.level);
+        let pages = (self.end - self.start) / size;
+        let unit = match self.level {
+            0 => "4K",
+            1 => "2M",
+            _ => "1G",
+        };
+        log::info!(
+            "  [{:#018x}-{:#018x}] -> [{:#014x}-{:#014x}] {pages:>6} x {unit} {}",
+            self.start,
+            self.end,
+            self.paddr,
+            self.paddr + (self.end - self.start),
+            Flags(self.flags)
+        );
+    }
+}
+
+/// What `dump_in` has seen so far.
+struct Dump {
+    run: Option<Run>,
+    runs: usize,
+    /// Pages mapped at each level.
+    pages: [usize; PAGE_LEVELS],
+}
+
+impl Dump {
+    fn add(&mut self, vaddr: usize, entry: &PageTableEntry, level: usize) {
+        let (paddr, flags) = (entry.paddr().as_usize(), entry.flags());
+        self.pages[level] += 1;
+        match &mut self.run {
+            Some(run) if run.continued_by(vaddr, paddr, level, flags) => {
+                run.end += level_size(level);
+            }
+            run => {
+                if let Some(done) = run.replace(Run {
+                    start: vaddr,
+                    end: vaddr + level_size(level),
+                    paddr,
+                    level,
+                    flags,
+                }) {
+                    done.log();
+                    self.runs += 1;
+                }
+            }
+        }
+    }
+
+    /// Visits every page of the table at `paddr`, which sits at `level`
+    /// and translates from `base` up, that overlaps `start..end`.
+    ///
+    /// # Safety
+    /// `paddr` must be a page-table page of a live page table.
+    unsafe fn walk(
+        &mut self,
+        paddr: PhysAddr,
+        level: usize,
+        base: usize,
+        start: usize,
+        end: usize,
+    ) {
+        let size = level_size(level);
+        // Safety: forwarded to the caller; the entries are only read.
+        let entries = unsafe { table(paddr) };
+        for (i, entry) in entries.iter().enumerate() {
+            let vaddr = mode::canonical(base + i * size);
+            if vaddr >= end || vaddr + (size - 1) < start || !entry.is_present() {
+                continue;
+            }
+            if level == 0 || entry.is_huge() {
+                self.add(vaddr, entry, level);
+            } else {
+                // Safety: the entry points to a next-level table.
+                unsafe { self.walk(entry.paddr(), level - 1, vaddr, start, end) };
+            }
+        }
+    }
+}
+
+/// Logs the pages mapped in `start..end` by the page table rooted at
+/// `root`, coalesced into runs.
+///
+/// # Safety
+/// `root` must be the root of a live page table.
+pub unsafe fn dump_in(root: PhysAddr, start: usize, end: usize) {
+    log::info!("page table {root:?}, [{start:#018x}-{end:#018x}]:");
+    let mut dump = Dump {
+        run: None,
+        runs: 0,
+        pages: [0; PAGE_LEVELS],
+    };
+    // Safety: forwarded to the caller.
+    unsafe { dump.walk(root, root_level(), 0, start, end) };
+    if let Some(run) = dump.run {
+        run.log();
+        dump.runs += 1;
+    }
+    log::info!(
+        "  {} runs: {} x 4K, {} x 2M, {} x 1G",
+        dump.runs,
+        dump.pages[0],
+        dump.pages[1],
+        dump.pages[2]
+    );
+}
+
+/// Logs the pages the kernel page table maps in `start..end`.
+pub fn dump(start: usize, end: usize) {
+    let mapper = PAGE_MAPPER.read();
+    // Safety: the kernel page table is live and locked.
+    unsafe { dump_in(mapper.root_paddr(), start, end) };
+}
+
+/// Logs the pages mapped in `start..end` by the page table this processor
+/// has loaded for `start`.
+pub fn dump_active(start: usize, end: usize) {
+    // Safety: the loaded page table is live.
+    unsafe { dump_in(arch::active_root(start), start, end) };
+}
@@ -0,0 +1,122 @@
+//! Zeroed frames: the scrub-on-free policy and a pool of pre-zeroed frames.
+//!
+//! Handing out frames with stale contents leaks data between subsystems, so
+//! the heap and the demand-paging fault path only ever get zeroed frames.
+//! Zeroing a frame inline costs a 4 KiB memset on the fault path; the pool
+//! moves that work to the idle loop, which tops it up with `refill`.
+//!
+//! Frames sitting in the pool are accounted to `FramePurpose::Cache`.
+use crate::memory::allocator::AllocError;
+use crate::memory::page_desc;
+use crate::memory::stats::{self, FramePurpose};
+use crate::memory::{PAGE_SIZE, frame_cache, hhdm_offset};
+use core::sync::atomic::{AtomicBool, Ordering};
+use memory_addr::PhysAddr;
+use spin::Mutex;
+
+/// Frames the pool holds when full.
+const POOL_SIZE: usize = 256;
+
+static SCRUB_ON_FREE: AtomicBool = AtomicBool::new(false);
+
+struct ZeroPool {
+    frames: [usize; POOL_SIZE],
+    count: usize,
+}
+
+static POOL: Mutex<ZeroPool> = Mutex::new(ZeroPool {
+    frames: [0; POOL_SIZE],
+    count: 0,
+});
+
+/// Fills `len` bytes of physical memory at `paddr` with zeroes through the
+/// HHDM.
+///
+/// # Safety
+/// The range must be RAM owned by the caller.
+pub unsafe fn zero_frames(paddr: usize, len: usize) {
+    unsafe { core::ptr::write_bytes((paddr + hhdm_offset()) as *mut u8, 0, len) };
+}
+
+/// Enables or disables zeroing frames when they are freed, so that freed
+/// data does not linger in memory. Off by default.
+pub fn set_scrub_on_free(enabled: bool) {
+    SCRUB_ON_FREE.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns `true` if frames are zeroed when they are freed.
+#[must_use]
+pub fn scrub_on_free() -> bool {
+    SCRUB_ON_FREE.load(Ordering::Relaxed)
+}
+
+/// Zeroes `len` bytes at `paddr` if the scrub-on-free policy is enabled.
+/// Called by the frame allocator and the frame caches on every free.
+pub(crate) fn scrub(paddr: usize, len: usize) {
+    if scrub_on_free() {
+        // Safety: the caller is freeing the range, so it still owns it.
+        unsafe { zero_frames(paddr, len) };
+    }
+}
+
+/// Allocates one zeroed frame for `purpose`, from the pre-zeroed pool if it
+/// has one and by zeroing a fresh frame otherwise.
+/// # Errors
+/// when the system is out of physical memory.
+pub fn alloc_zeroed_frame(purpose: FramePurpose) -> Result<PhysAddr, AllocError> {
+    if let Some(paddr) = take_from_pool(purpose) {
+        return Ok(paddr);
+    }
+    let paddr = frame_cache::alloc_frame(purpose)?;
+    // Safety: the frame was just allocated.
+    unsafe { zero_frames(paddr.as_usize(), PAGE_SIZE) };
+    Ok(paddr)
+}
+
+/// Like `alloc_zeroed_frame`, but returns `None` instead of spinning when
+/// the global allocator is locked. Meant for fault handlers.
+pub fn try_alloc_zeroed_frame(purpose: FramePurpose) -> Option<Result<PhysAddr, AllocError>> {
+    if let Some(paddr) = take_from_pool(purpose) {
+        return Some(Ok(paddr));
+    }
+    let frame = frame_cache::try_alloc_frame(purpose)?;
+    if let Ok(paddr) = frame {
+        // Safety: the frame was just allocated.
+        unsafe { zero_frames(paddr.as_usize(), PAGE_SIZE) };
+    }
+    Some(frame)
+}
+
+fn take_from_pool(purpose: FramePurpose) -> Option<PhysAddr> {
+    let mut pool = POOL.try_lock()?;
+    pool.count = pool.count.checked_sub(1)?;
+    let paddr = PhysAddr::from(pool.frames[pool.count]);
+    drop(pool);
+    stats::account_free(FramePurpose::Cache, 1);
+    stats::account_alloc(purpose, 1);
+    page_desc::transfer(paddr, FramePurpose::Cache, purpose);
+    Some(paddr)
+}
+
+/// Tops the pre-zeroed pool up, and the heap fault reserve of the current
+/// processor. Frames are zeroed without holding any lock, so this is cheap
+/// to call from the idle loop of every processor.
+pub fn refill() {
+    crate::memory::fault::refill_reserve();
+    while POOL.lock().count < POOL_SIZE {
+        let Ok(paddr) = frame_cache::alloc_frame(FramePurpose::Cache) else {
+            return;
+        };
+        // Safety: the frame was just allocated.
+        unsafe { zero_frames(paddr.as_usize(), PAGE_SIZE) };
+        let mut pool = POOL.lock();
+        if pool.count == POOL_SIZE {
+            drop(pool);
+            frame_cache::free_frame(paddr, FramePurpose::Cache);
+            return;
+        }
+        let count = pool.count;
+        pool.frames[count] = paddr.as_usize();
+        pool.count += 1;
+    }
+}
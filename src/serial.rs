@@ -1,6 +1,8 @@
 use core::fmt;
 use core::fmt::Write;
 use lazy_static::lazy_static;
+#[cfg(not(target_arch = "x86_64"))]
+use memory_addr::PhysAddr;
 use spin::Mutex;
 use uart_16550::{Config, Uart16550Tty};
 
@@ -9,6 +11,17 @@ type SerialPort = Uart16550Tty<uart_16550::backend::PioBackend>;
 #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
 type SerialPort = Uart16550Tty<uart_16550::backend::MmioBackend>;
 
+/// Physical address of the MMIO UART on the QEMU `virt` machines.
+#[cfg(target_arch = "riscv64")]
+const UART_BASE: usize = 0x1000_0000;
+#[cfg(target_arch = "aarch64")]
+const UART_BASE: usize = 0x0900_0000;
+#[cfg(target_arch = "loongarch64")]
+const UART_BASE: usize = 0x1fe0_01e0;
+/// Size of the 16550 register block with a register stride of 1.
+#[cfg(not(target_arch = "x86_64"))]
+const UART_SIZE: usize = 8;
+
 lazy_static! {
     pub static ref SERIAL_WRITER: Mutex<SerialPort> = {
         #[cfg(target_arch = "x86_64")]
@@ -16,37 +29,17 @@ lazy_static! {
             Uart16550Tty::new_port(0x3F8, Config::default()).expect("failed to init serial")
         };
 
-        #[cfg(target_arch = "riscv64")]
-        let serial_port = unsafe {
-            Uart16550Tty::new_mmio(
-                core::ptr::NonNull::new(0x10000000 as *mut u8)
-                    .expect("serial: null UART MMIO address on riscv64"),
-                1,
-                Config::default(),
-            )
-            .expect("serial: failed to init riscv64 UART")
-        };
-
-        #[cfg(target_arch = "aarch64")]
-        let serial_port = unsafe {
-            Uart16550Tty::new_mmio(
-                core::ptr::NonNull::new(0x09000000 as *mut u8)
-                    .expect("serial: null UART MMIO address on aarch64"),
-                1,
-                Config::default(),
-            )
-            .expect("serial: failed to init aarch64 UART")
-        };
-
-        #[cfg(target_arch = "loongarch64")]
+        // The registers stay mapped for as long as the kernel runs.
+        #[cfg(not(target_arch = "x86_64"))]
         let serial_port = unsafe {
+            let registers = crate::memory::mmio::ioremap(PhysAddr::from(UART_BASE), UART_SIZE)
+                .expect("serial: failed to map the UART registers");
             Uart16550Tty::new_mmio(
-                core::ptr::NonNull::new(0x1fe001e0 as *mut u8)
-                    .expect("serial: null UART MMIO address on loongarch64"),
+                core::ptr::NonNull::new(registers.leak()).expect("serial: null UART MMIO address"),
                 1,
                 Config::default(),
             )
-            .expect("serial: failed to init loongarch64 UART")
+            .expect("serial: failed to init UART")
         };
 
         Mutex::new(serial_port)